@@ -0,0 +1,758 @@
+//! `Arbitrary` implementations, enabled by "arbitrary" feature.
+//!
+//! Generated values respect wire constraints, so every generated `Frame` can be encoded and
+//! decoded back into the same value by a codec with `Role::Both` and otherwise default
+//! `Config`. To keep this property,
+//!
+//! - short strings are valid UTF-8 of at most 255 bytes;
+//! - field tables and field arrays are nested at most 3 levels and have a few entries, so a
+//!   frame never exceeds default `frame_max`;
+//! - `Float` and `Double` field values are never NaN;
+//! - only methods and properties which exist in 0-9-1 are generated (no `Connection.Redirect`,
+//!   `Channel.Alert`, nor `Unknown` payloads).
+
+use arbitrary::{Arbitrary, Unstructured, Result};
+use bytes::Bytes;
+
+use std::collections::HashMap;
+
+use args::{AmqpString, Decimal, FieldArgument};
+use frame::{Frame, FrameHeader, FramePayload, HEARTBEAT_TYPE_BYTE};
+use frame::method::{MethodPayload, ConnectionClass, ChannelClass, AccessClass, ExchangeClass,
+                    QueueClass, BasicClass, ConfirmClass, TxClass};
+use frame::method::{connection, channel, access, exchange, queue, basic, confirm};
+use frame::content_header::{ContentHeaderPayload, Properties, RawProperties, BASIC_CLASS_ID};
+use frame::content_body::ContentBodyPayload;
+
+const MAX_SHORT_STR_LEN: usize = 255;
+/// Long strings can be much longer, but they are kept short so that frames fit in frame_max.
+const MAX_LONG_STR_LEN: usize = 1024;
+const MAX_TABLE_DEPTH: usize = 3;
+const MAX_TABLE_ENTRIES: usize = 4;
+const MAX_BODY_LEN: usize = 4096;
+
+
+/// Implement `Arbitrary` by building a value from `$u`.
+macro_rules! impl_arbitrary {
+    ($t:ty, |$u:ident| $body:expr) => {
+        impl<'a> Arbitrary<'a> for $t {
+            fn arbitrary($u: &mut Unstructured<'a>) -> Result<Self> {
+                Ok($body)
+            }
+        }
+    };
+}
+
+
+// Helpers {{{
+fn string(u: &mut Unstructured, max_len: usize) -> Result<AmqpString> {
+    let mut s = String::new();
+    let n_chars = u.int_in_range(0..=max_len)?;
+    for _ in 0..n_chars {
+        let c = u.arbitrary::<char>()?;
+        if s.len() + c.len_utf8() > max_len {
+            break;
+        }
+        s.push(c);
+    }
+    Ok(AmqpString::from(s))
+}
+
+
+fn short_str(u: &mut Unstructured) -> Result<AmqpString> {
+    string(u, MAX_SHORT_STR_LEN)
+}
+
+
+fn long_str(u: &mut Unstructured) -> Result<AmqpString> {
+    string(u, MAX_LONG_STR_LEN)
+}
+
+
+fn byte_vec(u: &mut Unstructured, max_len: usize) -> Result<Vec<u8>> {
+    let len = u.int_in_range(0..=max_len)?.min(u.len());
+    Ok(u.bytes(len)?.to_vec())
+}
+
+
+fn field_table(u: &mut Unstructured) -> Result<HashMap<AmqpString, FieldArgument>> {
+    field_table_n(u, 1)
+}
+
+
+fn field_table_n(
+    u: &mut Unstructured,
+    depth: usize,
+) -> Result<HashMap<AmqpString, FieldArgument>> {
+    let mut table = HashMap::new();
+    for _ in 0..u.int_in_range(0..=MAX_TABLE_ENTRIES)? {
+        table.insert(short_str(u)?, field_value(u, depth)?);
+    }
+    Ok(table)
+}
+
+
+fn field_value(u: &mut Unstructured, depth: usize) -> Result<FieldArgument> {
+    // Nested values are not generated at the deepest level.
+    let max_kind = if depth < MAX_TABLE_DEPTH { 18 } else { 16 };
+    let value = match u.int_in_range(0..=max_kind)? {
+        0 => FieldArgument::Boolean(u.arbitrary()?),
+        1 => FieldArgument::SignedOctet(u.arbitrary()?),
+        2 => FieldArgument::UnsignedOctet(u.arbitrary()?),
+        3 => FieldArgument::SignedShort(u.arbitrary()?),
+        4 => FieldArgument::UnsignedShort(u.arbitrary()?),
+        5 => FieldArgument::SignedLong(u.arbitrary()?),
+        6 => FieldArgument::UnsignedLong(u.arbitrary()?),
+        7 => FieldArgument::SignedLongLong(u.arbitrary()?),
+        8 => FieldArgument::UnsignedLongLong(u.arbitrary()?),
+        9 => FieldArgument::Float(float(u)?),
+        10 => FieldArgument::Double(double(u)?),
+        11 => FieldArgument::Decimal(u.arbitrary()?),
+        12 => FieldArgument::ShortString(short_str(u)?),
+        13 => FieldArgument::LongString(long_str(u)?),
+        14 => FieldArgument::Timestamp(u.arbitrary()?),
+        15 => FieldArgument::Void,
+        16 => FieldArgument::ByteArray(byte_vec(u, MAX_LONG_STR_LEN)?),
+        17 => FieldArgument::NestedTable(field_table_n(u, depth + 1)?),
+        _ => {
+            let mut items = Vec::new();
+            for _ in 0..u.int_in_range(0..=MAX_TABLE_ENTRIES)? {
+                items.push(field_value(u, depth + 1)?);
+            }
+            FieldArgument::Array(items)
+        }
+    };
+    Ok(value)
+}
+
+
+/// NaN is a valid value on the wire, but it breaks equality of round-tripped values.
+fn float(u: &mut Unstructured) -> Result<f32> {
+    let f: f32 = u.arbitrary()?;
+    Ok(if f.is_nan() { 0.0 } else { f })
+}
+
+
+fn double(u: &mut Unstructured) -> Result<f64> {
+    let f: f64 = u.arbitrary()?;
+    Ok(if f.is_nan() { 0.0 } else { f })
+}
+// }}}
+
+
+// Field values {{{
+impl<'a> Arbitrary<'a> for AmqpString {
+    /// Generate a string which can be encoded as both short and long string.
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<AmqpString> {
+        short_str(u)
+    }
+}
+
+
+impl_arbitrary!(Decimal, |u| Decimal::new(u.arbitrary()?, u.arbitrary()?));
+
+impl_arbitrary!(FieldArgument, |u| field_value(u, 1)?);
+// }}}
+
+
+// Frame {{{
+impl_arbitrary!(Frame, |u| Frame {
+    header: FrameHeader { channel: u.arbitrary()? },
+    payload: u.arbitrary()?,
+});
+
+
+impl<'a> Arbitrary<'a> for FramePayload {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<FramePayload> {
+        let payload = match u.int_in_range(0..=3)? {
+            0 => FramePayload::Method(u.arbitrary()?),
+            1 => FramePayload::ContentHeader(u.arbitrary()?),
+            2 => FramePayload::ContentBody(u.arbitrary()?),
+            _ => FramePayload::Heartbeat(HEARTBEAT_TYPE_BYTE),
+        };
+        Ok(payload)
+    }
+}
+
+
+impl_arbitrary!(ContentBodyPayload, |u| ContentBodyPayload {
+    bytes: Bytes::from(byte_vec(u, MAX_BODY_LEN)?),
+});
+// }}}
+
+
+// Content header {{{
+impl<'a> Arbitrary<'a> for ContentHeaderPayload {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<ContentHeaderPayload> {
+        if u.ratio(1, 4)? {
+            // Class id 0 is invalid in content header.
+            let class_id = match u.int_in_range(1..=u16::max_value() - 1)? {
+                BASIC_CLASS_ID => u16::max_value(),
+                c => c,
+            };
+            Ok(ContentHeaderPayload {
+                class_id: class_id,
+                body_size: u.arbitrary()?,
+                properties: Properties::new(),
+                raw_properties: Some(u.arbitrary()?),
+            })
+        } else {
+            Ok(ContentHeaderPayload::basic(u.arbitrary()?, u.arbitrary()?))
+        }
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for RawProperties {
+    /// Generate flags whose continuation bits are already set as the encoder sets them.
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<RawProperties> {
+        let n_words = u.int_in_range(1..=3)?;
+        let mut flags = Vec::new();
+        for i in 0..n_words {
+            let word: u16 = u.arbitrary()?;
+            flags.push(word & !1 | (i + 1 < n_words) as u16);
+        }
+        Ok(RawProperties {
+            flags: flags,
+            list: Bytes::from(byte_vec(u, MAX_LONG_STR_LEN)?),
+        })
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for Properties {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Properties> {
+        fn option<'a, T, F>(u: &mut Unstructured<'a>, f: F) -> Result<Option<T>>
+        where
+            F: FnOnce(&mut Unstructured<'a>) -> Result<T>,
+        {
+            if u.arbitrary()? { f(u).map(Some) } else { Ok(None) }
+        }
+
+        Ok(Properties {
+            content_type: option(u, short_str)?,
+            content_encoding: option(u, short_str)?,
+            headers: option(u, field_table)?,
+            delivery_mode: u.arbitrary()?,
+            priority: u.arbitrary()?,
+            correlation_id: option(u, short_str)?,
+            reply_to: option(u, short_str)?,
+            expiration: option(u, short_str)?,
+            message_id: option(u, short_str)?,
+            timestamp: u.arbitrary()?,
+            type_: option(u, short_str)?,
+            user_id: option(u, short_str)?,
+            app_id: option(u, short_str)?,
+        })
+    }
+}
+// }}}
+
+
+// Method payload {{{
+impl<'a> Arbitrary<'a> for MethodPayload {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<MethodPayload> {
+        let payload = match u.int_in_range(0..=7)? {
+            0 => MethodPayload::Connection(u.arbitrary()?),
+            1 => MethodPayload::Channel(u.arbitrary()?),
+            2 => MethodPayload::Access(u.arbitrary()?),
+            3 => MethodPayload::Exchange(u.arbitrary()?),
+            4 => MethodPayload::Queue(u.arbitrary()?),
+            5 => MethodPayload::Basic(u.arbitrary()?),
+            6 => MethodPayload::Confirm(u.arbitrary()?),
+            _ => MethodPayload::Tx(u.arbitrary()?),
+        };
+        Ok(payload)
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for ConnectionClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<ConnectionClass> {
+        use self::ConnectionClass::*;
+        let method = match u.int_in_range(0..=14)? {
+            0 => Start(u.arbitrary()?),
+            1 => StartOk(u.arbitrary()?),
+            2 => Secure(u.arbitrary()?),
+            3 => SecureOk(u.arbitrary()?),
+            4 => Tune(u.arbitrary()?),
+            5 => TuneOk(u.arbitrary()?),
+            6 => Open(u.arbitrary()?),
+            7 => OpenOk(u.arbitrary()?),
+            8 => Close(u.arbitrary()?),
+            9 => CloseOk,
+            10 => Blocked(u.arbitrary()?),
+            11 => Unblocked,
+            12 => UpdateSecret(u.arbitrary()?),
+            _ => UpdateSecretOk,
+        };
+        Ok(method)
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for ChannelClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<ChannelClass> {
+        use self::ChannelClass::*;
+        let method = match u.int_in_range(0..=5)? {
+            0 => Open(u.arbitrary()?),
+            1 => OpenOk(u.arbitrary()?),
+            2 => Flow(u.arbitrary()?),
+            3 => FlowOk(u.arbitrary()?),
+            4 => Close(u.arbitrary()?),
+            _ => CloseOk,
+        };
+        Ok(method)
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for AccessClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<AccessClass> {
+        if u.arbitrary()? {
+            Ok(AccessClass::Request(u.arbitrary()?))
+        } else {
+            Ok(AccessClass::RequestOk(u.arbitrary()?))
+        }
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for ExchangeClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<ExchangeClass> {
+        use self::ExchangeClass::*;
+        let method = match u.int_in_range(0..=7)? {
+            0 => Declare(u.arbitrary()?),
+            1 => DeclareOk,
+            2 => Delete(u.arbitrary()?),
+            3 => DeleteOk,
+            4 => Bind(u.arbitrary()?),
+            5 => BindOk,
+            6 => Unbind(u.arbitrary()?),
+            _ => UnbindOk,
+        };
+        Ok(method)
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for QueueClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<QueueClass> {
+        use self::QueueClass::*;
+        let method = match u.int_in_range(0..=9)? {
+            0 => Declare(u.arbitrary()?),
+            1 => DeclareOk(u.arbitrary()?),
+            2 => Bind(u.arbitrary()?),
+            3 => BindOk,
+            4 => Unbind(u.arbitrary()?),
+            5 => UnbindOk,
+            6 => Purge(u.arbitrary()?),
+            7 => PurgeOk(u.arbitrary()?),
+            8 => Delete(u.arbitrary()?),
+            _ => DeleteOk(u.arbitrary()?),
+        };
+        Ok(method)
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for BasicClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<BasicClass> {
+        use self::BasicClass::*;
+        let method = match u.int_in_range(0..=17)? {
+            0 => Qos(u.arbitrary()?),
+            1 => QosOk,
+            2 => Consume(u.arbitrary()?),
+            3 => ConsumeOk(u.arbitrary()?),
+            4 => Cancel(u.arbitrary()?),
+            5 => CancelOk(u.arbitrary()?),
+            6 => Publish(u.arbitrary()?),
+            7 => Return(u.arbitrary()?),
+            8 => Deliver(u.arbitrary()?),
+            9 => Get(u.arbitrary()?),
+            10 => GetOk(u.arbitrary()?),
+            11 => GetEmpty(u.arbitrary()?),
+            12 => Ack(u.arbitrary()?),
+            13 => Reject(u.arbitrary()?),
+            14 => Nack(u.arbitrary()?),
+            15 => RecoverAsync(u.arbitrary()?),
+            16 => Recover(u.arbitrary()?),
+            _ => RecoverOk,
+        };
+        Ok(method)
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for ConfirmClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<ConfirmClass> {
+        if u.arbitrary()? {
+            Ok(ConfirmClass::Select(u.arbitrary()?))
+        } else {
+            Ok(ConfirmClass::SelectOk)
+        }
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for TxClass {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<TxClass> {
+        use self::TxClass::*;
+        Ok(u.choose(&[Select, SelectOk, Commit, CommitOk, Rollback, RollbackOk])?.clone())
+    }
+}
+// }}}
+
+
+// Connection methods {{{
+impl_arbitrary!(connection::StartMethod, |u| connection::StartMethod {
+    version_major: u.arbitrary()?,
+    version_minor: u.arbitrary()?,
+    server_properties: field_table(u)?,
+    mechanisms: long_str(u)?,
+    locales: long_str(u)?,
+});
+
+impl_arbitrary!(connection::StartOkMethod, |u| connection::StartOkMethod {
+    client_properties: field_table(u)?,
+    mechanism: short_str(u)?,
+    response: long_str(u)?,
+    locale: short_str(u)?,
+});
+
+impl_arbitrary!(connection::SecureMethod, |u| connection::SecureMethod {
+    challenge: long_str(u)?,
+});
+
+impl_arbitrary!(connection::SecureOkMethod, |u| connection::SecureOkMethod {
+    response: long_str(u)?,
+});
+
+impl_arbitrary!(connection::TuneMethod, |u| connection::TuneMethod {
+    channel_max: u.arbitrary()?,
+    frame_max: u.arbitrary()?,
+    heartbeat: u.arbitrary()?,
+});
+
+impl_arbitrary!(connection::TuneOkMethod, |u| connection::TuneOkMethod {
+    channel_max: u.arbitrary()?,
+    frame_max: u.arbitrary()?,
+    heartbeat: u.arbitrary()?,
+});
+
+impl_arbitrary!(connection::OpenMethod, |u| connection::OpenMethod {
+    virtual_host: short_str(u)?,
+    reserved1: short_str(u)?,
+    reserved2: u.arbitrary()?,
+});
+
+impl_arbitrary!(connection::OpenOkMethod, |u| connection::OpenOkMethod {
+    reserved1: short_str(u)?,
+});
+
+impl_arbitrary!(connection::CloseMethod, |u| connection::CloseMethod {
+    reply_code: u.arbitrary()?,
+    reply_text: short_str(u)?,
+    class_id: u.arbitrary()?,
+    method_id: u.arbitrary()?,
+});
+
+impl_arbitrary!(connection::RedirectMethod, |u| connection::RedirectMethod {
+    host: short_str(u)?,
+    known_hosts: short_str(u)?,
+});
+
+impl_arbitrary!(connection::BlockedMethod, |u| connection::BlockedMethod {
+    reason: short_str(u)?,
+});
+
+impl_arbitrary!(connection::UpdateSecretMethod, |u| connection::UpdateSecretMethod {
+    new_secret: long_str(u)?,
+    reason: short_str(u)?,
+});
+// }}}
+
+
+// Channel methods {{{
+impl_arbitrary!(channel::OpenMethod, |u| channel::OpenMethod { reserved1: short_str(u)? });
+
+impl_arbitrary!(channel::OpenOkMethod, |u| channel::OpenOkMethod { reserved1: long_str(u)? });
+
+impl_arbitrary!(channel::FlowMethod, |u| channel::FlowMethod { active: u.arbitrary()? });
+
+impl_arbitrary!(channel::FlowOkMethod, |u| channel::FlowOkMethod { active: u.arbitrary()? });
+
+impl_arbitrary!(channel::CloseMethod, |u| channel::CloseMethod {
+    reply_code: u.arbitrary()?,
+    reply_text: short_str(u)?,
+    class_id: u.arbitrary()?,
+    method_id: u.arbitrary()?,
+});
+
+impl_arbitrary!(channel::AlertMethod, |u| channel::AlertMethod {
+    reply_code: u.arbitrary()?,
+    reply_text: short_str(u)?,
+    details: field_table(u)?,
+});
+// }}}
+
+
+// Access methods {{{
+impl_arbitrary!(access::RequestMethod, |u| access::RequestMethod {
+    realm: short_str(u)?,
+    exclusive: u.arbitrary()?,
+    passive: u.arbitrary()?,
+    active: u.arbitrary()?,
+    write: u.arbitrary()?,
+    read: u.arbitrary()?,
+});
+
+impl_arbitrary!(access::RequestOkMethod, |u| access::RequestOkMethod { ticket: u.arbitrary()? });
+// }}}
+
+
+// Exchange methods {{{
+impl_arbitrary!(exchange::DeclareMethod, |u| exchange::DeclareMethod {
+    reserved1: u.arbitrary()?,
+    exchange: short_str(u)?,
+    typ: short_str(u)?,
+    passive: u.arbitrary()?,
+    durable: u.arbitrary()?,
+    auto_delete: u.arbitrary()?,
+    internal: u.arbitrary()?,
+    no_wait: u.arbitrary()?,
+    arguments: field_table(u)?,
+});
+
+impl_arbitrary!(exchange::DeleteMethod, |u| exchange::DeleteMethod {
+    reserved1: u.arbitrary()?,
+    exchange: short_str(u)?,
+    if_unused: u.arbitrary()?,
+    no_wait: u.arbitrary()?,
+});
+
+impl_arbitrary!(exchange::BindMethod, |u| exchange::BindMethod {
+    reserved1: u.arbitrary()?,
+    destination: short_str(u)?,
+    source: short_str(u)?,
+    routing_key: short_str(u)?,
+    no_wait: u.arbitrary()?,
+    arguments: field_table(u)?,
+});
+
+impl_arbitrary!(exchange::UnbindMethod, |u| exchange::UnbindMethod {
+    reserved1: u.arbitrary()?,
+    destination: short_str(u)?,
+    source: short_str(u)?,
+    routing_key: short_str(u)?,
+    no_wait: u.arbitrary()?,
+    arguments: field_table(u)?,
+});
+// }}}
+
+
+// Queue methods {{{
+impl_arbitrary!(queue::DeclareMethod, |u| queue::DeclareMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    passive: u.arbitrary()?,
+    durable: u.arbitrary()?,
+    exclusive: u.arbitrary()?,
+    auto_delete: u.arbitrary()?,
+    no_wait: u.arbitrary()?,
+    arguments: field_table(u)?,
+});
+
+impl_arbitrary!(queue::DeclareOkMethod, |u| queue::DeclareOkMethod {
+    queue: short_str(u)?,
+    message_count: u.arbitrary()?,
+    consumer_count: u.arbitrary()?,
+});
+
+impl_arbitrary!(queue::BindMethod, |u| queue::BindMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    exchange: short_str(u)?,
+    routing_key: short_str(u)?,
+    no_wait: u.arbitrary()?,
+    arguments: field_table(u)?,
+});
+
+impl_arbitrary!(queue::UnbindMethod, |u| queue::UnbindMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    exchange: short_str(u)?,
+    routing_key: short_str(u)?,
+    arguments: field_table(u)?,
+});
+
+impl_arbitrary!(queue::PurgeMethod, |u| queue::PurgeMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    no_wait: u.arbitrary()?,
+});
+
+impl_arbitrary!(queue::PurgeOkMethod, |u| queue::PurgeOkMethod {
+    message_count: u.arbitrary()?,
+});
+
+impl_arbitrary!(queue::DeleteMethod, |u| queue::DeleteMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    if_unused: u.arbitrary()?,
+    if_empty: u.arbitrary()?,
+    no_wait: u.arbitrary()?,
+});
+
+impl_arbitrary!(queue::DeleteOkMethod, |u| queue::DeleteOkMethod {
+    message_count: u.arbitrary()?,
+});
+// }}}
+
+
+// Basic methods {{{
+impl_arbitrary!(basic::QosMethod, |u| basic::QosMethod {
+    prefetch_size: u.arbitrary()?,
+    prefetch_count: u.arbitrary()?,
+    global: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::ConsumeMethod, |u| basic::ConsumeMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    consumer_tag: short_str(u)?,
+    no_local: u.arbitrary()?,
+    no_ack: u.arbitrary()?,
+    exclusive: u.arbitrary()?,
+    no_wait: u.arbitrary()?,
+    arguments: field_table(u)?,
+});
+
+impl_arbitrary!(basic::ConsumeOkMethod, |u| basic::ConsumeOkMethod {
+    consumer_tag: short_str(u)?,
+});
+
+impl_arbitrary!(basic::CancelMethod, |u| basic::CancelMethod {
+    consumer_tag: short_str(u)?,
+    no_wait: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::CancelOkMethod, |u| basic::CancelOkMethod {
+    consumer_tag: short_str(u)?,
+});
+
+impl_arbitrary!(basic::PublishMethod, |u| basic::PublishMethod {
+    reserved1: u.arbitrary()?,
+    exchange: short_str(u)?,
+    routing_key: short_str(u)?,
+    mandatory: u.arbitrary()?,
+    immediate: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::ReturnMethod, |u| basic::ReturnMethod {
+    reply_code: u.arbitrary()?,
+    reply_text: short_str(u)?,
+    exchange: short_str(u)?,
+    routing_key: short_str(u)?,
+});
+
+impl_arbitrary!(basic::DeliverMethod, |u| basic::DeliverMethod {
+    consumer_tag: short_str(u)?,
+    delivery_tag: u.arbitrary()?,
+    redeliverd: u.arbitrary()?,
+    exchange: short_str(u)?,
+    routing_key: short_str(u)?,
+});
+
+impl_arbitrary!(basic::GetMethod, |u| basic::GetMethod {
+    reserved1: u.arbitrary()?,
+    queue: short_str(u)?,
+    no_ack: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::GetOkMethod, |u| basic::GetOkMethod {
+    delivery_tag: u.arbitrary()?,
+    redeliverd: u.arbitrary()?,
+    exchange: short_str(u)?,
+    routing_key: short_str(u)?,
+    message_count: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::GetEmptyMethod, |u| basic::GetEmptyMethod {
+    reserved1: short_str(u)?,
+});
+
+impl_arbitrary!(basic::AckMethod, |u| basic::AckMethod {
+    delivery_tag: u.arbitrary()?,
+    multiple: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::RejectMethod, |u| basic::RejectMethod {
+    delivery_tag: u.arbitrary()?,
+    requeue: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::NackMethod, |u| basic::NackMethod {
+    delivery_tag: u.arbitrary()?,
+    multiple: u.arbitrary()?,
+    requeue: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::RecoverAsyncMethod, |u| basic::RecoverAsyncMethod {
+    requeue: u.arbitrary()?,
+});
+
+impl_arbitrary!(basic::RecoverMethod, |u| basic::RecoverMethod { requeue: u.arbitrary()? });
+// }}}
+
+
+// Confirm methods {{{
+impl_arbitrary!(confirm::SelectMethod, |u| confirm::SelectMethod { no_wait: u.arbitrary()? });
+// }}}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use config::{Config, Role};
+    use frame::decoder::decode_frame;
+    use frame::encoder::encode_frame;
+
+    /// Deterministic pseudo random bytes (xorshift), so failures can be reproduced.
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn arbitrary_frames_round_trip() {
+        let config = Config {
+            role: Role::Both,
+            ..Config::default()
+        };
+
+        for seed in 0..2000 {
+            let bytes = random_bytes(seed, 4096);
+            let frame = Frame::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+
+            let mut dst = BytesMut::new();
+            encode_frame(frame.clone(), &mut dst, &config)
+                .unwrap_or_else(|e| panic!("seed {} : {} : {:?}", seed, e, frame));
+            let decoded = decode_frame(&mut dst, &config).unwrap().unwrap();
+
+            assert_eq!(decoded, frame, "seed {}", seed);
+            assert_eq!(dst.len(), 0);
+        }
+    }
+}
+// }}}
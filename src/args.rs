@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 use std::ops::Deref;
 use std::convert::From;
+use std::fmt;
+use std::str::FromStr;
+use std::error::Error;
 
 use bytes::Bytes;
 
@@ -18,23 +21,157 @@ pub enum FieldArgument {
     UnsignedLongLong(u64),
     Float(f32),
     Double(f64),
-    Decimal(i64), // For now, we do not handle big number which is bigger than max of i64.
+    Decimal(Decimal),
     ShortString(AmqpString),
     LongString(AmqpString),
-    // Array(), // I can not find any definition of this field.
+    Array(Vec<FieldArgument>),
     Timestamp(u64),
     NestedTable(HashMap<AmqpString, FieldArgument>),
     Void,
-    ByteArray(Vec<u8>), // How can we treat it
+    ByteArray(Vec<u8>),
 }
 
 
 
+/// Decimal field value. It represents `value * 10^(-scale)`.
+///
+/// `PartialEq` compares representation, so `1.0` (scale 1) and `1.00` (scale 2) are not equal.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Decimal {
+    pub scale: u8,
+    pub value: i32,
+}
+
+
+impl Decimal {
+    pub fn new(scale: u8, value: i32) -> Decimal {
+        Decimal {
+            scale: scale,
+            value: value,
+        }
+    }
+
+    /// Make a decimal which has `scale` digits after the decimal point.
+    /// Returns `None` if `value` is not finite or does not fit in `i32` with the scale.
+    pub fn from_f64(value: f64, scale: u8) -> Option<Decimal> {
+        let scaled = (value * 10_f64.powi(scale as i32)).round();
+        if !scaled.is_finite() || scaled < i32::min_value() as f64 ||
+            scaled > i32::max_value() as f64
+        {
+            return None;
+        }
+        Some(Decimal::new(scale, scaled as i32))
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.value as f64 / 10_f64.powi(self.scale as i32)
+    }
+}
+
+
+impl From<Decimal> for f64 {
+    fn from(decimal: Decimal) -> f64 {
+        decimal.to_f64()
+    }
+}
+
+
+/// Formats without rounding, e.g. `Decimal::new(3, -1500)` is "-1.500".
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.value < 0 { "-" } else { "" };
+        let width = self.scale as usize + 1;
+        let digits = format!("{:0>width$}", (self.value as i64).abs(), width = width);
+        let (int, frac) = digits.split_at(digits.len() - self.scale as usize);
+        if frac.is_empty() {
+            write!(f, "{}{}", sign, int)
+        } else {
+            write!(f, "{}{}.{}", sign, int, frac)
+        }
+    }
+}
+
+
+/// Parses a string such as "-1.500". The number of digits after the decimal point becomes
+/// the scale.
+impl FromStr for Decimal {
+    type Err = ParseDecimalError;
+
+    fn from_str(s: &str) -> Result<Decimal, ParseDecimalError> {
+        let (negative, unsigned) = match s.as_bytes().first() {
+            Some(&b'-') => (true, &s[1..]),
+            Some(&b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (int, frac) = match unsigned.find('.') {
+            Some(i) => (&unsigned[..i], &unsigned[i + 1..]),
+            None => (unsigned, ""),
+        };
+
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if int.is_empty() || !is_digits(int) || !is_digits(frac) ||
+            (unsigned.contains('.') && frac.is_empty())
+        {
+            return Err(ParseDecimalError::Invalid);
+        }
+        if frac.len() > u8::max_value() as usize {
+            return Err(ParseDecimalError::OutOfRange);
+        }
+
+        let mut value: i64 = 0;
+        for b in int.bytes().chain(frac.bytes()) {
+            value = value * 10 + (b - b'0') as i64;
+            if value > i32::max_value() as i64 + 1 {
+                return Err(ParseDecimalError::OutOfRange);
+            }
+        }
+        let value = if negative { -value } else { value };
+        if value > i32::max_value() as i64 {
+            return Err(ParseDecimalError::OutOfRange);
+        }
+
+        Ok(Decimal::new(frac.len() as u8, value as i32))
+    }
+}
+
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ParseDecimalError {
+    /// String is not a decimal number.
+    Invalid,
+    /// Number of digits does not fit in `Decimal`.
+    OutOfRange,
+}
+
+
+impl fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseDecimalError::Invalid => write!(f, "invalid decimal"),
+            ParseDecimalError::OutOfRange => write!(f, "decimal is out of range"),
+        }
+    }
+}
+
+
+impl Error for ParseDecimalError {}
+
+
+
 /// String being able to do Zero-cost conversion from `Bytes` or `&'static str`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AmqpString(pub(crate) Bytes);
 
 
+impl AmqpString {
+    /// Raw bytes of the string. Long strings (e.g. SASL response) may not be utf-8, so use
+    /// this instead of dereferencing to `str` for them.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+
 impl Deref for AmqpString {
     type Target = str;
 
@@ -67,3 +204,38 @@ impl From<&'static str> for AmqpString {
         AmqpString(Bytes::from_static(bytes.as_bytes()))
     }
 }
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimal_to_and_from_string() {
+        let cases = [
+            ("1.500", Decimal::new(3, 1500)),
+            ("-0.05", Decimal::new(2, -5)),
+            ("42", Decimal::new(0, 42)),
+            ("-2147483648", Decimal::new(0, i32::min_value())),
+        ];
+        for &(s, decimal) in cases.iter() {
+            assert_eq!(s.parse::<Decimal>(), Ok(decimal));
+            assert_eq!(decimal.to_string(), s);
+        }
+
+        assert_eq!("1.".parse::<Decimal>(), Err(ParseDecimalError::Invalid));
+        assert_eq!("a.1".parse::<Decimal>(), Err(ParseDecimalError::Invalid));
+        assert_eq!("2147483648".parse::<Decimal>(), Err(ParseDecimalError::OutOfRange));
+    }
+
+    #[test]
+    fn decimal_to_and_from_f64() {
+        assert_eq!(Decimal::from_f64(-1.25, 2), Some(Decimal::new(2, -125)));
+        assert_eq!(f64::from(Decimal::new(2, -125)), -1.25);
+        assert_eq!(Decimal::from_f64(1e10, 0), None);
+        assert_eq!(Decimal::from_f64(::std::f64::NAN, 0), None);
+    }
+}
+// }}}
@@ -0,0 +1,290 @@
+//! Settings of `Codec`.
+//!
+//! Most of these settings are decided while handshaking (e.g. `frame_max` by
+//! `Connection.Tune` / `Connection.TuneOk`), so all fields are public and can be updated
+//! at any time through `Codec::config_mut`.
+
+use std::error::Error;
+use std::fmt;
+
+use frame::{FrameType, CONTENT_BODY_TYPE_BYTE, HEARTBEAT_TYPE_BYTE, LEGACY_HEARTBEAT_TYPE_BYTE,
+            METHOD_TYPE_BYTE};
+use frame::protocol_header::ProtocolHeader;
+
+/// frame_max used until `Connection.Tune` is negotiated. This is the default value of RabbitMQ.
+pub const DEFAULT_FRAME_MAX: u32 = 131_072;
+
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Config {
+    /// Protocol version which decides layouts of methods and the protocol header.
+    /// Unlike other fields, this should not be changed after the protocol header is sent.
+    pub protocol_version: ProtocolVersion,
+
+    /// Which side of the connection this codec works for. Methods which this side can not
+    /// send are rejected while encoding, and methods which the peer can not send are
+    /// rejected while decoding.
+    pub role: Role,
+
+    /// Maximum frame size in bytes, including frame header and frame end.
+    /// Zero means no limit.
+    pub frame_max: u32,
+
+    /// Highest channel id which can be used. Zero means no limit.
+    pub channel_max: u16,
+
+    /// Limits applied while decoding field tables and long strings.
+    pub limits: DecodeLimits,
+
+    /// If true, unknown frame types, classes and methods are decoded into
+    /// `FramePayload::Unknown` / `MethodPayload::Unknown` instead of being errors.
+    /// These are encoded back byte-for-byte, so proxies can forward them.
+    pub passthrough_unknown: bool,
+
+    /// Type byte written into heartbeat frames. Default is 8 as defined in the spec.
+    /// Type bytes of method, content header and content body (1 to 3) are invalid.
+    /// Use `set_heartbeat_frame_type` to have it checked.
+    pub heartbeat_frame_type: u8,
+
+    /// If true, both 8 and legacy 4 are decoded as heartbeat frame in addition to
+    /// `heartbeat_frame_type`. If false, only `heartbeat_frame_type` is.
+    pub accept_legacy_heartbeat: bool,
+
+    /// If true, channel of every frame is checked against its payload in both directions.
+    /// See `Frame::check_channel`.
+    pub validate_channels: bool,
+
+    /// If true, `Codec` checks order of content frames on each channel in both directions.
+    /// See `ContentSequence`.
+    pub validate_content_sequence: bool,
+
+    /// Which type tags are used for field values in both directions.
+    pub field_dialect: FieldDialect,
+}
+
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            protocol_version: ProtocolVersion::default(),
+            role: Role::default(),
+            frame_max: DEFAULT_FRAME_MAX,
+            channel_max: 0,
+            limits: DecodeLimits::default(),
+            passthrough_unknown: false,
+            heartbeat_frame_type: HEARTBEAT_TYPE_BYTE,
+            accept_legacy_heartbeat: true,
+            validate_channels: false,
+            validate_content_sequence: false,
+            field_dialect: FieldDialect::default(),
+        }
+    }
+}
+
+
+impl Config {
+    /// Check whether a frame with `size` bytes (including frame header and frame end) is
+    /// acceptable.
+    pub fn is_acceptable_frame_size(&self, size: usize) -> bool {
+        self.frame_max == 0 || size <= self.frame_max as usize
+    }
+
+    pub fn is_acceptable_channel(&self, channel: u16) -> bool {
+        self.channel_max == 0 || channel <= self.channel_max
+    }
+
+    /// Set `heartbeat_frame_type`, rejecting type bytes of other frames.
+    pub fn set_heartbeat_frame_type(&mut self, byte: u8) -> Result<(), InvalidHeartbeatType> {
+        if !is_valid_heartbeat_type(byte) {
+            return Err(InvalidHeartbeatType(byte));
+        }
+        self.heartbeat_frame_type = byte;
+        Ok(())
+    }
+
+    /// Check settings which can not be enforced by types.
+    pub fn is_valid(&self) -> bool {
+        is_valid_heartbeat_type(self.heartbeat_frame_type)
+    }
+
+    /// Decide how a frame type byte is treated by a codec using this config.
+    pub fn frame_type_of(&self, byte: u8) -> FrameType {
+        let is_known_heartbeat = byte == HEARTBEAT_TYPE_BYTE ||
+            byte == LEGACY_HEARTBEAT_TYPE_BYTE;
+        // Invalid heartbeat_frame_type must not turn data frames into heartbeats.
+        let is_configured_heartbeat = byte == self.heartbeat_frame_type &&
+            is_valid_heartbeat_type(byte);
+        if is_configured_heartbeat || (self.accept_legacy_heartbeat && is_known_heartbeat) {
+            return FrameType::Heartbeat;
+        }
+
+        match FrameType::from(byte) {
+            // Heartbeat type byte which is not accepted by this config
+            FrameType::Heartbeat => FrameType::Unknown(byte),
+            typ => typ,
+        }
+    }
+
+    /// Type byte written into frames of `typ`.
+    pub fn type_byte_of(&self, typ: FrameType) -> u8 {
+        match typ {
+            FrameType::Heartbeat => self.heartbeat_frame_type,
+            typ => u8::from(typ),
+        }
+    }
+}
+
+
+fn is_valid_heartbeat_type(byte: u8) -> bool {
+    byte < METHOD_TYPE_BYTE || byte > CONTENT_BODY_TYPE_BYTE
+}
+
+
+/// `Config::heartbeat_frame_type` is a type byte of method, content header or content body.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct InvalidHeartbeatType(pub u8);
+
+
+impl fmt::Display for InvalidHeartbeatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not a valid heartbeat frame type", self.0)
+    }
+}
+
+
+impl Error for InvalidHeartbeatType {}
+
+
+
+/// Version of AMQP protocol.
+///
+/// Most methods have the same layout in all versions. Differences handled by this crate are
+///
+/// - `Connection.Close` / `Connection.CloseOk` are method 60 / 61 in 0-8.
+/// - `Connection.Redirect` exists in 0-8 (method 50) and 0-9 (method 42).
+/// - `Channel.Alert` exists only in 0-8, and `Channel.OpenOk` has no field in 0-8.
+/// - `Basic.Consume` has no arguments table in 0-8.
+/// - `Exchange.Bind` / `Exchange.Unbind`, `Queue.Unbind` and `Confirm` class do not exist in
+///   0-8.
+/// - `Connection.UpdateSecret` / `Connection.UpdateSecretOk` exist only in 0-9-1.
+/// - Protocol header.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ProtocolVersion {
+    Amqp0_8,
+    Amqp0_9,
+    Amqp0_9_1,
+}
+
+
+impl ProtocolVersion {
+    pub fn protocol_header(&self) -> ProtocolHeader {
+        match *self {
+            ProtocolVersion::Amqp0_8 => ProtocolHeader::amqp_0_8(),
+            ProtocolVersion::Amqp0_9 => ProtocolHeader::amqp_0_9(),
+            ProtocolVersion::Amqp0_9_1 => ProtocolHeader::amqp_0_9_1(),
+        }
+    }
+}
+
+
+impl Default for ProtocolVersion {
+    fn default() -> ProtocolVersion {
+        ProtocolVersion::Amqp0_9_1
+    }
+}
+
+
+
+/// Side of a connection.
+///
+/// Methods also tell which side sends them by `MethodPayload::sent_by`, where `Both` means
+/// the method can be sent by either side (e.g. `Connection.Close`).
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Role {
+    Client,
+    Server,
+    /// Accept every method in both directions. Useful for proxies and tests.
+    Both,
+}
+
+
+impl Role {
+    /// Whether this side can send a method which is sent by `sender`.
+    pub fn can_send(&self, sender: Role) -> bool {
+        match (*self, sender) {
+            (Role::Both, _) | (_, Role::Both) => true,
+            (role, sender) => role == sender,
+        }
+    }
+
+    /// Whether this side can receive a method which is sent by `sender`.
+    pub fn can_receive(&self, sender: Role) -> bool {
+        match (*self, sender) {
+            (Role::Both, _) | (_, Role::Both) => true,
+            (role, sender) => role != sender,
+        }
+    }
+}
+
+
+impl Default for Role {
+    fn default() -> Role {
+        Role::Client
+    }
+}
+
+
+
+/// Set of type tags of field values.
+///
+/// The spec and brokers disagree on some tags, e.g. 's' is a short string in the spec but a
+/// signed 16-bit integer in RabbitMQ and Qpid. Values which the dialect does not support
+/// (e.g. unsigned integers in RabbitMQ) are encoded as a wider type.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum FieldDialect {
+    /// Tags defined in the 0-9-1 spec.
+    Spec0_9_1,
+    /// Tags which RabbitMQ uses.
+    RabbitMQ,
+    /// Tags which Qpid uses.
+    Qpid,
+}
+
+
+impl Default for FieldDialect {
+    fn default() -> FieldDialect {
+        FieldDialect::Spec0_9_1
+    }
+}
+
+
+
+/// Limits to protect ourselves from hostile input.
+/// Every length prefix in a received frame is checked against these before it is trusted.
+#[derive(PartialEq, Clone, Debug)]
+pub struct DecodeLimits {
+    /// How deep field tables and field arrays can be nested. Top level table is depth 1.
+    pub max_table_depth: usize,
+
+    /// Maximum number of entries in a field table or items in a field array
+    /// (nested ones are counted separately).
+    pub max_table_entries: usize,
+
+    /// Maximum size of a field table or a field array in bytes.
+    pub max_table_bytes: usize,
+
+    /// Maximum length of a long string or a byte array in bytes.
+    pub max_long_str_len: usize,
+}
+
+
+impl Default for DecodeLimits {
+    fn default() -> DecodeLimits {
+        DecodeLimits {
+            max_table_depth: 32,
+            max_table_entries: 8192,
+            max_table_bytes: 1024 * 1024,
+            max_long_str_len: 1024 * 1024,
+        }
+    }
+}
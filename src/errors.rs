@@ -0,0 +1,447 @@
+//! Error types returned by the frame decoder and encoder.
+//!
+//! Every decode error carries the channel and the byte offset at which it was found, and maps
+//! to a reply code defined by the AMQP spec. A connection can use it to close itself cleanly
+//! with `connection::CloseMethod` instead of just dropping the socket.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use args::AmqpString;
+use frame::method::connection::CloseMethod;
+use frame::protocol_header::ProtocolHeader;
+
+
+/// Reply codes defined in the AMQP 0-9-1 spec.
+/// Only codes which can be caused by a frame level error are listed.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ReplyCode {
+    FrameError,
+    SyntaxError,
+    CommandInvalid,
+    ChannelError,
+    UnexpectedFrame,
+    ResourceError,
+    NotAllowed,
+    NotImplemented,
+    InternalError,
+}
+
+
+// Implementation of ReplyCode {{{
+impl ReplyCode {
+    pub fn code(&self) -> u16 {
+        match *self {
+            ReplyCode::FrameError => 501,
+            ReplyCode::SyntaxError => 502,
+            ReplyCode::CommandInvalid => 503,
+            ReplyCode::ChannelError => 504,
+            ReplyCode::UnexpectedFrame => 505,
+            ReplyCode::ResourceError => 506,
+            ReplyCode::NotAllowed => 530,
+            ReplyCode::NotImplemented => 540,
+            ReplyCode::InternalError => 541,
+        }
+    }
+
+    /// Name of this reply code as written in the spec (e.g. "FRAME_ERROR").
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReplyCode::FrameError => "FRAME_ERROR",
+            ReplyCode::SyntaxError => "SYNTAX_ERROR",
+            ReplyCode::CommandInvalid => "COMMAND_INVALID",
+            ReplyCode::ChannelError => "CHANNEL_ERROR",
+            ReplyCode::UnexpectedFrame => "UNEXPECTED_FRAME",
+            ReplyCode::ResourceError => "RESOURCE_ERROR",
+            ReplyCode::NotAllowed => "NOT_ALLOWED",
+            ReplyCode::NotImplemented => "NOT_IMPLEMENTED",
+            ReplyCode::InternalError => "INTERNAL_ERROR",
+        }
+    }
+}
+// }}}
+
+
+
+// ChannelViolation {{{
+/// A frame is placed on a channel where the spec does not allow it.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ChannelViolation {
+    /// Connection class method on a non-zero channel.
+    ConnectionMethodOnChannel { method_id: u16 },
+    /// Method of other classes on channel zero.
+    MethodOnChannelZero { class_id: u16, method_id: u16 },
+    /// Content header or content body frame on channel zero.
+    ContentOnChannelZero,
+    /// Heartbeat frame on a non-zero channel.
+    HeartbeatOnChannel,
+}
+
+
+impl ChannelViolation {
+    pub fn reply_code(&self) -> ReplyCode {
+        match *self {
+            ChannelViolation::ConnectionMethodOnChannel { .. } => ReplyCode::CommandInvalid,
+            ChannelViolation::MethodOnChannelZero { .. } => ReplyCode::ChannelError,
+            ChannelViolation::ContentOnChannelZero => ReplyCode::ChannelError,
+            ChannelViolation::HeartbeatOnChannel => ReplyCode::FrameError,
+        }
+    }
+
+    /// Class id and method id of the method which caused this violation.
+    pub fn method(&self) -> Option<(u16, u16)> {
+        match *self {
+            ChannelViolation::ConnectionMethodOnChannel { method_id } => Some((10, method_id)),
+            ChannelViolation::MethodOnChannelZero { class_id, method_id } => {
+                Some((class_id, method_id))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+impl fmt::Display for ChannelViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChannelViolation::ConnectionMethodOnChannel { method_id } => write!(
+                f,
+                "connection method {} on non-zero channel",
+                method_id
+            ),
+            ChannelViolation::MethodOnChannelZero { class_id, method_id } => write!(
+                f,
+                "method {} of class {} on channel 0",
+                method_id,
+                class_id
+            ),
+            ChannelViolation::ContentOnChannelZero => write!(f, "content frame on channel 0"),
+            ChannelViolation::HeartbeatOnChannel => write!(f, "heartbeat on non-zero channel"),
+        }
+    }
+}
+// }}}
+
+
+
+// SequenceError {{{
+/// Content frames are not sent in the order the spec requires. See `ContentSequence`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum SequenceError {
+    /// Content header without a preceding content-bearing method.
+    OrphanHeader,
+    /// Content body without a preceding content header.
+    OrphanBody,
+    /// Method in the middle of content.
+    InterleavedMethod { class_id: u16, method_id: u16 },
+    /// class_id of content header differs from the class of the content-bearing method.
+    ClassMismatch { expected: u16, found: u16 },
+    /// Content bodies are larger than `body_size` in the content header.
+    BodyOverrun { body_size: u64, received: u64 },
+    /// Next content header arrives before content bodies reach `body_size`.
+    BodyUnderrun { body_size: u64, received: u64 },
+}
+
+
+impl SequenceError {
+    pub fn reply_code(&self) -> ReplyCode {
+        ReplyCode::UnexpectedFrame
+    }
+
+    /// Class id and method id of the method which caused this error.
+    pub fn method(&self) -> Option<(u16, u16)> {
+        match *self {
+            SequenceError::InterleavedMethod { class_id, method_id } => {
+                Some((class_id, method_id))
+            }
+            _ => None,
+        }
+    }
+}
+
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SequenceError::OrphanHeader => write!(f, "content header without method"),
+            SequenceError::OrphanBody => write!(f, "content body without content header"),
+            SequenceError::InterleavedMethod { class_id, method_id } => write!(
+                f,
+                "method {} of class {} in the middle of content",
+                method_id,
+                class_id
+            ),
+            SequenceError::ClassMismatch { expected, found } => write!(
+                f,
+                "content header of class {} after method of class {}",
+                found,
+                expected
+            ),
+            SequenceError::BodyOverrun { body_size, received } => write!(
+                f,
+                "content body overruns body size {} ({} bytes)",
+                body_size,
+                received
+            ),
+            SequenceError::BodyUnderrun { body_size, received } => write!(
+                f,
+                "content body underruns body size {} ({} bytes)",
+                body_size,
+                received
+            ),
+        }
+    }
+}
+// }}}
+
+
+
+// DecodeError {{{
+/// Error returned when received bytes can not be decoded into a `Frame`.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Channel of the frame being decoded.
+    pub channel: u16,
+    /// Byte offset from the beginning of the frame (including the frame header).
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+
+#[derive(Debug)]
+pub enum DecodeErrorKind {
+    Io(io::Error),
+    /// Server rejected our protocol version and proposes the version it supports.
+    ProtocolVersionMismatch(ProtocolHeader),
+    /// Frame does not end with `FRAME_END_OCTET`. Holds the octet found instead.
+    BadFrameEnd(u8),
+    /// Frame is larger than negotiated frame_max. `size` includes frame header and frame end.
+    FrameTooLarge { size: usize, frame_max: u32 },
+    UnknownFrameType(u8),
+    UnknownClass(u16),
+    UnknownMethod { class_id: u16, method_id: u16 },
+    /// The method is known, but the peer is not supposed to send it in `Config::role`.
+    UnexpectedMethod { class_id: u16, method_id: u16 },
+    /// Payload ended before all fields are decoded.
+    TruncatedPayload,
+    BadFieldTag(u8),
+    InvalidUtf8,
+    /// Content header has a property flag which is not defined in the class.
+    /// `index` counts from the highest bit of the first flags word.
+    UnknownProperty { class_id: u16, index: usize },
+    /// Field tables are nested deeper than `DecodeLimits::max_table_depth`.
+    TableTooDeep(usize),
+    /// Field table has more entries than `DecodeLimits::max_table_entries`.
+    TooManyTableEntries(usize),
+    /// Field table is larger than `DecodeLimits::max_table_bytes`. Holds the size prefix.
+    TableTooLarge(usize),
+    /// Long string is longer than `DecodeLimits::max_long_str_len`. Holds the length prefix.
+    LongStringTooLong(usize),
+    /// Frame is received on a wrong channel. Checked only if `Config::validate_channels` is set.
+    ChannelViolation(ChannelViolation),
+    /// Content frames are received in a wrong order.
+    /// Checked only if `Config::validate_content_sequence` is set.
+    ContentSequence(SequenceError),
+}
+
+
+impl DecodeError {
+    pub fn new(channel: u16, offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            channel: channel,
+            offset: offset,
+            kind: kind,
+        }
+    }
+
+    pub fn reply_code(&self) -> ReplyCode {
+        use self::DecodeErrorKind::*;
+        match self.kind {
+            Io(_) => ReplyCode::InternalError,
+            ProtocolVersionMismatch(_) => ReplyCode::NotImplemented,
+            BadFrameEnd(_) |
+            FrameTooLarge { .. } |
+            UnknownFrameType(_) |
+            TruncatedPayload => ReplyCode::FrameError,
+            BadFieldTag(_) | InvalidUtf8 | UnknownProperty { .. } => ReplyCode::SyntaxError,
+            UnknownClass(_) | UnknownMethod { .. } => ReplyCode::NotImplemented,
+            UnexpectedMethod { .. } => ReplyCode::CommandInvalid,
+            TableTooDeep(_) |
+            TooManyTableEntries(_) |
+            TableTooLarge(_) |
+            LongStringTooLong(_) => ReplyCode::ResourceError,
+            ChannelViolation(ref v) => v.reply_code(),
+            ContentSequence(ref e) => e.reply_code(),
+        }
+    }
+
+    /// Make a `Connection.Close` method which reports this error to the peer.
+    pub fn close_method(&self) -> CloseMethod {
+        let (class_id, method_id) = match self.kind {
+            DecodeErrorKind::UnknownClass(c) => (c, 0),
+            DecodeErrorKind::UnknownMethod { class_id, method_id } |
+            DecodeErrorKind::UnexpectedMethod { class_id, method_id } => (class_id, method_id),
+            DecodeErrorKind::ChannelViolation(ref v) => v.method().unwrap_or((0, 0)),
+            DecodeErrorKind::ContentSequence(ref e) => e.method().unwrap_or((0, 0)),
+            _ => (0, 0),
+        };
+        CloseMethod {
+            reply_code: self.reply_code().code(),
+            reply_text: AmqpString::from(format!("{} - {}", self.reply_code().name(), self)),
+            class_id: class_id,
+            method_id: method_id,
+        }
+    }
+}
+
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DecodeErrorKind::*;
+        match self.kind {
+            Io(ref e) => write!(f, "io error : {}", e),
+            ProtocolVersionMismatch(ref h) => write!(
+                f,
+                "server proposes protocol version {}-{}-{} (protocol id {})",
+                h.major,
+                h.minor,
+                h.revision,
+                h.protocol_id
+            ),
+            BadFrameEnd(b) => write!(f, "invalid frame end octet {:#04x}", b),
+            FrameTooLarge { size, frame_max } => {
+                write!(f, "frame size {} exceeds frame_max {}", size, frame_max)
+            }
+            UnknownFrameType(t) => write!(f, "unknown frame type {}", t),
+            UnknownClass(c) => write!(f, "unknown class id {}", c),
+            UnknownMethod { class_id, method_id } => {
+                write!(f, "unknown method id {} in class {}", method_id, class_id)
+            }
+            UnexpectedMethod { class_id, method_id } => {
+                write!(f, "method {} in class {} is not expected from peer", method_id, class_id)
+            }
+            TruncatedPayload => write!(f, "truncated payload"),
+            BadFieldTag(t) => write!(f, "unknown field value tag {:#04x}", t),
+            InvalidUtf8 => write!(f, "string is not valid utf-8"),
+            UnknownProperty { class_id, index } => {
+                write!(f, "unknown property {} in class {}", index, class_id)
+            }
+            TableTooDeep(depth) => write!(f, "field table is nested too deep ({})", depth),
+            TooManyTableEntries(n) => write!(f, "field table has too many entries ({})", n),
+            TableTooLarge(size) => write!(f, "field table is too large ({} bytes)", size),
+            LongStringTooLong(len) => write!(f, "long string is too long ({} bytes)", len),
+            ChannelViolation(ref v) => write!(f, "{}", v),
+            ContentSequence(ref e) => write!(f, "{}", e),
+        }?;
+        write!(f, " (channel {}, offset {})", self.channel, self.offset)
+    }
+}
+
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.kind {
+            DecodeErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> DecodeError {
+        DecodeError::new(0, 0, DecodeErrorKind::Io(e))
+    }
+}
+// }}}
+
+
+
+// EncodeError {{{
+/// Error returned when a `Frame` can not be encoded.
+/// Nothing is written into the destination buffer when this error is returned.
+#[derive(Debug)]
+pub struct EncodeError {
+    /// Channel of the frame being encoded.
+    pub channel: u16,
+    pub kind: EncodeErrorKind,
+}
+
+
+#[derive(Debug)]
+pub enum EncodeErrorKind {
+    Io(io::Error),
+    /// Short string is longer than 255 bytes. Holds the actual length.
+    ShortStringTooLong(usize),
+    /// Long string is longer than `u32::MAX` bytes. Holds the actual length.
+    LongStringTooLong(usize),
+    /// Encoded frame is larger than negotiated frame_max.
+    /// `size` includes frame header and frame end.
+    FrameTooLarge { size: usize, frame_max: u32 },
+    /// Channel id is higher than negotiated channel_max.
+    ChannelOutOfRange { channel_max: u16 },
+    /// The method is not supposed to be sent by this side in `Config::role`, or does not
+    /// exist in `Config::protocol_version`.
+    UnsupportedMethod { class_id: u16, method_id: u16 },
+    UnencodableField(&'static str),
+    /// Frame is going to be sent on a wrong channel.
+    /// Checked only if `Config::validate_channels` is set.
+    ChannelViolation(ChannelViolation),
+    /// Content frames are going to be sent in a wrong order.
+    /// Checked only if `Config::validate_content_sequence` is set.
+    ContentSequence(SequenceError),
+}
+
+
+impl EncodeError {
+    pub fn new(channel: u16, kind: EncodeErrorKind) -> EncodeError {
+        EncodeError {
+            channel: channel,
+            kind: kind,
+        }
+    }
+}
+
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EncodeErrorKind::*;
+        match self.kind {
+            Io(ref e) => write!(f, "io error : {}", e),
+            ShortStringTooLong(len) => write!(f, "short string is too long ({} bytes)", len),
+            LongStringTooLong(len) => write!(f, "long string is too long ({} bytes)", len),
+            FrameTooLarge { size, frame_max } => {
+                write!(f, "frame size {} exceeds frame_max {}", size, frame_max)
+            }
+            ChannelOutOfRange { channel_max } => {
+                write!(f, "channel id exceeds channel_max {}", channel_max)
+            }
+            UnsupportedMethod { class_id, method_id } => {
+                write!(f, "method {} in class {} can not be sent", method_id, class_id)
+            }
+            UnencodableField(ref reason) => write!(f, "unencodable field value : {}", reason),
+            ChannelViolation(ref v) => write!(f, "{}", v),
+            ContentSequence(ref e) => write!(f, "{}", e),
+        }?;
+        write!(f, " (channel {})", self.channel)
+    }
+}
+
+
+impl Error for EncodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.kind {
+            EncodeErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for EncodeError {
+    fn from(e: io::Error) -> EncodeError {
+        EncodeError::new(0, EncodeErrorKind::Io(e))
+    }
+}
+// }}}
@@ -3,100 +3,154 @@ use bytes::{BytesMut, BigEndian, Buf};
 use std::io::Cursor;
 use std::collections::HashMap;
 
-use super::{ContentHeaderPayload, Properties};
+use super::{ContentHeaderPayload, Properties, RawProperties, BASIC_CLASS_ID};
 use args::{AmqpString, FieldArgument};
+use config::Config;
+use errors::DecodeErrorKind;
+use frame::method::decoder::take_bytes;
 
 const NUM_OF_PROPERTY: usize = 13;
+/// Index of reserved "cluster-id" property of Basic class.
+const CLUSTER_ID_PROPERTY: usize = 13;
+const FLAGS_PER_WORD: usize = 15;
 
-pub fn decode_payload(payload: &mut BytesMut) -> ContentHeaderPayload {
-    let properties = decode_properties(payload.split_off(12));
-
-    let mut others_cursor = Cursor::new(payload.take());
+pub fn decode_payload(
+    payload: &mut BytesMut,
+    config: &Config,
+) -> Result<ContentHeaderPayload, DecodeErrorKind> {
+    let mut others_cursor = Cursor::new(take_bytes(payload, 12)?);
 
     let class_id = others_cursor.get_u16::<BigEndian>();
-    assert!(class_id != 0);
+    if class_id == 0 {
+        return Err(DecodeErrorKind::UnknownClass(class_id));
+    }
 
     let weight = others_cursor.get_u16::<BigEndian>(); // must be zero
-    assert_eq!(weight, 0);
+    if weight != 0 {
+        debug!("weight field of content header is not zero : {}", weight);
+    }
 
     let body_size = others_cursor.get_u64::<BigEndian>();
     drop(others_cursor);
 
+    let flags = decode_property_flags(payload)?;
+    let (properties, raw_properties) = if class_id == BASIC_CLASS_ID {
+        (decode_properties(&flags, payload, config)?, None)
+    } else {
+        let raw = RawProperties {
+            flags: flags,
+            list: payload.take().freeze(),
+        };
+        (Properties::new(), Some(raw))
+    };
+
     let payload = ContentHeaderPayload {
         class_id: class_id,
         body_size: body_size,
         properties: properties,
+        raw_properties: raw_properties,
     };
 
-    payload
+    Ok(payload)
 }
 
 
 
-fn decode_properties(mut bytes: BytesMut) -> Properties {
-    let mut flags = Cursor::new(bytes.split_to(2)).get_u16::<BigEndian>();
+/// Decode property flags words.
+/// The lowest bit of each word tells whether another word follows.
+fn decode_property_flags(bytes: &mut BytesMut) -> Result<Vec<u16>, DecodeErrorKind> {
+    let mut flags = Vec::with_capacity(1);
+    loop {
+        let word = Cursor::new(take_bytes(bytes, 2)?).get_u16::<BigEndian>();
+        flags.push(word);
+        if word & 1 == 0 {
+            return Ok(flags);
+        }
+    }
+}
+
 
+/// Decode properties of Basic class.
+fn decode_properties(
+    flags: &[u16],
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<Properties, DecodeErrorKind> {
     let mut ps = Properties::new();
 
-    for i in 0..NUM_OF_PROPERTY {
-        if flags == 0 {
-            break;
+    for i in 0..flags.len() * FLAGS_PER_WORD {
+        if !check_flag_n(flags, i) {
+            continue;
         }
-        if check_flag_n(&flags, i) {
-            remove_flag_n(&mut flags, i);
-            set_property_n(&mut ps, i, &mut bytes);
+        match i {
+            i if i < NUM_OF_PROPERTY => set_property_n(&mut ps, i, bytes, config)?,
+            CLUSTER_ID_PROPERTY => {
+                let cluster_id = decode_short_str(bytes)?;
+                debug!("reserved cluster-id property is ignored : {:?}", cluster_id);
+            }
+            i => {
+                return Err(DecodeErrorKind::UnknownProperty {
+                    class_id: BASIC_CLASS_ID,
+                    index: i,
+                })
+            }
         }
     }
 
-    ps
+    Ok(ps)
 }
 
 
-fn check_flag_n(flags: &u16, i: usize) -> bool {
-    flags & (1u16 << (15 - i)) != 0
+/// Check the flag of `i`th property. Each word holds 15 flags from the highest bit.
+fn check_flag_n(flags: &[u16], i: usize) -> bool {
+    flags[i / FLAGS_PER_WORD] & (1u16 << (15 - i % FLAGS_PER_WORD)) != 0
 }
 
 
-fn remove_flag_n(flags: &mut u16, i: usize) {
-    *flags -= 1u16 << (15 - i);
-}
-
-fn set_property_n(ps: &mut Properties, i: usize, bytes: &mut BytesMut) {
+fn set_property_n(
+    ps: &mut Properties,
+    i: usize,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<(), DecodeErrorKind> {
     match i {
-        0 => ps.content_type = Some(decode_short_str(bytes)),
-        1 => ps.content_encoding = Some(decode_short_str(bytes)),
-        2 => ps.headers = Some(decode_field_table(bytes)),
-        3 => ps.delivery_mode = Some(decode_u8(bytes)),
-        4 => ps.priority = Some(decode_u8(bytes)),
-        5 => ps.correlation_id = Some(decode_short_str(bytes)),
-        6 => ps.reply_to = Some(decode_short_str(bytes)),
-        7 => ps.expiration = Some(decode_short_str(bytes)),
-        8 => ps.message_id = Some(decode_short_str(bytes)),
-        9 => ps.timestamp = Some(decode_i64(bytes)),
-        10 => ps.type_ = Some(decode_short_str(bytes)),
-        11 => ps.user_id = Some(decode_short_str(bytes)),
-        12 => ps.app_id = Some(decode_short_str(bytes)),
+        0 => ps.content_type = Some(decode_short_str(bytes)?),
+        1 => ps.content_encoding = Some(decode_short_str(bytes)?),
+        2 => ps.headers = Some(decode_field_table(bytes, config)?),
+        3 => ps.delivery_mode = Some(decode_u8(bytes)?),
+        4 => ps.priority = Some(decode_u8(bytes)?),
+        5 => ps.correlation_id = Some(decode_short_str(bytes)?),
+        6 => ps.reply_to = Some(decode_short_str(bytes)?),
+        7 => ps.expiration = Some(decode_short_str(bytes)?),
+        8 => ps.message_id = Some(decode_short_str(bytes)?),
+        9 => ps.timestamp = Some(decode_i64(bytes)?),
+        10 => ps.type_ = Some(decode_short_str(bytes)?),
+        11 => ps.user_id = Some(decode_short_str(bytes)?),
+        12 => ps.app_id = Some(decode_short_str(bytes)?),
         _ => unreachable!(),
     }
+    Ok(())
 }
 
 
-fn decode_short_str(bytes: &mut BytesMut) -> AmqpString {
-    let len = Cursor::new(bytes.split_to(1)).get_u8();
-    AmqpString(bytes.split_to(len as usize).freeze())
+fn decode_short_str(bytes: &mut BytesMut) -> Result<AmqpString, DecodeErrorKind> {
+    ::frame::method::decoder::decode_short_str(bytes)
 }
 
 
-fn decode_u8(bytes: &mut BytesMut) -> u8 {
-    Cursor::new(bytes.split_to(1)).get_u8()
+fn decode_u8(bytes: &mut BytesMut) -> Result<u8, DecodeErrorKind> {
+    Ok(take_bytes(bytes, 1)?[0])
 }
 
 
-fn decode_i64(bytes: &mut BytesMut) -> i64 {
-    Cursor::new(bytes.split_to(8)).get_i64::<BigEndian>()
+fn decode_i64(bytes: &mut BytesMut) -> Result<i64, DecodeErrorKind> {
+    Ok(Cursor::new(take_bytes(bytes, 8)?).get_i64::<BigEndian>())
 }
 
 
-fn decode_field_table(bytes: &mut BytesMut) -> HashMap<AmqpString, FieldArgument> {
-    ::frame::method::decoder::decode_field_table(bytes)
+fn decode_field_table(
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<HashMap<AmqpString, FieldArgument>, DecodeErrorKind> {
+    ::frame::method::decoder::decode_field_table(bytes, config)
 }
@@ -2,12 +2,17 @@ use bytes::{BigEndian, BufMut};
 
 use super::{ContentHeaderPayload, Properties};
 use args::{AmqpString, FieldArgument};
+use config::Config;
+use errors::EncodeErrorKind;
 
 use std::collections::HashMap;
 
 const MIN_PAYLOAD_SIZE: usize = 14;
 
-pub fn encode_payload(payload: ContentHeaderPayload) -> Vec<u8> {
+pub fn encode_payload(
+    payload: ContentHeaderPayload,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     debug!("Start encoging conent header");
 
     let mut dst = Vec::with_capacity(MIN_PAYLOAD_SIZE);
@@ -21,20 +26,26 @@ pub fn encode_payload(payload: ContentHeaderPayload) -> Vec<u8> {
     dst.put_u64::<BigEndian>(payload.body_size);
     debug!("body size : {}", payload.body_size);
 
-    // Encode property flags
-    let property_flags = property_flags(&payload.properties);
-    dst.put_u16::<BigEndian>(property_flags);
-    debug!("property flags : {}", property_flags);
-
-    // Encode property list
-    if property_flags != 0b_0000_0000_0000_0001 {
-        property_list(&payload.properties, &mut dst);
+    match payload.raw_properties {
+        None => {
+            let property_flags = property_flags(&payload.properties);
+            dst.put_u16::<BigEndian>(property_flags);
+            debug!("property flags : {}", property_flags);
+
+            property_list(&payload.properties, &mut dst, config)?;
+        }
+        Some(ref raw) => {
+            encode_raw_flags(&raw.flags, &mut dst);
+            dst.extend_from_slice(raw.list.as_ref());
+        }
     }
 
-    dst
+    Ok(dst)
 }
 
 
+/// Property flags of Basic class. All of them fit in the first word, so continuation bit
+/// (the lowest bit) is never set.
 fn property_flags(ps: &Properties) -> u16 {
     0b_____1000000000000000 * ps.content_type.is_some() as u16 +
         0b_0100000000000000 * ps.content_encoding.is_some() as u16 +
@@ -48,24 +59,37 @@ fn property_flags(ps: &Properties) -> u16 {
         0b_0000000001000000 * ps.timestamp.is_some() as u16 +
         0b_0000000000100000 * ps.type_.is_some() as u16 +
         0b_0000000000010000 * ps.user_id.is_some() as u16 +
-        0b_0000000000001000 * ps.app_id.is_some() as u16 +
+        0b_0000000000001000 * ps.app_id.is_some() as u16
+}
+
 
-        // No further properties.
-        0b_0000_0000_0000_0001
+/// Encode flags words setting continuation bit of every word except the last one.
+fn encode_raw_flags(flags: &[u16], dst: &mut Vec<u8>) {
+    if flags.is_empty() {
+        dst.put_u16::<BigEndian>(0);
+    }
+    for (i, word) in flags.iter().enumerate() {
+        let has_next = i + 1 < flags.len();
+        dst.put_u16::<BigEndian>(word & !1 | has_next as u16);
+    }
 }
 
 
-fn property_list(ps: &Properties, dst: &mut Vec<u8>) {
+fn property_list(
+    ps: &Properties,
+    dst: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), EncodeErrorKind> {
     if let Some(ref s) = ps.content_type {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref s) = ps.content_encoding {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref f) = ps.headers {
-        encode_field_table(f, dst);
+        encode_field_table(f, dst, config)?;
     }
 
     if let Some(ref o) = ps.delivery_mode {
@@ -77,19 +101,19 @@ fn property_list(ps: &Properties, dst: &mut Vec<u8>) {
     }
 
     if let Some(ref s) = ps.correlation_id {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref s) = ps.reply_to {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref s) = ps.expiration {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref s) = ps.message_id {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref t) = ps.timestamp {
@@ -97,24 +121,29 @@ fn property_list(ps: &Properties, dst: &mut Vec<u8>) {
     }
 
     if let Some(ref s) = ps.type_ {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref s) = ps.user_id {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
 
     if let Some(ref s) = ps.app_id {
-        encode_short_str(s, dst);
+        encode_short_str(s, dst)?;
     }
+
+    Ok(())
 }
 
 
-fn encode_short_str(s: &AmqpString, dst: &mut Vec<u8>) {
-    dst.put_u8(s.len() as u8);
-    dst.put(s.as_bytes());
+fn encode_short_str(s: &AmqpString, dst: &mut Vec<u8>) -> Result<(), EncodeErrorKind> {
+    ::frame::method::encoder::encode_short_str_0(s, dst)
 }
 
-fn encode_field_table(f: &HashMap<AmqpString, FieldArgument>, dst: &mut Vec<u8>) {
-    ::frame::method::encoder::encode_field_table_0(f, dst);
+fn encode_field_table(
+    f: &HashMap<AmqpString, FieldArgument>,
+    dst: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), EncodeErrorKind> {
+    ::frame::method::encoder::encode_field_table_0(f, dst, config)
 }
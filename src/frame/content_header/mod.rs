@@ -6,14 +6,46 @@ pub use self::encoder::encode_payload;
 
 use args::{AmqpString, FieldArgument};
 
+use bytes::Bytes;
+
 use std::collections::HashMap;
 
+/// Class id of Basic class. Only properties of this class are decoded.
+pub const BASIC_CLASS_ID: u16 = 60;
+
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct ContentHeaderPayload {
     pub class_id: u16,
     pub body_size: u64,
+    /// Properties of Basic class. Empty if the class is not Basic.
     pub properties: Properties,
+    /// Properties of other classes than Basic. These are not decoded because we do not know
+    /// their types. If this is `Some`, it is encoded instead of `properties`.
+    pub raw_properties: Option<RawProperties>,
+}
+
+
+impl ContentHeaderPayload {
+    /// Make a content header of Basic class.
+    pub fn basic(body_size: u64, properties: Properties) -> ContentHeaderPayload {
+        ContentHeaderPayload {
+            class_id: BASIC_CLASS_ID,
+            body_size: body_size,
+            properties: properties,
+            raw_properties: None,
+        }
+    }
+}
+
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct RawProperties {
+    /// Property flags words. The lowest bit of each word (continuation bit) is ignored when
+    /// encoding and set only if another word follows.
+    pub flags: Vec<u16>,
+    /// Encoded property list.
+    pub list: Bytes,
 }
 
 
@@ -60,20 +92,18 @@ impl Properties {
 mod tests {
     use super::*;
     use bytes::BytesMut;
+    use config::Config;
+    use errors::DecodeErrorKind;
 
     #[test]
     fn encode_and_decode_without_properties() {
-        let payload = ContentHeaderPayload {
-            class_id: 42,
-            body_size: 10000,
-            properties: Properties::new(),
-        };
+        let payload = ContentHeaderPayload::basic(10000, Properties::new());
 
         let cloned = payload.clone();
 
-        let mut encoded = BytesMut::from(encode_payload(payload));
+        let mut encoded = BytesMut::from(encode_payload(payload, &Config::default()).unwrap());
 
-        let decoded = decode_payload(&mut encoded);
+        let decoded = decode_payload(&mut encoded, &Config::default()).unwrap();
 
         assert_eq!(decoded, cloned);
         assert_eq!(encoded.len(), 0);
@@ -87,21 +117,60 @@ mod tests {
             ps.priority = Some(42);
             ps
         };
-        let payload = ContentHeaderPayload {
-            class_id: 42,
-            body_size: 10000,
-            properties: properties,
-        };
+        let payload = ContentHeaderPayload::basic(10000, properties);
 
         let cloned = payload.clone();
 
-        let mut encoded = BytesMut::from(encode_payload(payload));
+        let mut encoded = BytesMut::from(encode_payload(payload, &Config::default()).unwrap());
 
-        let decoded = decode_payload(&mut encoded);
+        let decoded = decode_payload(&mut encoded, &Config::default()).unwrap();
 
         assert_eq!(decoded, cloned);
         assert_eq!(encoded.len(), 0);
     }
 
+    #[test]
+    fn last_flag_word_has_no_continuation_bit() {
+        let mut ps = Properties::new();
+        ps.app_id = Some(AmqpString::from("a"));
+        let payload = ContentHeaderPayload::basic(0, ps);
+
+        let encoded = encode_payload(payload, &Config::default()).unwrap();
+
+        assert_eq!(&encoded[12..], &[0b_0000_0000, 0b_0000_1000, 1, b'a']);
+    }
+
+    #[test]
+    fn other_class_has_raw_properties() {
+        // Two flag words and 3 bytes of property list
+        let mut bytes = BytesMut::from(
+            &[0, 70, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0x80, 0x01, 0x40, 0x00, 7, 8, 9][..],
+        );
+
+        let decoded = decode_payload(&mut bytes, &Config::default()).unwrap();
+
+        let raw = RawProperties {
+            flags: vec![0x8001, 0x4000],
+            list: Bytes::from(&[7, 8, 9][..]),
+        };
+        assert_eq!(decoded.raw_properties, Some(raw));
+        assert_eq!(decoded.properties, Properties::new());
+
+        let encoded = encode_payload(decoded, &Config::default()).unwrap();
+        assert_eq!(&encoded[12..], &[0x80, 0x01, 0x40, 0x00, 7, 8, 9]);
+    }
+
+    #[test]
+    fn unknown_basic_property_is_error() {
+        // Continuation bit is set, and the second word has a flag
+        let mut bytes = BytesMut::from(
+            &[0, 60, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0x00, 0x01, 0x80, 0x00][..],
+        );
+
+        match decode_payload(&mut bytes, &Config::default()) {
+            Err(DecodeErrorKind::UnknownProperty { class_id: 60, index: 15 }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
 }
 // }}}
@@ -2,38 +2,51 @@ use bytes::{BytesMut, BigEndian, Buf};
 
 use std::io::{Cursor, Seek, SeekFrom};
 
+use config::Config;
+use errors::{DecodeError, DecodeErrorKind};
 use frame::{Frame, FrameHeader, FrameType, FramePayload, FRAME_END_OCTET, method, content_body,
             content_header};
 
 const FRAME_HEADER_BYTE_SIZE: usize = 7;
 
-pub fn decode_frame(src: &mut BytesMut) -> Option<Frame> {
+pub fn decode_frame(src: &mut BytesMut, config: &Config) -> Result<Option<Frame>, DecodeError> {
 
     debug!("Decode frame : {:?}", src);
 
-    match extract_frame_bytes(src) {
+    match extract_frame_bytes(src, config)? {
         Some(mut frame_bytes) => {
             debug!("Extracted a frame : {:?}", frame_bytes);
 
-            let (typ, channel, payload_size) =
-                decode_header(&mut frame_bytes.split_to(FRAME_HEADER_BYTE_SIZE));
+            let (typ_byte, typ, channel, payload_size) =
+                decode_header(&mut frame_bytes.split_to(FRAME_HEADER_BYTE_SIZE), config)?;
 
             debug!("frame type is {:?}", typ);
             debug!("frame channel is {}", channel);
             debug!("frame payload_size is {}", payload_size);
 
-            let payload = decode_payload(&typ, &mut frame_bytes.split_to(payload_size as usize));
+            let mut payload_bytes = frame_bytes.split_to(payload_size as usize);
+            let payload = decode_payload(typ, typ_byte, &mut payload_bytes, config);
+            let payload = payload.map_err(|kind| {
+                let offset = FRAME_HEADER_BYTE_SIZE + payload_size as usize - payload_bytes.len();
+                DecodeError::new(channel, offset, kind)
+            })?;
 
             let frame = Frame {
                 header: FrameHeader { channel: channel },
                 payload: payload,
             };
 
+            if config.validate_channels {
+                frame.check_channel().map_err(|v| {
+                    DecodeError::new(channel, 1, DecodeErrorKind::ChannelViolation(v))
+                })?;
+            }
+
             debug!("Finish decoding frame : {:?}", frame);
 
-            Some(frame)
+            Ok(Some(frame))
         }
-        None => None,
+        None => Ok(None),
     }
 }
 
@@ -41,67 +54,276 @@ pub fn decode_frame(src: &mut BytesMut) -> Option<Frame> {
 /// Extract a frame bytes.
 /// If there is not enough length to make frame, this function returns None.
 /// If there is enough length, this function extract it after check frame end.
+/// Extracted bytes are removed from `src` even if frame end is invalid.
 ///
-/// # Panics
-/// If frame end is invalid
-fn extract_frame_bytes(src: &mut BytesMut) -> Option<BytesMut> {
+/// Size of frame is checked against `config.frame_max` before waiting for the rest of
+/// the frame, so we never buffer a too large frame.
+fn extract_frame_bytes(
+    src: &mut BytesMut,
+    config: &Config,
+) -> Result<Option<BytesMut>, DecodeError> {
     if src.len() < 8 {
-        None
+        Ok(None)
     } else {
         let mut cursor = Cursor::new(src);
-        cursor.seek(SeekFrom::Current(3_i64)).expect("Never fail");
+        cursor.seek(SeekFrom::Current(1_i64)).expect("Never fail");
+        let channel = cursor.get_u16::<BigEndian>();
         let size = cursor.get_u32::<BigEndian>() as usize;
 
         let src = cursor.into_inner();
 
+        if !config.is_acceptable_frame_size(size + 8) {
+            let kind = DecodeErrorKind::FrameTooLarge {
+                size: size + 8,
+                frame_max: config.frame_max,
+            };
+            return Err(DecodeError::new(channel, 3, kind));
+        }
+
         if src.len() >= size + 8 {
             let bytes = src.split_to(size + 8);
 
             // Check frame end
-            if !bytes.as_ref().ends_with(&[FRAME_END_OCTET]) {
-                panic!("Invalid Frame End");
+            let frame_end = bytes[size + 7];
+            if frame_end != FRAME_END_OCTET {
+                let kind = DecodeErrorKind::BadFrameEnd(frame_end);
+                return Err(DecodeError::new(channel, size + 7, kind));
             }
 
-            Some(bytes)
+            Ok(Some(bytes))
         } else {
-            None
+            Ok(None)
         }
     }
 }
 
 
-/// Decode frame header. This function returns tuple of (type_octet, channel_id, body_size).
+/// Decode frame header. This function returns tuple of
+/// (type_octet, frame_type, channel_id, body_size).
+/// `bytes` must be the whole header, which `extract_frame_bytes` has already made sure of.
 ///
-/// # Panics
-/// when `src` does not have enough length.
-fn decode_header(bytes: &mut BytesMut) -> (FrameType, u16, u32) {
+/// # Errors
+/// `UnknownFrameType` if the frame type is unknown and `passthrough_unknown` is off.
+fn decode_header(
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<(u8, FrameType, u16, u32), DecodeError> {
     let mut cursor = Cursor::new(bytes);
-    let typ = match cursor.get_u8() {
-        1 => FrameType::Method,
-        2 => FrameType::ContentHeader,
-        3 => FrameType::ContentBody,
-        4 | 8 => FrameType::Heartbeat, // RabbitMQ sends heartbeat frame starting with 8
-        b => unreachable!("Unexpected frame type '{}' is received", b),
-    };
+    let typ_byte = cursor.get_u8();
     let channel = cursor.get_u16::<BigEndian>();
     let size = cursor.get_u32::<BigEndian>();
 
-    (typ, channel, size)
+    let typ = match config.frame_type_of(typ_byte) {
+        FrameType::Unknown(b) if !config.passthrough_unknown => {
+            let kind = DecodeErrorKind::UnknownFrameType(b);
+            return Err(DecodeError::new(channel, 0, kind));
+        }
+        typ => typ,
+    };
+
+    Ok((typ_byte, typ, channel, size))
 }
 
 
 /// Decode frame payload with `FrameType`.
 /// You **MUTS** gime `Bytes` which has **EXACT* length of payload (without frame-end).
-///
-/// # Panics
-/// when `payload` does not have enough length.
-fn decode_payload(typ: &FrameType, bytes: &mut BytesMut) -> FramePayload {
+/// Returned error does not know its position, so caller should compute it from the length
+/// of rest of `bytes`.
+fn decode_payload(
+    typ: FrameType,
+    typ_byte: u8,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<FramePayload, DecodeErrorKind> {
     use self::FrameType::*;
-    let payload = match *typ {
-        Method => FramePayload::Method(method::decoder::decode_payload(bytes)),
-        ContentHeader => FramePayload::ContentHeader(content_header::decode_payload(bytes)),
+    let payload = match typ {
+        Method => FramePayload::Method(method::decoder::decode_payload(bytes, config)?),
+        ContentHeader => {
+            FramePayload::ContentHeader(content_header::decode_payload(bytes, config)?)
+        }
         ContentBody => FramePayload::ContentBody(content_body::decode_payload(bytes)),
-        Heartbeat => FramePayload::Heartbeat,
+        Heartbeat => FramePayload::Heartbeat(typ_byte),
+        Unknown(t) => FramePayload::Unknown {
+            frame_type: t,
+            payload: bytes.take().freeze(),
+        },
     };
-    payload
+    Ok(payload)
+}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::InvalidHeartbeatType;
+    use errors::ChannelViolation;
+
+    #[test]
+    fn bad_frame_end_is_error() {
+        let mut src = BytesMut::from(&[8, 0, 0, 0, 0, 0, 0, 0x00][..]);
+
+        let err = decode_frame(&mut src, &Config::default()).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::BadFrameEnd(0x00) => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(err.offset, 7);
+        assert_eq!(err.reply_code().code(), 501);
+        assert_eq!(src.len(), 0);
+    }
+
+    #[test]
+    fn unknown_frame_type_is_error() {
+        let mut src = BytesMut::from(&[42, 0, 3, 0, 0, 0, 0, FRAME_END_OCTET][..]);
+
+        let err = decode_frame(&mut src, &Config::default()).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::UnknownFrameType(42) => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(err.channel, 3);
+    }
+
+    #[test]
+    fn truncated_method_is_error() {
+        // Connection.Tune without heartbeat field
+        let mut src = BytesMut::from(
+            &[1, 0, 0, 0, 0, 0, 10, 0, 10, 0, 30, 0, 0, 0, 2, 0, 0, FRAME_END_OCTET][..],
+        );
+
+        let err = decode_frame(&mut src, &Config::default()).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::TruncatedPayload => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(err.offset, 17);
+    }
+
+    #[test]
+    fn unknown_method_is_error() {
+        let mut src = BytesMut::from(&[1, 0, 1, 0, 0, 0, 4, 0, 10, 0, 99, FRAME_END_OCTET][..]);
+
+        let err = decode_frame(&mut src, &Config::default()).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::UnknownMethod { class_id: 10, method_id: 99 } => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        let close = err.close_method();
+        assert_eq!(close.reply_code, 540);
+        assert_eq!((close.class_id, close.method_id), (10, 99));
+    }
+
+    #[test]
+    fn too_large_frame_is_error_before_buffering() {
+        let config = Config {
+            frame_max: 4096,
+            ..Config::default()
+        };
+        // Only a header of 4096 bytes payload frame.
+        let mut src = BytesMut::from(&[3, 0, 1, 0, 0, 0x10, 0x00, 0x00][..]);
+
+        let err = decode_frame(&mut src, &config).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::FrameTooLarge { size: 4104, frame_max: 4096 } => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(err.channel, 1);
+    }
+
+    #[test]
+    fn passthrough_unknown_method_and_frame() {
+        use frame::encoder::encode_frame;
+
+        let config = Config {
+            passthrough_unknown: true,
+            ..Config::default()
+        };
+        let bytes = [
+            1, 0, 1, 0, 0, 0, 7, 0, 60, 0, 250, 1, 2, 3, FRAME_END_OCTET, // Basic method 250
+            1, 0, 1, 0, 0, 0, 4, 0, 99, 0, 10, FRAME_END_OCTET, // Class 99
+            42, 0, 1, 0, 0, 0, 1, 7, FRAME_END_OCTET, // Frame type 42
+        ];
+        let mut src = BytesMut::from(&bytes[..]);
+        let mut dst = BytesMut::new();
+
+        while let Some(frame) = decode_frame(&mut src, &config).unwrap() {
+            encode_frame(frame, &mut dst, &config).unwrap();
+        }
+
+        assert_eq!(dst.as_ref(), &bytes[..]);
+    }
+
+    #[test]
+    fn heartbeat_type_byte_is_configurable() {
+        let strict = Config {
+            accept_legacy_heartbeat: false,
+            ..Config::default()
+        };
+        let legacy = [4, 0, 0, 0, 0, 0, 0, FRAME_END_OCTET];
+
+        let frame = decode_frame(&mut BytesMut::from(&legacy[..]), &Config::default()).unwrap();
+        assert_eq!(frame, Some(Frame::new(0, FramePayload::Heartbeat(4))));
+        assert_eq!(frame.unwrap().frame_type(), FrameType::Heartbeat);
+
+        match decode_frame(&mut BytesMut::from(&legacy[..]), &strict) {
+            Err(DecodeError { kind: DecodeErrorKind::UnknownFrameType(4), .. }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_heartbeat_type_does_not_hide_data_frames() {
+        let config = Config {
+            heartbeat_frame_type: 1,
+            ..Config::default()
+        };
+        let bytes = [1, 0, 0, 0, 0, 0, 4, 0, 10, 0, 51, FRAME_END_OCTET]; // Connection.CloseOk
+
+        let frame = decode_frame(&mut BytesMut::from(&bytes[..]), &config).unwrap().unwrap();
+
+        assert_eq!(frame.frame_type(), FrameType::Method);
+        assert!(!config.is_valid());
+
+        let mut config = Config::default();
+        assert_eq!(config.set_heartbeat_frame_type(3), Err(InvalidHeartbeatType(3)));
+        assert_eq!(config.set_heartbeat_frame_type(4), Ok(()));
+        assert_eq!(config.heartbeat_frame_type, 4);
+    }
+
+    #[test]
+    fn heartbeat_on_non_zero_channel_is_error() {
+        let config = Config {
+            validate_channels: true,
+            ..Config::default()
+        };
+        let mut src = BytesMut::from(&[8, 0, 1, 0, 0, 0, 0, FRAME_END_OCTET][..]);
+
+        let err = decode_frame(&mut src, &config).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::ChannelViolation(ChannelViolation::HeartbeatOnChannel) => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(err.close_method().reply_code, 501);
+    }
+
+    #[test]
+    fn decode_tune_method() {
+        let mut src = BytesMut::from(
+            &[1, 0, 0, 0, 0, 0, 12, 0, 10, 0, 30, 0, 0, 0, 2, 0, 0, 0, 60, FRAME_END_OCTET][..],
+        );
+
+        let frame = decode_frame(&mut src, &Config::default()).unwrap().unwrap();
+
+        let tune = frame.method().and_then(|m| m.connection()).and_then(|c| c.tune());
+        assert_eq!(tune.map(|t| (t.channel_max, t.frame_max, t.heartbeat)), Some((0, 131072, 60)));
+    }
 }
+// }}}
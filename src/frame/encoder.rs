@@ -1,44 +1,66 @@
-use bytes::{BufMut, BytesMut, BigEndian};
+use bytes::{BufMut, Bytes, BytesMut, BigEndian};
 
+use config::Config;
+use errors::{EncodeError, EncodeErrorKind};
 use frame::*;
 
 
-const METHOD_TYPE_BYTE: u8 = 1;
-const CONTENT_HEADER_TYPE_BYTE: u8 = 2;
-const CONTENT_BODY_TYPE_BYTE: u8 = 3;
-const HEARTBEAT_TYPE_BYTE: u8 = 8;
+/// Encode a frame into `dst`.
+/// If it fails, nothing is written into `dst`.
+pub fn encode_frame(item: Frame, dst: &mut BytesMut, config: &Config) -> Result<(), EncodeError> {
+    debug!("Start encode frame : {:?}", item);
 
+    let channel = item.header.channel;
+    let to_encode_error = |kind: EncodeErrorKind| EncodeError::new(channel, kind);
 
-pub fn encode_frame(item: Frame, dst: &mut BytesMut) {
-    debug!("Start encode frame : {:?}", item);
+    if !config.is_acceptable_channel(channel) {
+        let kind = EncodeErrorKind::ChannelOutOfRange { channel_max: config.channel_max };
+        return Err(to_encode_error(kind));
+    }
+
+    if config.validate_channels {
+        item.check_channel().map_err(|v| {
+            to_encode_error(EncodeErrorKind::ChannelViolation(v))
+        })?;
+    }
 
-    match item.payload {
+    let (type_byte, payload) = match item.payload {
         FramePayload::Method(payload) => {
-            let payload = method::encoder::encode_payload(payload);
-            encode_frame_inner(METHOD_TYPE_BYTE, item.header.channel, payload.as_ref(), dst);
+            let payload =
+                method::encoder::encode_payload(payload, config).map_err(to_encode_error)?;
+            (METHOD_TYPE_BYTE, Bytes::from(payload))
         }
         FramePayload::ContentHeader(payload) => {
-            let payload = content_header::encode_payload(payload);
-            encode_frame_inner(
-                CONTENT_HEADER_TYPE_BYTE,
-                item.header.channel,
-                payload.as_ref(),
-                dst,
-            );
+            let payload =
+                content_header::encode_payload(payload, config).map_err(to_encode_error)?;
+            (CONTENT_HEADER_TYPE_BYTE, Bytes::from(payload))
         }
         FramePayload::ContentBody(payload) => {
             let payload = content_body::encoder::encode_payload(payload);
-            encode_frame_inner(
-                CONTENT_BODY_TYPE_BYTE,
-                item.header.channel,
-                payload.as_ref(),
-                dst,
-            );
+            (CONTENT_BODY_TYPE_BYTE, payload)
         }
-        FramePayload::Heartbeat => {
-            encode_frame_inner(HEARTBEAT_TYPE_BYTE, item.header.channel, &[], dst);
+        FramePayload::Heartbeat(_) => {
+            (config.type_byte_of(FrameType::Heartbeat), Bytes::new())
         }
+        FramePayload::Unknown {
+            frame_type,
+            payload,
+        } => (frame_type, payload),
     };
+
+    // frame header (7 bytes) + payload + frame end (1 byte)
+    let frame_size = payload.len() + 8;
+    if !config.is_acceptable_frame_size(frame_size) || payload.len() > u32::max_value() as usize {
+        let kind = EncodeErrorKind::FrameTooLarge {
+            size: frame_size,
+            frame_max: config.frame_max,
+        };
+        return Err(to_encode_error(kind));
+    }
+
+    encode_frame_inner(type_byte, channel, payload.as_ref(), dst);
+
+    Ok(())
 }
 
 
@@ -57,3 +79,130 @@ fn encode_frame_inner(type_byte: u8, channel: u16, encoded_payload: &[u8], dst:
     dst.reserve(1);
     dst.put_u8(FRAME_END_OCTET);
 }
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use args::{AmqpString, FieldArgument};
+    use frame::method::MethodPayload;
+    use frame::method::connection::{ConnectionClass, OpenMethod};
+    use frame::method::basic::BasicClass;
+    use frame::content_body::ContentBodyPayload;
+    use errors::ChannelViolation;
+    use std::collections::HashMap;
+
+    #[test]
+    fn too_long_short_string_is_error() {
+        let open = OpenMethod {
+            virtual_host: AmqpString::from(vec![b'a'; 256]),
+            reserved1: AmqpString::from(""),
+            reserved2: false,
+        };
+        let frame = Frame::new_method(0, MethodPayload::Connection(ConnectionClass::Open(open)));
+        let mut dst = BytesMut::new();
+
+        let err = encode_frame(frame, &mut dst, &Config::default()).unwrap_err();
+
+        match err.kind {
+            EncodeErrorKind::ShortStringTooLong(256) => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(dst.len(), 0);
+    }
+
+    #[test]
+    fn server_method_is_unsupported() {
+        let frame = Frame::new_method(1, MethodPayload::Basic(BasicClass::QosOk));
+        let mut dst = BytesMut::new();
+
+        let err = encode_frame(frame, &mut dst, &Config::default()).unwrap_err();
+
+        match err.kind {
+            EncodeErrorKind::UnsupportedMethod { class_id: 60, method_id: 11 } => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(err.channel, 1);
+    }
+
+    #[test]
+    fn too_large_frame_is_error() {
+        let config = Config {
+            frame_max: 4096,
+            ..Config::default()
+        };
+        let body = ContentBodyPayload { bytes: Bytes::from(vec![0; 4089]) };
+        let mut dst = BytesMut::new();
+
+        let err = encode_frame(Frame::new_content_body(1, body), &mut dst, &config).unwrap_err();
+
+        match err.kind {
+            EncodeErrorKind::FrameTooLarge { size: 4097, frame_max: 4096 } => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(dst.len(), 0);
+    }
+
+    #[test]
+    fn too_high_channel_is_error() {
+        let config = Config {
+            channel_max: 10,
+            ..Config::default()
+        };
+        let mut dst = BytesMut::new();
+
+        let err = encode_frame(Frame::new_heartbeat(11), &mut dst, &config).unwrap_err();
+
+        match err.kind {
+            EncodeErrorKind::ChannelOutOfRange { channel_max: 10 } => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+    }
+
+    #[test]
+    fn heartbeat_type_byte_is_configurable() {
+        let config = Config {
+            heartbeat_frame_type: 4,
+            ..Config::default()
+        };
+        let mut dst = BytesMut::new();
+
+        encode_frame(Frame::new_heartbeat(0), &mut dst, &config).unwrap();
+
+        assert_eq!(dst.as_ref(), &[4, 0, 0, 0, 0, 0, 0, FRAME_END_OCTET]);
+    }
+
+    #[test]
+    fn connection_method_on_non_zero_channel_is_error() {
+        let config = Config {
+            validate_channels: true,
+            ..Config::default()
+        };
+        let frame = Frame::new_method(1, MethodPayload::Connection(ConnectionClass::CloseOk));
+        let mut dst = BytesMut::new();
+
+        let err = encode_frame(frame, &mut dst, &config).unwrap_err();
+
+        match err.kind {
+            EncodeErrorKind::ChannelViolation(v) => {
+                assert_eq!(v, ChannelViolation::ConnectionMethodOnChannel { method_id: 51 });
+                assert_eq!(v.reply_code().name(), "COMMAND_INVALID");
+            }
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+    }
+
+    #[test]
+    fn byte_array_is_length_prefixed() {
+        let mut table = HashMap::new();
+        table.insert(AmqpString::from("b"), FieldArgument::ByteArray(vec![1, 2, 3]));
+        let mut dst = Vec::new();
+
+        method::encoder::encode_field_table_0(&table, &mut dst, &Config::default()).unwrap();
+
+        assert_eq!(dst, vec![0, 0, 0, 10, 1, b'b', b'x', 0, 0, 0, 3, 1, 2, 3]);
+    }
+}
+// }}}
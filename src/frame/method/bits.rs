@@ -0,0 +1,66 @@
+//! Packing of bit fields.
+//!
+//! The spec packs consecutive bit fields of a method into shared octets. The first bit goes
+//! into the least significant bit of the first octet, and a new octet is started after every
+//! 8 bits. A field of any other type between bits ends the current octet.
+//!
+//! Both method encoder and decoder use these functions, so every method agrees on the layout.
+
+
+/// Number of octets which `n` consecutive bit fields occupy.
+pub(crate) fn octets_for_bits(n: usize) -> usize {
+    (n + 7) / 8
+}
+
+
+/// Pack consecutive bit fields and append them into `dst`.
+pub(crate) fn pack_bits(bits: &[bool], dst: &mut Vec<u8>) {
+    for chunk in bits.chunks(8) {
+        let octet = chunk.iter().enumerate().fold(0_u8, |octet, (i, &bit)| {
+            octet | ((bit as u8) << i)
+        });
+        dst.push(octet);
+    }
+}
+
+
+/// Unpack `n` consecutive bit fields from `octets`.
+///
+/// # Panics
+/// when `octets` is shorter than `octets_for_bits(n)`.
+pub(crate) fn unpack_bits(octets: &[u8], n: usize) -> Vec<bool> {
+    (0..n)
+        .map(|i| octets[i / 8] & (1 << (i % 8)) != 0)
+        .collect()
+}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_bit_is_least_significant() {
+        let mut dst = Vec::new();
+        pack_bits(&[true, false, true], &mut dst);
+        assert_eq!(dst, vec![0b_0000_0101]);
+        assert_eq!(unpack_bits(&dst, 3), vec![true, false, true]);
+    }
+
+    #[test]
+    fn ninth_bit_starts_new_octet() {
+        let mut bits = vec![false; 9];
+        bits[7] = true;
+        bits[8] = true;
+
+        let mut dst = Vec::new();
+        pack_bits(&bits, &mut dst);
+
+        assert_eq!(dst, vec![0b_1000_0000, 0b_0000_0001]);
+        assert_eq!(octets_for_bits(9), 2);
+        assert_eq!(unpack_bits(&dst, 9), bits);
+    }
+}
+// }}}
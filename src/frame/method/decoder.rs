@@ -3,288 +3,1466 @@ use bytes::{BytesMut, BigEndian, Buf};
 use std::io::Cursor;
 use std::collections::HashMap;
 
-use frame::method::{MethodPayload, ConnectionClass, ChannelClass, ExchangeClass, QueueClass,
-                    BasicClass, TxClass};
+use config::{Config, ProtocolVersion};
+use errors::DecodeErrorKind;
+use frame::method::bits::{octets_for_bits, unpack_bits};
+use frame::method::field_tag::kind_of;
+use frame::method::{MethodPayload, ConnectionClass, ChannelClass, AccessClass, ExchangeClass,
+                    QueueClass, BasicClass, ConfirmClass, TxClass};
 use args::*;
 
-/// # NOTICE
-/// This method does not check payload length.
-pub fn decode_payload(bytes: &mut BytesMut) -> MethodPayload {
-    let mut cursor = Cursor::new(bytes.split_off(4).freeze());
-
-    let class_id = cursor.get_u16::<BigEndian>();
+/// Decode method payload.
+/// Trailing bytes after the last field are ignored.
+pub fn decode_payload(
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<MethodPayload, DecodeErrorKind> {
+    let class_id = decode_short(bytes)?;
     debug!("class_id is {}", class_id);
 
-    let method_id = cursor.get_u16::<BigEndian>();
+    let method_id = decode_short(bytes)?;
     debug!("method_id is {}", method_id);
 
-    drop(cursor);
+    let result = match class_id {
+        10 => decode_connection_class(method_id, bytes, config).map(MethodPayload::Connection),
+        20 => decode_channel_class(method_id, bytes, config).map(MethodPayload::Channel),
+        30 => decode_access_class(method_id, bytes, config).map(MethodPayload::Access),
+        40 => decode_exchange_class(method_id, bytes, config).map(MethodPayload::Exchange),
+        50 => decode_queue_class(method_id, bytes, config).map(MethodPayload::Queue),
+        60 => decode_basic_class(method_id, bytes, config).map(MethodPayload::Basic),
+        85 => decode_confirm_class(method_id, bytes, config).map(MethodPayload::Confirm),
+        90 => decode_tx_class(method_id, bytes, config).map(MethodPayload::Tx),
+        c => Err(DecodeErrorKind::UnknownClass(c)),
+    };
+
+    // Unknown class or method is detected before reading any argument,
+    // so the rest of `bytes` is exactly its arguments.
+    let method = match result {
+        Err(DecodeErrorKind::UnknownClass(_)) |
+        Err(DecodeErrorKind::UnknownMethod { .. }) if config.passthrough_unknown => {
+            MethodPayload::Unknown {
+                class_id: class_id,
+                method_id: method_id,
+                arguments: bytes.take().freeze(),
+            }
+        }
+        r => r?,
+    };
 
-    match class_id {
-        10 => MethodPayload::Connection(decode_connection_class(method_id, bytes)),
-        20 => MethodPayload::Channel(decode_channel_class(method_id, bytes)),
-        40 => MethodPayload::Exchange(decode_exchange_class(method_id, bytes)),
-        50 => MethodPayload::Queue(decode_queue_class(method_id, bytes)),
-        60 => MethodPayload::Basic(decode_basic_class(method_id, bytes)),
-        90 => MethodPayload::Tx(decode_tx_class(method_id, bytes)),
-        c => unreachable!("Unexpected class id {}", c),
+    if !config.role.can_receive(method.sent_by()) {
+        return Err(DecodeErrorKind::UnexpectedMethod {
+            class_id: class_id,
+            method_id: method_id,
+        });
     }
+    Ok(method)
 }
 
 
 // Decode Connection Class {{{
-fn decode_connection_class(method_id: u16, bytes: &mut BytesMut) -> ConnectionClass {
+fn decode_connection_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<ConnectionClass, DecodeErrorKind> {
     use frame::method::connection::*;
     use self::ConnectionClass::*;
-    match method_id {
+    let method = match method_id {
         10 => Start(StartMethod {
-            version_major: decode_octet(bytes),
-            version_minor: decode_octet(bytes),
-            server_properties: decode_field_table(bytes),
-            mechanisms: decode_long_str(bytes),
-            locales: decode_long_str(bytes),
+            version_major: decode_octet(bytes)?,
+            version_minor: decode_octet(bytes)?,
+            server_properties: decode_field_table(bytes, config)?,
+            mechanisms: decode_long_str(bytes, config)?,
+            locales: decode_long_str(bytes, config)?,
+        }),
+        11 => StartOk(StartOkMethod {
+            client_properties: decode_field_table(bytes, config)?,
+            mechanism: decode_short_str(bytes)?,
+            response: decode_long_str(bytes, config)?,
+            locale: decode_short_str(bytes)?,
         }),
-        20 => Secure(SecureMethod { challenge: decode_long_str(bytes) }),
+        20 => Secure(SecureMethod { challenge: decode_long_str(bytes, config)? }),
+        21 => SecureOk(SecureOkMethod { response: decode_long_str(bytes, config)? }),
         30 => Tune(TuneMethod {
-            channel_max: decode_short(bytes),
-            frame_max: decode_long(bytes),
-            heartbeat: decode_short(bytes),
+            channel_max: decode_short(bytes)?,
+            frame_max: decode_long(bytes)?,
+            heartbeat: decode_short(bytes)?,
         }),
-        41 => OpenOk(OpenOkMethod { reserved1: decode_short_str(bytes) }),
-        50 => Close(CloseMethod {
-            reply_code: decode_short(bytes),
-            reply_text: decode_short_str(bytes),
-            class_id: decode_short(bytes),
-            method_id: decode_short(bytes),
+        31 => TuneOk(TuneOkMethod {
+            channel_max: decode_short(bytes)?,
+            frame_max: decode_long(bytes)?,
+            heartbeat: decode_short(bytes)?,
         }),
-        51 => CloseOk,
-        60 => Blocked(BlockedMethod { reason: decode_short_str(bytes) }),
-        61 => Unblocked,
-        m => unreachable!("Unexpected method id {} in Connection class", m),
-    }
+        40 => Open(OpenMethod {
+            virtual_host: decode_short_str(bytes)?,
+            reserved1: decode_short_str(bytes)?,
+            reserved2: decode_bit(bytes)?,
+        }),
+        41 => OpenOk(OpenOkMethod { reserved1: decode_short_str(bytes)? }),
+        70 if config.protocol_version == ProtocolVersion::Amqp0_9_1 => {
+            UpdateSecret(UpdateSecretMethod {
+                new_secret: decode_long_str(bytes, config)?,
+                reason: decode_short_str(bytes)?,
+            })
+        }
+        m => return decode_connection_close(m, bytes, config),
+    };
+    Ok(method)
+}
+
+
+/// Methods after OpenOk, whose ids depend on the protocol version.
+fn decode_connection_close(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<ConnectionClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 10;
+    use frame::method::connection::*;
+    use self::ConnectionClass::*;
+    use config::ProtocolVersion::*;
+    let method = match (method_id, config.protocol_version) {
+        (50, Amqp0_8) | (42, Amqp0_9) => Redirect(RedirectMethod {
+            host: decode_short_str(bytes)?,
+            known_hosts: decode_short_str(bytes)?,
+        }),
+        (60, Amqp0_8) | (50, _) => Close(CloseMethod {
+            reply_code: decode_short(bytes)?,
+            reply_text: decode_short_str(bytes)?,
+            class_id: decode_short(bytes)?,
+            method_id: decode_short(bytes)?,
+        }),
+        (61, Amqp0_8) | (51, _) => CloseOk,
+        (60, _) => Blocked(BlockedMethod { reason: decode_short_str(bytes)? }),
+        (61, _) => Unblocked,
+        (71, Amqp0_9_1) => UpdateSecretOk,
+        (m, _) => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
 }
 // }}}
 
 
 // Decode Channel Class {{{
-fn decode_channel_class(method_id: u16, bytes: &mut BytesMut) -> ChannelClass {
+fn decode_channel_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<ChannelClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 20;
     use frame::method::channel::*;
     use self::ChannelClass::*;
-    match method_id {
-        11 => OpenOk(OpenOkMethod { reserved1: decode_long_str(bytes) }),
-        20 => Flow(FlowMethod { active: decode_bool_1(bytes) }),
-        21 => FlowOk(FlowOkMethod { active: decode_bool_1(bytes) }),
+    let method = match method_id {
+        10 => Open(OpenMethod { reserved1: decode_short_str(bytes)? }),
+        11 if config.protocol_version == ProtocolVersion::Amqp0_8 => {
+            OpenOk(OpenOkMethod { reserved1: AmqpString::from("") })
+        }
+        11 => OpenOk(OpenOkMethod { reserved1: decode_long_str(bytes, config)? }),
+        20 => Flow(FlowMethod { active: decode_bit(bytes)? }),
+        21 => FlowOk(FlowOkMethod { active: decode_bit(bytes)? }),
         40 => Close(CloseMethod {
-            reply_code: decode_short(bytes),
-            reply_text: decode_short_str(bytes),
-            class_id: decode_short(bytes),
-            method_id: decode_short(bytes),
+            reply_code: decode_short(bytes)?,
+            reply_text: decode_short_str(bytes)?,
+            class_id: decode_short(bytes)?,
+            method_id: decode_short(bytes)?,
         }),
         41 => CloseOk,
-        m => unreachable!("Unexpected method id {} in Channel class", m),
-    }
+        30 if config.protocol_version == ProtocolVersion::Amqp0_8 => Alert(AlertMethod {
+            reply_code: decode_short(bytes)?,
+            reply_text: decode_short_str(bytes)?,
+            details: decode_field_table(bytes, config)?,
+        }),
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
+}
+// }}}
+
+
+// Decode Access Class {{{
+fn decode_access_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    _config: &Config,
+) -> Result<AccessClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 30;
+    use frame::method::access::*;
+    use self::AccessClass::*;
+    let method = match method_id {
+        10 => {
+            let realm = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 5)?;
+            Request(RequestMethod {
+                realm: realm,
+                exclusive: bits[0],
+                passive: bits[1],
+                active: bits[2],
+                write: bits[3],
+                read: bits[4],
+            })
+        }
+        11 => RequestOk(RequestOkMethod { ticket: decode_short(bytes)? }),
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
 }
 // }}}
 
 
 // Decode Exchange Class {{{
-fn decode_exchange_class(method_id: u16, _payload: &mut BytesMut) -> ExchangeClass {
+fn decode_exchange_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<ExchangeClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 40;
+    use frame::method::exchange::*;
     use self::ExchangeClass::*;
-    match method_id {
+    let is_0_8 = config.protocol_version == ProtocolVersion::Amqp0_8;
+    let method = match method_id {
+        10 => {
+            let reserved1 = decode_short(bytes)?;
+            let exchange = decode_short_str(bytes)?;
+            let typ = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 5)?;
+            Declare(DeclareMethod {
+                reserved1: reserved1,
+                exchange: exchange,
+                typ: typ,
+                passive: bits[0],
+                durable: bits[1],
+                auto_delete: bits[2],
+                internal: bits[3],
+                no_wait: bits[4],
+                arguments: decode_field_table(bytes, config)?,
+            })
+        }
         11 => DeclareOk,
+        20 => {
+            let reserved1 = decode_short(bytes)?;
+            let exchange = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 2)?;
+            Delete(DeleteMethod {
+                reserved1: reserved1,
+                exchange: exchange,
+                if_unused: bits[0],
+                no_wait: bits[1],
+            })
+        }
         21 => DeleteOk,
-        31 => BindOk, // rabbitmq-specific extension
-        51 => UnbindOk, // rabbitmq-specific extension
-        m => unreachable!("Unexpected method id {} in Channel class", m),
-    }
+        // rabbitmq-specific extension
+        30 if !is_0_8 => {
+            let reserved1 = decode_short(bytes)?;
+            let destination = decode_short_str(bytes)?;
+            let source = decode_short_str(bytes)?;
+            let routing_key = decode_short_str(bytes)?;
+            let no_wait = decode_bit(bytes)?;
+            Bind(BindMethod {
+                reserved1: reserved1,
+                destination: destination,
+                source: source,
+                routing_key: routing_key,
+                no_wait: no_wait,
+                arguments: decode_field_table(bytes, config)?,
+            })
+        }
+        31 if !is_0_8 => BindOk, // rabbitmq-specific extension
+        // rabbitmq-specific extension
+        40 if !is_0_8 => {
+            let reserved1 = decode_short(bytes)?;
+            let destination = decode_short_str(bytes)?;
+            let source = decode_short_str(bytes)?;
+            let routing_key = decode_short_str(bytes)?;
+            let no_wait = decode_bit(bytes)?;
+            Unbind(UnbindMethod {
+                reserved1: reserved1,
+                destination: destination,
+                source: source,
+                routing_key: routing_key,
+                no_wait: no_wait,
+                arguments: decode_field_table(bytes, config)?,
+            })
+        }
+        51 if !is_0_8 => UnbindOk, // rabbitmq-specific extension
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
 }
 // }}}
 
 
 // Decode Queue Class {{{
-fn decode_queue_class(method_id: u16, bytes: &mut BytesMut) -> QueueClass {
+fn decode_queue_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<QueueClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 50;
     use frame::method::queue::*;
     use self::QueueClass::*;
-    match method_id {
+    let is_0_8 = config.protocol_version == ProtocolVersion::Amqp0_8;
+    let method = match method_id {
+        10 => {
+            let reserved1 = decode_short(bytes)?;
+            let queue = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 5)?;
+            Declare(DeclareMethod {
+                reserved1: reserved1,
+                queue: queue,
+                passive: bits[0],
+                durable: bits[1],
+                exclusive: bits[2],
+                auto_delete: bits[3],
+                no_wait: bits[4],
+                arguments: decode_field_table(bytes, config)?,
+            })
+        }
         11 => DeclareOk(DeclareOkMethod {
-            queue: decode_short_str(bytes),
-            message_count: decode_long(bytes),
-            consumer_count: decode_long(bytes),
+            queue: decode_short_str(bytes)?,
+            message_count: decode_long(bytes)?,
+            consumer_count: decode_long(bytes)?,
         }),
+        20 => {
+            let reserved1 = decode_short(bytes)?;
+            let queue = decode_short_str(bytes)?;
+            let exchange = decode_short_str(bytes)?;
+            let routing_key = decode_short_str(bytes)?;
+            let no_wait = decode_bit(bytes)?;
+            Bind(BindMethod {
+                reserved1: reserved1,
+                queue: queue,
+                exchange: exchange,
+                routing_key: routing_key,
+                no_wait: no_wait,
+                arguments: decode_field_table(bytes, config)?,
+            })
+        }
         21 => BindOk,
-        31 => PurgeOk(PurgeOkMethod { message_count: decode_long(bytes) }),
-        41 => DeleteOk(DeleteOkMethod { message_count: decode_long(bytes) }),
-        51 => UnbindOk,
-        m => unreachable!("Unexpected method id {} in Channel class", m),
-    }
+        30 => Purge(PurgeMethod {
+            reserved1: decode_short(bytes)?,
+            queue: decode_short_str(bytes)?,
+            no_wait: decode_bit(bytes)?,
+        }),
+        31 => PurgeOk(PurgeOkMethod { message_count: decode_long(bytes)? }),
+        40 => {
+            let reserved1 = decode_short(bytes)?;
+            let queue = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 3)?;
+            Delete(DeleteMethod {
+                reserved1: reserved1,
+                queue: queue,
+                if_unused: bits[0],
+                if_empty: bits[1],
+                no_wait: bits[2],
+            })
+        }
+        41 => DeleteOk(DeleteOkMethod { message_count: decode_long(bytes)? }),
+        50 if !is_0_8 => Unbind(UnbindMethod {
+            reserved1: decode_short(bytes)?,
+            queue: decode_short_str(bytes)?,
+            exchange: decode_short_str(bytes)?,
+            routing_key: decode_short_str(bytes)?,
+            arguments: decode_field_table(bytes, config)?,
+        }),
+        51 if !is_0_8 => UnbindOk,
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
 }
 // }}}
 
 
 // Decode Basic Class {{{
-fn decode_basic_class(method_id: u16, bytes: &mut BytesMut) -> BasicClass {
+fn decode_basic_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<BasicClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 60;
     use frame::method::basic::*;
     use self::BasicClass::*;
-    match method_id {
+    let method = match method_id {
+        10 => Qos(QosMethod {
+            prefetch_size: decode_long(bytes)?,
+            prefetch_count: decode_short(bytes)?,
+            global: decode_bit(bytes)?,
+        }),
         11 => QosOk,
-        21 => ConsumeOk(ConsumeOkMethod { consumer_tag: decode_short_str(bytes) }),
-        31 => CancelOk(CancelOkMethod { consumer_tag: decode_short_str(bytes) }),
+        20 => {
+            let reserved1 = decode_short(bytes)?;
+            let queue = decode_short_str(bytes)?;
+            let consumer_tag = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 4)?;
+            Consume(ConsumeMethod {
+                reserved1: reserved1,
+                queue: queue,
+                consumer_tag: consumer_tag,
+                no_local: bits[0],
+                no_ack: bits[1],
+                exclusive: bits[2],
+                no_wait: bits[3],
+                arguments: match config.protocol_version {
+                    // 0-8 has no arguments for Consume
+                    ProtocolVersion::Amqp0_8 => HashMap::new(),
+                    _ => decode_field_table(bytes, config)?,
+                },
+            })
+        }
+        21 => ConsumeOk(ConsumeOkMethod { consumer_tag: decode_short_str(bytes)? }),
+        // Server sends this when the queue is deleted (consumer cancel notification)
+        30 => Cancel(CancelMethod {
+            consumer_tag: decode_short_str(bytes)?,
+            no_wait: decode_bit(bytes)?,
+        }),
+        31 => CancelOk(CancelOkMethod { consumer_tag: decode_short_str(bytes)? }),
+        40 => {
+            let reserved1 = decode_short(bytes)?;
+            let exchange = decode_short_str(bytes)?;
+            let routing_key = decode_short_str(bytes)?;
+            let bits = decode_bits(bytes, 2)?;
+            Publish(PublishMethod {
+                reserved1: reserved1,
+                exchange: exchange,
+                routing_key: routing_key,
+                mandatory: bits[0],
+                immediate: bits[1],
+            })
+        }
         50 => Return(ReturnMethod {
-            reply_code: decode_short(bytes),
-            reply_text: decode_short_str(bytes),
-            exchange: decode_short_str(bytes),
-            routing_key: decode_short_str(bytes),
+            reply_code: decode_short(bytes)?,
+            reply_text: decode_short_str(bytes)?,
+            exchange: decode_short_str(bytes)?,
+            routing_key: decode_short_str(bytes)?,
         }),
         60 => Deliver(DeliverMethod {
-            consumer_tag: decode_short_str(bytes),
-            delivery_tag: decode_longlong(bytes),
-            redeliverd: decode_bool_1(bytes),
-            exchange: decode_short_str(bytes),
-            routing_key: decode_short_str(bytes),
+            consumer_tag: decode_short_str(bytes)?,
+            delivery_tag: decode_longlong(bytes)?,
+            redeliverd: decode_bit(bytes)?,
+            exchange: decode_short_str(bytes)?,
+            routing_key: decode_short_str(bytes)?,
+        }),
+        70 => Get(GetMethod {
+            reserved1: decode_short(bytes)?,
+            queue: decode_short_str(bytes)?,
+            no_ack: decode_bit(bytes)?,
         }),
         71 => GetOk(GetOkMethod {
-            delivery_tag: decode_longlong(bytes),
-            redeliverd: decode_bool_1(bytes),
-            exchange: decode_short_str(bytes),
-            routing_key: decode_short_str(bytes),
-            message_count: decode_long(bytes),
+            delivery_tag: decode_longlong(bytes)?,
+            redeliverd: decode_bit(bytes)?,
+            exchange: decode_short_str(bytes)?,
+            routing_key: decode_short_str(bytes)?,
+            message_count: decode_long(bytes)?,
         }),
-        72 => GetEmpty(GetEmptyMethod { reserved1: decode_short_str(bytes) }),
+        72 => GetEmpty(GetEmptyMethod { reserved1: decode_short_str(bytes)? }),
+        // Ack and Nack are sent by both sides (publisher confirms / consumer acks).
         80 => Ack(AckMethod {
-            delivery_tag: decode_longlong(bytes),
-            multiple: decode_bool_1(bytes),
+            delivery_tag: decode_longlong(bytes)?,
+            multiple: decode_bit(bytes)?,
         }),
+        90 => Reject(RejectMethod {
+            delivery_tag: decode_longlong(bytes)?,
+            requeue: decode_bit(bytes)?,
+        }),
+        100 => RecoverAsync(RecoverAsyncMethod { requeue: decode_bit(bytes)? }),
+        110 => Recover(RecoverMethod { requeue: decode_bit(bytes)? }),
+        111 => RecoverOk,
 
         // rabbitmq-specific extension
-        120 => Nack(NackMethod {
-            delivery_tag: decode_longlong(bytes),
-            multiple: decode_bool_1(bytes),
-        }),
+        120 => {
+            let delivery_tag = decode_longlong(bytes)?;
+            let bits = decode_bits(bytes, 2)?;
+            Nack(NackMethod {
+                delivery_tag: delivery_tag,
+                multiple: bits[0],
+                requeue: bits[1],
+            })
+        }
+
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
+}
+// }}}
+
 
-        m => unreachable!("Unexpected method id {} in Channel class", m),
+// Decode Confirm Class {{{
+fn decode_confirm_class(
+    method_id: u16,
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<ConfirmClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 85;
+    use frame::method::confirm::*;
+    use self::ConfirmClass::*;
+    if config.protocol_version == ProtocolVersion::Amqp0_8 {
+        return Err(unknown_method(CLASS_ID, method_id));
     }
+    let method = match method_id {
+        10 => Select(SelectMethod { no_wait: decode_bit(bytes)? }),
+        11 => SelectOk,
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
 }
 // }}}
 
 
 // Decode Tx Class {{{
-fn decode_tx_class(method_id: u16, _bytes: &mut BytesMut) -> TxClass {
+fn decode_tx_class(
+    method_id: u16,
+    _bytes: &mut BytesMut,
+    _config: &Config,
+) -> Result<TxClass, DecodeErrorKind> {
+    const CLASS_ID: u16 = 90;
     use self::TxClass::*;
-    match method_id {
+    let method = match method_id {
+        10 => Select,
         11 => SelectOk,
+        20 => Commit,
         21 => CommitOk,
+        30 => Rollback,
         31 => RollbackOk,
-        m => unreachable!("Unexpected method id {} in Connection class", m),
-    }
+        m => return Err(unknown_method(CLASS_ID, m)),
+    };
+    Ok(method)
 }
 // }}}
 
 
 // Decode methods {{{
-fn decode_bool_1(bytes: &mut BytesMut) -> bool {
-    bytes.split_to(1)[0] & 0b_1000_0000 == 0b_1000_0000
+fn unknown_method(class_id: u16, method_id: u16) -> DecodeErrorKind {
+    DecodeErrorKind::UnknownMethod {
+        class_id: class_id,
+        method_id: method_id,
+    }
 }
 
 
-fn decode_octet(bytes: &mut BytesMut) -> u8 {
-    bytes.split_to(1)[0]
+/// Split first `len` bytes from `bytes`.
+/// Returns `TruncatedPayload` error if `bytes` does not have enough length.
+pub(crate) fn take_bytes(bytes: &mut BytesMut, len: usize) -> Result<BytesMut, DecodeErrorKind> {
+    if bytes.len() < len {
+        Err(DecodeErrorKind::TruncatedPayload)
+    } else {
+        Ok(bytes.split_to(len))
+    }
 }
 
 
-fn decode_short(bytes: &mut BytesMut) -> u16 {
-    Cursor::new(bytes.split_to(2)).get_u16::<BigEndian>()
+/// Decode `n` consecutive bit fields packed into shared octets.
+fn decode_bits(bytes: &mut BytesMut, n: usize) -> Result<Vec<bool>, DecodeErrorKind> {
+    Ok(unpack_bits(&take_bytes(bytes, octets_for_bits(n))?, n))
 }
 
 
-fn decode_long(bytes: &mut BytesMut) -> u32 {
-    Cursor::new(bytes.split_to(4)).get_u32::<BigEndian>()
+/// Decode a bit field which is not followed by another bit field.
+fn decode_bit(bytes: &mut BytesMut) -> Result<bool, DecodeErrorKind> {
+    Ok(decode_bits(bytes, 1)?[0])
 }
 
 
-fn decode_longlong(bytes: &mut BytesMut) -> u64 {
-    Cursor::new(bytes.split_to(8)).get_u64::<BigEndian>()
+fn decode_octet(bytes: &mut BytesMut) -> Result<u8, DecodeErrorKind> {
+    Ok(take_bytes(bytes, 1)?[0])
 }
 
 
-fn decode_short_str(bytes: &mut BytesMut) -> AmqpString {
-    let len = bytes.split_to(1)[0];
-    AmqpString(bytes.split_to(len as usize).freeze())
+fn decode_short(bytes: &mut BytesMut) -> Result<u16, DecodeErrorKind> {
+    Ok(Cursor::new(take_bytes(bytes, 2)?).get_u16::<BigEndian>())
 }
 
 
-fn decode_long_str(bytes: &mut BytesMut) -> AmqpString {
-    let len = Cursor::new(bytes.split_to(4)).get_u32::<BigEndian>();
-    AmqpString(bytes.split_to(len as usize).freeze())
+fn decode_long(bytes: &mut BytesMut) -> Result<u32, DecodeErrorKind> {
+    Ok(Cursor::new(take_bytes(bytes, 4)?).get_u32::<BigEndian>())
 }
 
 
-pub(crate) fn decode_field_table(bytes: &mut BytesMut) -> HashMap<AmqpString, FieldArgument> {
+fn decode_longlong(bytes: &mut BytesMut) -> Result<u64, DecodeErrorKind> {
+    Ok(Cursor::new(take_bytes(bytes, 8)?).get_u64::<BigEndian>())
+}
+
+
+pub(crate) fn decode_short_str(bytes: &mut BytesMut) -> Result<AmqpString, DecodeErrorKind> {
+    let len = decode_octet(bytes)?;
+    decode_utf8(take_bytes(bytes, len as usize)?)
+}
+
+
+pub(crate) fn decode_long_str(
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<AmqpString, DecodeErrorKind> {
+    let len = decode_long(bytes)? as usize;
+    if len > config.limits.max_long_str_len {
+        return Err(DecodeErrorKind::LongStringTooLong(len));
+    }
+    // Long strings are binary (e.g. SASL response), so they are not checked as utf-8.
+    Ok(AmqpString(take_bytes(bytes, len)?.freeze()))
+}
+
+
+/// Short strings are names and must be valid utf-8.
+fn decode_utf8(bytes: BytesMut) -> Result<AmqpString, DecodeErrorKind> {
+    match ::std::str::from_utf8(bytes.as_ref()) {
+        Ok(_) => Ok(AmqpString(bytes.freeze())),
+        Err(_) => Err(DecodeErrorKind::InvalidUtf8),
+    }
+}
+
+
+/// Decode field table.
+/// Items are decoded from `bytes` directly (not from a split buffer) so that the length of
+/// rest of `bytes` always tells the position of an error.
+pub(crate) fn decode_field_table(
+    bytes: &mut BytesMut,
+    config: &Config,
+) -> Result<HashMap<AmqpString, FieldArgument>, DecodeErrorKind> {
+    decode_field_table_n(bytes, config, 1)
+}
+
+
+/// Decode field table at nesting depth `depth`.
+fn decode_field_table_n(
+    bytes: &mut BytesMut,
+    config: &Config,
+    depth: usize,
+) -> Result<HashMap<AmqpString, FieldArgument>, DecodeErrorKind> {
     debug!("decode field table");
 
-    let size = Cursor::new(bytes.split_to(4)).get_u32::<BigEndian>() as u64;
+    let limits = &config.limits;
+    if depth > limits.max_table_depth {
+        return Err(DecodeErrorKind::TableTooDeep(depth));
+    }
 
-    let mut bytes = bytes.split_to(size as usize);
+    let size = decode_long(bytes)? as usize;
+    if size > limits.max_table_bytes {
+        return Err(DecodeErrorKind::TableTooLarge(size));
+    }
+    if bytes.len() < size {
+        return Err(DecodeErrorKind::TruncatedPayload);
+    }
+    let end = bytes.len() - size;
 
     let mut table = HashMap::new();
 
-    while bytes.len() > 0 {
-        let item_name = decode_short_str(&mut bytes);
-        let item_value = decode_field_item_value(&mut bytes);
+    while bytes.len() > end {
+        if table.len() >= limits.max_table_entries {
+            return Err(DecodeErrorKind::TooManyTableEntries(table.len() + 1));
+        }
+        let item_name = decode_short_str(bytes)?;
+        let item_value = decode_field_item_value(bytes, config, depth)?;
         table.insert(item_name, item_value);
     }
 
-    table
+    // Last item overran the table
+    if bytes.len() < end {
+        return Err(DecodeErrorKind::TruncatedPayload);
+    }
+
+    Ok(table)
 }
 
 
-/*
-fn decode_field_array(cursor: &mut Cursor<Bytes>) -> Result<FieldArray> {
-    let size = cursor.get_u32::<BigEndian>() as u64;
+/// Decode field array at nesting depth `depth`.
+/// Arrays are limited by `DecodeLimits` in the same way as field tables.
+fn decode_field_array_n(
+    bytes: &mut BytesMut,
+    config: &Config,
+    depth: usize,
+) -> Result<Vec<FieldArgument>, DecodeErrorKind> {
+    debug!("decode field array");
+
+    let limits = &config.limits;
+    if depth > limits.max_table_depth {
+        return Err(DecodeErrorKind::TableTooDeep(depth));
+    }
+
+    let size = decode_long(bytes)? as usize;
+    if size > limits.max_table_bytes {
+        return Err(DecodeErrorKind::TableTooLarge(size));
+    }
+    if bytes.len() < size {
+        return Err(DecodeErrorKind::TruncatedPayload);
+    }
+    let end = bytes.len() - size;
 
     let mut items = Vec::new();
-    let start_pos = cursor.position();
 
-    while (cursor.position() - start_pos) < size {
-        let item = decode_field_item_value(cursor);
-        items.push(item);
+    while bytes.len() > end {
+        if items.len() >= limits.max_table_entries {
+            return Err(DecodeErrorKind::TooManyTableEntries(items.len() + 1));
+        }
+        items.push(decode_field_item_value(bytes, config, depth)?);
+    }
+
+    // Last item overran the array
+    if bytes.len() < end {
+        return Err(DecodeErrorKind::TruncatedPayload);
     }
-    Ok(FieldArray(items))
+
+    Ok(items)
 }
-*/
-
-
-fn decode_field_item_value(bytes: &mut BytesMut) -> FieldArgument {
-    let flag = bytes.split_to(1)[0];
-    match flag {
-        0x74 => FieldArgument::Boolean(bytes.split_to(1)[0] == 0x01),
-        0x62 => FieldArgument::SignedOctet(Cursor::new(bytes.split_to(1)).get_i8()),
-        0x42 => FieldArgument::UnsignedOctet(bytes.split_to(1)[0]),
-        0x55 => FieldArgument::SignedShort(Cursor::new(bytes.split_to(2)).get_i16::<BigEndian>()),
-        0x75 => FieldArgument::UnsignedShort(Cursor::new(bytes.split_to(2)).get_u16::<BigEndian>()),
-        0x49 => FieldArgument::SignedLong(Cursor::new(bytes.split_to(4)).get_i32::<BigEndian>()),
-        0x69 => FieldArgument::UnsignedLong(Cursor::new(bytes.split_to(4)).get_u32::<BigEndian>()),
-        0x4C => FieldArgument::SignedLongLong(
-            Cursor::new(bytes.split_to(8)).get_i64::<BigEndian>(),
-        ),
-        0x6C => FieldArgument::UnsignedLongLong(
-            Cursor::new(bytes.split_to(8)).get_u64::<BigEndian>(),
-        ),
-        0x66 => FieldArgument::Float(Cursor::new(bytes.split_to(4)).get_f32::<BigEndian>()),
-        0x63 => FieldArgument::Double(Cursor::new(bytes.split_to(8)).get_f64::<BigEndian>()),
-        0x44 => FieldArgument::Decimal(Cursor::new(bytes.split_to(8)).get_i64::<BigEndian>()),
-        0x73 => {
-            let len = bytes.split_to(1)[0];
-            FieldArgument::ShortString(AmqpString(bytes.split_to(len as usize).freeze()))
-        }
-        0x53 => {
-            let len = Cursor::new(bytes.split_to(4)).get_u32::<BigEndian>();
-            FieldArgument::LongString(AmqpString(bytes.split_to(len as usize).freeze()))
-        }
-        0x54 => FieldArgument::Timestamp(Cursor::new(bytes.split_to(8)).get_u64::<BigEndian>()),
-        0x46 => FieldArgument::NestedTable(decode_field_table(bytes)),
-        0x56 => FieldArgument::Void,
-        0x78 => panic!(), // I don't know how should I treat it
-        b => unreachable!("Unexpected byte {} at decode_field_item_value", b),
+
+
+/// Decode a byte array. Its length is limited by `DecodeLimits::max_long_str_len`.
+fn decode_byte_array(bytes: &mut BytesMut, config: &Config) -> Result<Vec<u8>, DecodeErrorKind> {
+    let len = decode_long(bytes)? as usize;
+    if len > config.limits.max_long_str_len {
+        return Err(DecodeErrorKind::LongStringTooLong(len));
+    }
+    Ok(take_bytes(bytes, len)?.to_vec())
+}
+
+
+/// Decode a field value in a table or an array at nesting depth `depth`.
+fn decode_field_item_value(
+    bytes: &mut BytesMut,
+    config: &Config,
+    depth: usize,
+) -> Result<FieldArgument, DecodeErrorKind> {
+    use frame::method::field_tag::FieldKind::*;
+
+    let tag = decode_octet(bytes)?;
+    let kind = match kind_of(config.field_dialect, tag) {
+        Some(kind) => kind,
+        None => return Err(DecodeErrorKind::BadFieldTag(tag)),
+    };
+    let item = match kind {
+        Boolean => FieldArgument::Boolean(decode_octet(bytes)? == 0x01),
+        SignedOctet => FieldArgument::SignedOctet(decode_octet(bytes)? as i8),
+        UnsignedOctet => FieldArgument::UnsignedOctet(decode_octet(bytes)?),
+        SignedShort => FieldArgument::SignedShort(decode_short(bytes)? as i16),
+        UnsignedShort => FieldArgument::UnsignedShort(decode_short(bytes)?),
+        SignedLong => FieldArgument::SignedLong(decode_long(bytes)? as i32),
+        UnsignedLong => FieldArgument::UnsignedLong(decode_long(bytes)?),
+        SignedLongLong => FieldArgument::SignedLongLong(decode_longlong(bytes)? as i64),
+        UnsignedLongLong => FieldArgument::UnsignedLongLong(decode_longlong(bytes)?),
+        Float => FieldArgument::Float(Cursor::new(take_bytes(bytes, 4)?).get_f32::<BigEndian>()),
+        Double => FieldArgument::Double(Cursor::new(take_bytes(bytes, 8)?).get_f64::<BigEndian>()),
+        Decimal => {
+            let scale = decode_octet(bytes)?;
+            FieldArgument::Decimal(::args::Decimal::new(scale, decode_long(bytes)? as i32))
+        }
+        ShortString => FieldArgument::ShortString(decode_short_str(bytes)?),
+        LongString => FieldArgument::LongString(decode_long_str(bytes, config)?),
+        Array => FieldArgument::Array(decode_field_array_n(bytes, config, depth + 1)?),
+        Timestamp => FieldArgument::Timestamp(decode_longlong(bytes)?),
+        Table => FieldArgument::NestedTable(decode_field_table_n(bytes, config, depth + 1)?),
+        Void => FieldArgument::Void,
+        ByteArray => FieldArgument::ByteArray(decode_byte_array(bytes, config)?),
+    };
+    Ok(item)
+}
+// }}}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+    use config::{DecodeLimits, Role};
+
+    fn config_with_limits(limits: DecodeLimits) -> Config {
+        Config {
+            limits: limits,
+            ..Config::default()
+        }
+    }
+
+    /// Make a field table which contains `depth` nested tables.
+    fn nested_table_bytes(depth: usize) -> BytesMut {
+        let mut table = vec![0, 0, 0, 0];
+        for _ in 0..depth {
+            let mut outer = Vec::new();
+            outer.put_u32::<BigEndian>(table.len() as u32 + 3);
+            outer.extend_from_slice(&[1, b'a', b'F']);
+            outer.extend_from_slice(&table);
+            table = outer;
+        }
+        BytesMut::from(table)
+    }
+
+    #[test]
+    fn too_deep_table_is_error() {
+        let config = config_with_limits(DecodeLimits {
+            max_table_depth: 4,
+            ..DecodeLimits::default()
+        });
+
+        assert!(decode_field_table(&mut nested_table_bytes(3), &config).is_ok());
+        match decode_field_table(&mut nested_table_bytes(4), &config) {
+            Err(DecodeErrorKind::TableTooDeep(5)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn too_many_table_entries_is_error() {
+        let config = config_with_limits(DecodeLimits {
+            max_table_entries: 1,
+            ..DecodeLimits::default()
+        });
+        let mut bytes = BytesMut::from(&[0, 0, 0, 6, 1, b'a', b'V', 1, b'b', b'V'][..]);
+
+        match decode_field_table(&mut bytes, &config) {
+            Err(DecodeErrorKind::TooManyTableEntries(2)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn too_large_table_is_error_before_reading_it() {
+        let config = config_with_limits(DecodeLimits {
+            max_table_bytes: 1024,
+            ..DecodeLimits::default()
+        });
+        let mut bytes = BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF][..]);
+
+        match decode_field_table(&mut bytes, &config) {
+            Err(DecodeErrorKind::TableTooLarge(0xFFFF_FFFF)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn too_long_long_string_is_error() {
+        let config = config_with_limits(DecodeLimits {
+            max_long_str_len: 2,
+            ..DecodeLimits::default()
+        });
+        let mut bytes = BytesMut::from(&[0, 0, 0, 3, b'a', b'b', b'c'][..]);
+
+        match decode_long_str(&mut bytes, &config) {
+            Err(DecodeErrorKind::LongStringTooLong(3)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn round_trip_nested_arrays_and_byte_arrays() {
+        use frame::method::encoder::encode_field_table_0;
+
+        let mut inner = HashMap::new();
+        inner.insert(AmqpString::from("count"), FieldArgument::SignedLongLong(1));
+        inner.insert(AmqpString::from("raw"), FieldArgument::ByteArray(vec![0xCE, 0, 1]));
+        inner.insert(AmqpString::from("price"), FieldArgument::Decimal(Decimal::new(2, -125)));
+
+        let mut table = HashMap::new();
+        table.insert(
+            AmqpString::from("x-death"),
+            FieldArgument::Array(vec![
+                FieldArgument::NestedTable(inner),
+                FieldArgument::Array(vec![FieldArgument::ByteArray(vec![])]),
+                FieldArgument::LongString(AmqpString::from("queue")),
+            ]),
+        );
+        table.insert(AmqpString::from("empty"), FieldArgument::Array(vec![]));
+
+        let mut dst = Vec::new();
+        encode_field_table_0(&table, &mut dst, &Config::default()).unwrap();
+        let mut bytes = BytesMut::from(dst);
+
+        assert_eq!(decode_field_table(&mut bytes, &Config::default()).unwrap(), table);
+        assert_eq!(bytes.len(), 0);
+    }
+
+    #[test]
+    fn decimal_is_5_bytes() {
+        // { "d": 1.5, "v": void }
+        let mut bytes = BytesMut::from(
+            &[0, 0, 0, 11, 1, b'd', b'D', 1, 0, 0, 0, 15, 1, b'v', b'V'][..],
+        );
+
+        let table = decode_field_table(&mut bytes, &Config::default()).unwrap();
+
+        let d = table.get(&AmqpString::from("d"));
+        assert_eq!(d, Some(&FieldArgument::Decimal(Decimal::new(1, 15))));
+        assert_eq!(table.get(&AmqpString::from("v")), Some(&FieldArgument::Void));
+    }
+
+    #[test]
+    fn tags_follow_field_dialect() {
+        use frame::method::encoder::encode_field_table_0;
+        use config::FieldDialect;
+
+        let rabbitmq = Config {
+            field_dialect: FieldDialect::RabbitMQ,
+            ..Config::default()
+        };
+        // { "a": 's' 0x0001 } in RabbitMQ, { "a": 's' "b" } in the spec
+        let mut bytes = BytesMut::from(&[0, 0, 0, 5, 1, b'a', b's', 1, b'b'][..]);
+
+        let table = decode_field_table(&mut bytes.clone(), &rabbitmq).unwrap();
+        let short = FieldArgument::SignedShort(0x0162);
+        assert_eq!(table.get(&AmqpString::from("a")), Some(&short));
+
+        let table = decode_field_table(&mut bytes, &Config::default()).unwrap();
+        let string = FieldArgument::ShortString(AmqpString::from("b"));
+        assert_eq!(table.get(&AmqpString::from("a")), Some(&string));
+
+        // RabbitMQ does not know unsigned short, so it is sent as signed long.
+        let mut table = HashMap::new();
+        table.insert(AmqpString::from("a"), FieldArgument::UnsignedShort(1));
+        let mut dst = Vec::new();
+        encode_field_table_0(&table, &mut dst, &rabbitmq).unwrap();
+        assert_eq!(dst, vec![0, 0, 0, 7, 1, b'a', b'I', 0, 0, 0, 1]);
+
+        let mut bytes = BytesMut::from(&[0, 0, 0, 5, 1, b'a', b'u', 0, 1][..]);
+        match decode_field_table(&mut bytes, &rabbitmq) {
+            Err(DecodeErrorKind::BadFieldTag(b'u')) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn too_deep_array_is_error() {
+        let config = config_with_limits(DecodeLimits {
+            max_table_depth: 2,
+            ..DecodeLimits::default()
+        });
+        // { "a": [ [] ] }
+        let mut bytes = BytesMut::from(
+            &[0, 0, 0, 12, 1, b'a', b'A', 0, 0, 0, 5, b'A', 0, 0, 0, 0][..],
+        );
+
+        match decode_field_table(&mut bytes, &config) {
+            Err(DecodeErrorKind::TableTooDeep(3)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    fn round_trip(method: MethodPayload) -> MethodPayload {
+        use frame::method::encoder::encode_payload;
+        let config = Config {
+            role: Role::Both,
+            ..Config::default()
+        };
+        let mut bytes = BytesMut::from(encode_payload(method, &config).unwrap());
+        let decoded = decode_payload(&mut bytes, &config).unwrap();
+        assert_eq!(bytes.len(), 0);
+        decoded
+    }
+
+    #[test]
+    fn round_trip_exchange_declare() {
+        use frame::method::exchange::DeclareMethod;
+
+        let mut arguments = HashMap::new();
+        arguments.insert(AmqpString::from("alternate-exchange"), FieldArgument::Void);
+        // Set every other bit so that a shifted bit can be detected.
+        let method = MethodPayload::Exchange(ExchangeClass::Declare(DeclareMethod {
+            reserved1: 0,
+            exchange: AmqpString::from("logs"),
+            typ: AmqpString::from("topic"),
+            passive: true,
+            durable: false,
+            auto_delete: true,
+            internal: false,
+            no_wait: true,
+            arguments: arguments,
+        }));
+
+        assert_eq!(round_trip(method.clone()), method);
+    }
+
+    #[test]
+    fn round_trip_queue_declare() {
+        use frame::method::queue::DeclareMethod;
+
+        let method = MethodPayload::Queue(QueueClass::Declare(DeclareMethod {
+            reserved1: 0,
+            queue: AmqpString::from("tasks"),
+            passive: false,
+            durable: true,
+            exclusive: false,
+            auto_delete: true,
+            no_wait: false,
+            arguments: HashMap::new(),
+        }));
+
+        assert_eq!(round_trip(method.clone()), method);
+    }
+
+    #[test]
+    fn round_trip_basic_consume() {
+        use frame::method::basic::ConsumeMethod;
+
+        let method = MethodPayload::Basic(BasicClass::Consume(ConsumeMethod {
+            reserved1: 0,
+            queue: AmqpString::from("tasks"),
+            consumer_tag: AmqpString::from("worker"),
+            no_local: false,
+            no_ack: true,
+            exclusive: true,
+            no_wait: false,
+            arguments: HashMap::new(),
+        }));
+
+        assert_eq!(round_trip(method.clone()), method);
+    }
+
+    #[test]
+    fn round_trip_other_multi_bit_methods() {
+        use frame::method::{exchange, queue, basic};
+
+        let methods = vec![
+            MethodPayload::Exchange(ExchangeClass::Delete(exchange::DeleteMethod {
+                reserved1: 0,
+                exchange: AmqpString::from("logs"),
+                if_unused: false,
+                no_wait: true,
+            })),
+            MethodPayload::Queue(QueueClass::Delete(queue::DeleteMethod {
+                reserved1: 0,
+                queue: AmqpString::from("tasks"),
+                if_unused: true,
+                if_empty: false,
+                no_wait: true,
+            })),
+            MethodPayload::Basic(BasicClass::Publish(basic::PublishMethod {
+                reserved1: 0,
+                exchange: AmqpString::from(""),
+                routing_key: AmqpString::from("tasks"),
+                mandatory: false,
+                immediate: true,
+            })),
+            MethodPayload::Channel(ChannelClass::FlowOk(::frame::method::channel::FlowOkMethod {
+                active: true,
+            })),
+        ];
+
+        for method in methods {
+            assert_eq!(round_trip(method.clone()), method);
+        }
+    }
+
+    #[test]
+    fn round_trip_ack_reject_and_nack() {
+        use frame::method::basic::{AckMethod, RejectMethod, NackMethod};
+
+        let methods = vec![
+            MethodPayload::Basic(BasicClass::Ack(AckMethod {
+                delivery_tag: 42,
+                multiple: true,
+            })),
+            MethodPayload::Basic(BasicClass::Reject(RejectMethod {
+                delivery_tag: 42,
+                requeue: true,
+            })),
+            MethodPayload::Basic(BasicClass::Nack(NackMethod {
+                delivery_tag: 42,
+                multiple: false,
+                requeue: true,
+            })),
+        ];
+
+        for method in methods {
+            assert_eq!(round_trip(method.clone()), method);
+        }
+    }
+
+    #[test]
+    fn round_trip_cancel_and_cancel_ok() {
+        use frame::method::basic::{CancelMethod, CancelOkMethod};
+
+        let methods = vec![
+            MethodPayload::Basic(BasicClass::Cancel(CancelMethod {
+                consumer_tag: AmqpString::from("worker"),
+                no_wait: true,
+            })),
+            MethodPayload::Basic(BasicClass::CancelOk(CancelOkMethod {
+                consumer_tag: AmqpString::from("worker"),
+            })),
+        ];
+
+        for method in methods {
+            assert_eq!(round_trip(method.clone()), method);
+        }
+    }
+
+    #[test]
+    fn update_secret_and_update_secret_ok() {
+        use frame::method::encoder::encode_payload;
+        use frame::method::connection::UpdateSecretMethod;
+
+        let update = MethodPayload::Connection(ConnectionClass::UpdateSecret(UpdateSecretMethod {
+            new_secret: AmqpString::from("jwt"),
+            reason: AmqpString::from("x"),
+        }));
+        assert_eq!(
+            encode_payload(update, &Config::default()).unwrap(),
+            vec![0, 10, 0, 70, 0, 0, 0, 3, b'j', b'w', b't', 1, b'x']
+        );
+
+        let mut bytes = BytesMut::from(&[0, 10, 0, 71][..]);
+        let update_ok = decode_payload(&mut bytes, &Config::default()).unwrap();
+        assert_eq!(update_ok.connection().and_then(|c| c.update_secret_ok()), Some(()));
+    }
+
+    #[test]
+    fn round_trip_access_request_and_request_ok() {
+        use frame::method::access::{RequestMethod, RequestOkMethod};
+
+        let methods = vec![
+            MethodPayload::Access(AccessClass::Request(RequestMethod {
+                realm: AmqpString::from("/data"),
+                exclusive: false,
+                passive: true,
+                active: true,
+                write: false,
+                read: true,
+            })),
+            MethodPayload::Access(AccessClass::RequestOk(RequestOkMethod { ticket: 1 })),
+        ];
+
+        for method in methods {
+            assert_eq!(round_trip(method.clone()), method);
+        }
+    }
+
+    #[test]
+    fn confirm_select_and_select_ok() {
+        use frame::method::encoder::encode_payload;
+        use frame::method::confirm::SelectMethod;
+
+        let select = MethodPayload::Confirm(ConfirmClass::Select(SelectMethod { no_wait: true }));
+        assert_eq!(encode_payload(select, &Config::default()).unwrap(), vec![0, 85, 0, 10, 1]);
+
+        let mut bytes = BytesMut::from(&[0, 85, 0, 11][..]);
+        let select_ok = decode_payload(&mut bytes, &Config::default()).unwrap();
+        assert_eq!(select_ok.confirm().and_then(|c| c.select_ok()), Some(()));
+    }
+
+    #[test]
+    fn redelivered_is_least_significant_bit() {
+        // Basic.Deliver with consumer_tag "c", delivery_tag 1 and redelivered set
+        let mut bytes = BytesMut::from(
+            &[0, 60, 0, 60, 1, b'c', 0, 0, 0, 0, 0, 0, 0, 1, 0b_0000_0001, 0, 0][..],
+        );
+
+        match decode_payload(&mut bytes, &Config::default()).unwrap() {
+            MethodPayload::Basic(BasicClass::Deliver(m)) => assert!(m.redeliverd),
+            m => panic!("Unexpected method {:?}", m),
+        }
+    }
+
+    fn config_of(version: ProtocolVersion) -> Config {
+        Config {
+            protocol_version: version,
+            role: Role::Both,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn connection_close_id_follows_protocol_version() {
+        use frame::method::encoder::encode_payload;
+
+        let config = config_of(ProtocolVersion::Amqp0_8);
+        let close_ok = MethodPayload::Connection(ConnectionClass::CloseOk);
+        assert_eq!(encode_payload(close_ok, &config).unwrap(), vec![0, 10, 0, 61]);
+
+        // Connection.Close in 0-8, which is Connection.Blocked in 0-9-1
+        let bytes = [0, 10, 0, 60, 1, 64, 1, b'x', 0, 0, 0, 0];
+        let decoded = decode_payload(&mut BytesMut::from(&bytes[..]), &config).unwrap();
+        assert_eq!(decoded.connection().and_then(|c| c.close()).map(|m| m.reply_code), Some(320));
+
+        let decoded = decode_payload(&mut BytesMut::from(&bytes[..]), &Config::default()).unwrap();
+        assert!(decoded.connection().and_then(|c| c.blocked()).is_some());
+    }
+
+    #[test]
+    fn connection_redirect_exists_only_before_0_9_1() {
+        let bytes = [0, 10, 0, 42, 1, b'h', 0];
+
+        let decoded = decode_payload(
+            &mut BytesMut::from(&bytes[..]),
+            &config_of(ProtocolVersion::Amqp0_9),
+        ).unwrap();
+        let redirect = decoded.connection().and_then(|c| c.redirect()).unwrap();
+        assert_eq!(redirect.host, AmqpString::from("h"));
+
+        match decode_payload(&mut BytesMut::from(&bytes[..]), &Config::default()) {
+            Err(DecodeErrorKind::UnknownMethod { class_id: 10, method_id: 42 }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn channel_methods_in_0_8() {
+        let config = config_of(ProtocolVersion::Amqp0_8);
+
+        let mut bytes = BytesMut::from(&[0, 20, 0, 11][..]);
+        let open_ok = decode_payload(&mut bytes, &config).unwrap();
+        assert_eq!(
+            open_ok.channel().and_then(|c| c.open_ok()).map(|m| m.reserved1.clone()),
+            Some(AmqpString::from(""))
+        );
+
+        let mut bytes = BytesMut::from(&[0, 20, 0, 30, 1, 56, 0, 0, 0, 0, 0][..]);
+        let alert = decode_payload(&mut bytes, &config).unwrap();
+        assert_eq!(alert.channel().and_then(|c| c.alert()).map(|m| m.reply_code), Some(312));
+    }
+
+    #[test]
+    fn basic_consume_has_no_arguments_in_0_8() {
+        use frame::method::encoder::encode_payload;
+        use frame::method::basic::ConsumeMethod;
+
+        let config = config_of(ProtocolVersion::Amqp0_8);
+        let mut consume = ConsumeMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            consumer_tag: AmqpString::from(""),
+            no_local: false,
+            no_ack: true,
+            exclusive: false,
+            no_wait: false,
+            arguments: HashMap::new(),
+        };
+
+        let method = MethodPayload::Basic(BasicClass::Consume(consume.clone()));
+        let encoded = encode_payload(method.clone(), &config).unwrap();
+        assert_eq!(encoded, vec![0, 60, 0, 20, 0, 0, 1, b'q', 0, 0b_0000_0010]);
+        let mut bytes = BytesMut::from(encoded);
+        assert_eq!(decode_payload(&mut bytes, &config).unwrap(), method);
+        assert_eq!(bytes.len(), 0);
+
+        consume.arguments.insert(AmqpString::from("x-priority"), FieldArgument::SignedLong(1));
+        let method = MethodPayload::Basic(BasicClass::Consume(consume));
+        assert!(encode_payload(method, &config).is_err());
+    }
+
+    #[test]
+    fn methods_added_after_0_8_are_unsupported_in_0_8() {
+        use errors::EncodeErrorKind;
+        use frame::method::encoder::encode_payload;
+        use frame::method::confirm::ConfirmClass;
+        use frame::method::connection::OpenMethod;
+        use frame::method::exchange::ExchangeClass;
+        use frame::method::queue::QueueClass;
+
+        let config = config_of(ProtocolVersion::Amqp0_8);
+        let methods = [
+            MethodPayload::Exchange(ExchangeClass::BindOk),
+            MethodPayload::Exchange(ExchangeClass::UnbindOk),
+            MethodPayload::Queue(QueueClass::UnbindOk),
+            MethodPayload::Confirm(ConfirmClass::SelectOk),
+        ];
+        for method in methods.iter() {
+            let (class_id, method_id) = (method.class_id(), method.method_id());
+            match encode_payload(method.clone(), &config) {
+                Err(EncodeErrorKind::UnsupportedMethod { .. }) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+
+            let mut bytes = BytesMut::from(&[0, class_id as u8, 0, method_id as u8][..]);
+            match decode_payload(&mut bytes, &config) {
+                Err(DecodeErrorKind::UnknownMethod { .. }) => {}
+                r => panic!("Unexpected result {:?}", r),
+            }
+
+            let config = config_of(ProtocolVersion::Amqp0_9_1);
+            let mut bytes = BytesMut::from(encode_payload(method.clone(), &config).unwrap());
+            assert_eq!(&decode_payload(&mut bytes, &config).unwrap(), method);
+        }
+
+        // Connection.Open has "capabilities" and "insist" in 0-8.
+        let bytes = [0, 10, 0, 40, 1, b'/', 1, b'x', 1];
+        let decoded = decode_payload(&mut BytesMut::from(&bytes[..]), &config).unwrap();
+        let open: &OpenMethod = decoded.connection().and_then(|c| c.open()).unwrap();
+        assert_eq!((&**open.capabilities(), open.insist()), ("x", true));
+    }
+
+    #[test]
+    fn update_secret_exists_only_in_0_9_1() {
+        use errors::EncodeErrorKind;
+        use frame::method::encoder::encode_payload;
+        use frame::method::connection::UpdateSecretMethod;
+
+        let update = MethodPayload::Connection(ConnectionClass::UpdateSecret(UpdateSecretMethod {
+            new_secret: AmqpString::from("token"),
+            reason: AmqpString::from("refresh"),
+        }));
+        let methods = [update, MethodPayload::Connection(ConnectionClass::UpdateSecretOk)];
+        for method in methods.iter() {
+            let config = config_of(ProtocolVersion::Amqp0_9_1);
+            let encoded = encode_payload(method.clone(), &config).unwrap();
+            let decoded = decode_payload(&mut BytesMut::from(&encoded[..]), &config).unwrap();
+            assert_eq!(&decoded, method);
+
+            for &version in &[ProtocolVersion::Amqp0_8, ProtocolVersion::Amqp0_9] {
+                let config = config_of(version);
+                match encode_payload(method.clone(), &config) {
+                    Err(EncodeErrorKind::UnsupportedMethod { class_id: 10, .. }) => {}
+                    r => panic!("Unexpected result {:?}", r),
+                }
+                match decode_payload(&mut BytesMut::from(&encoded[..]), &config) {
+                    Err(DecodeErrorKind::UnknownMethod { class_id: 10, .. }) => {}
+                    r => panic!("Unexpected result {:?}", r),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_methods_of_both_directions() {
+        use frame::method::{connection, channel, exchange, queue, basic, confirm};
+
+        let mut properties = HashMap::new();
+        properties.insert(AmqpString::from("product"), FieldArgument::LongString("x".into()));
+        let methods = vec![
+            MethodPayload::Connection(ConnectionClass::Start(connection::StartMethod {
+                version_major: 0,
+                version_minor: 9,
+                server_properties: properties.clone(),
+                mechanisms: AmqpString::from("PLAIN AMQPLAIN"),
+                locales: AmqpString::from("en_US"),
+            })),
+            MethodPayload::Connection(ConnectionClass::StartOk(connection::StartOkMethod {
+                client_properties: properties,
+                mechanism: AmqpString::from("PLAIN"),
+                response: AmqpString::from("\0guest\0guest"),
+                locale: AmqpString::from("en_US"),
+            })),
+            MethodPayload::Connection(ConnectionClass::Tune(connection::TuneMethod {
+                channel_max: 2047,
+                frame_max: 131_072,
+                heartbeat: 60,
+            })),
+            MethodPayload::Connection(ConnectionClass::Open(connection::OpenMethod {
+                virtual_host: AmqpString::from("/"),
+                reserved1: AmqpString::from(""),
+                reserved2: true,
+            })),
+            MethodPayload::Connection(ConnectionClass::OpenOk(connection::OpenOkMethod {
+                reserved1: AmqpString::from(""),
+            })),
+            MethodPayload::Channel(ChannelClass::Open(channel::OpenMethod {
+                reserved1: AmqpString::from(""),
+            })),
+            MethodPayload::Channel(ChannelClass::OpenOk(channel::OpenOkMethod {
+                reserved1: AmqpString::from(""),
+            })),
+            MethodPayload::Exchange(ExchangeClass::Bind(exchange::BindMethod {
+                reserved1: 0,
+                destination: AmqpString::from("dst"),
+                source: AmqpString::from("src"),
+                routing_key: AmqpString::from("a.b"),
+                no_wait: true,
+                arguments: HashMap::new(),
+            })),
+            MethodPayload::Exchange(ExchangeClass::BindOk),
+            MethodPayload::Queue(QueueClass::DeclareOk(queue::DeclareOkMethod {
+                queue: AmqpString::from("amq.gen-1"),
+                message_count: 3,
+                consumer_count: 1,
+            })),
+            MethodPayload::Queue(QueueClass::Unbind(queue::UnbindMethod {
+                reserved1: 0,
+                queue: AmqpString::from("tasks"),
+                exchange: AmqpString::from("logs"),
+                routing_key: AmqpString::from("a"),
+                arguments: HashMap::new(),
+            })),
+            MethodPayload::Queue(QueueClass::Purge(queue::PurgeMethod {
+                reserved1: 0,
+                queue: AmqpString::from("tasks"),
+                no_wait: false,
+            })),
+            MethodPayload::Basic(BasicClass::Qos(basic::QosMethod {
+                prefetch_size: 0,
+                prefetch_count: 10,
+                global: true,
+            })),
+            MethodPayload::Basic(BasicClass::Deliver(basic::DeliverMethod {
+                consumer_tag: AmqpString::from("worker"),
+                delivery_tag: 7,
+                redeliverd: true,
+                exchange: AmqpString::from("logs"),
+                routing_key: AmqpString::from("a"),
+            })),
+            MethodPayload::Basic(BasicClass::GetOk(basic::GetOkMethod {
+                delivery_tag: 7,
+                redeliverd: false,
+                exchange: AmqpString::from(""),
+                routing_key: AmqpString::from("tasks"),
+                message_count: 0,
+            })),
+            MethodPayload::Basic(BasicClass::Return(basic::ReturnMethod {
+                reply_code: 312,
+                reply_text: AmqpString::from("NO_ROUTE"),
+                exchange: AmqpString::from("logs"),
+                routing_key: AmqpString::from("a"),
+            })),
+            MethodPayload::Basic(BasicClass::Recover(basic::RecoverMethod { requeue: true })),
+            MethodPayload::Basic(BasicClass::RecoverOk),
+            MethodPayload::Confirm(ConfirmClass::Select(confirm::SelectMethod { no_wait: false })),
+            MethodPayload::Tx(TxClass::Commit),
+            MethodPayload::Tx(TxClass::CommitOk),
+        ];
+
+        for method in methods {
+            assert_eq!(round_trip(method.clone()), method);
+        }
+    }
+
+    #[test]
+    fn role_decides_direction_of_methods() {
+        use frame::method::encoder::encode_payload;
+        use frame::method::basic::GetEmptyMethod;
+        use errors::EncodeErrorKind;
+
+        let client = Config::default();
+        let server = Config {
+            role: Role::Server,
+            ..Config::default()
+        };
+        let get_empty = MethodPayload::Basic(BasicClass::GetEmpty(GetEmptyMethod {
+            reserved1: AmqpString::from(""),
+        }));
+
+        match encode_payload(get_empty.clone(), &client) {
+            Err(EncodeErrorKind::UnsupportedMethod { class_id: 60, method_id: 72 }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let encoded = encode_payload(get_empty.clone(), &server).unwrap();
+
+        match decode_payload(&mut BytesMut::from(encoded.clone()), &server) {
+            Err(DecodeErrorKind::UnexpectedMethod { class_id: 60, method_id: 72 }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(decode_payload(&mut BytesMut::from(encoded), &client).unwrap(), get_empty);
+
+        // Methods sent by both sides
+        let close_ok = MethodPayload::Connection(ConnectionClass::CloseOk);
+        assert!(encode_payload(close_ok.clone(), &client).is_ok());
+        assert!(encode_payload(close_ok, &server).is_ok());
+    }
+
+    #[test]
+    fn long_strings_are_not_checked_as_utf8() {
+        use frame::method::connection::StartOkMethod;
+
+        let binary = AmqpString::from(&[0, b'u', 0, 0xFF, 0xFE][..]);
+        let mut properties = HashMap::new();
+        properties.insert(AmqpString::from("token"), FieldArgument::LongString(binary.clone()));
+        let method = MethodPayload::Connection(ConnectionClass::StartOk(StartOkMethod {
+            client_properties: properties,
+            mechanism: AmqpString::from("PLAIN"),
+            response: binary,
+            locale: AmqpString::from("en_US"),
+        }));
+
+        assert_eq!(round_trip(method.clone()), method);
+
+        // Short strings are still checked.
+        let mut bytes = BytesMut::from(&[0, 60, 0, 21, 1, 0xFF][..]);
+        match decode_payload(&mut bytes, &Config::default()) {
+            Err(DecodeErrorKind::InvalidUtf8) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
     }
 }
 // }}}
@@ -2,95 +2,185 @@ use bytes::{BufMut, BigEndian};
 
 use std::collections::HashMap;
 
-use super::{MethodPayload, ConnectionClass, ChannelClass, ExchangeClass, QueueClass, BasicClass,
-            TxClass};
+use super::bits::pack_bits;
+use super::field_tag::{FieldKind, tag_of, widen};
+use super::{MethodPayload, ConnectionClass, ChannelClass, AccessClass, ExchangeClass, QueueClass,
+            BasicClass, ConfirmClass, TxClass};
 use args::*;
+use config::{Config, ProtocolVersion};
+use errors::EncodeErrorKind;
 
 
-pub fn encode_payload(payload: MethodPayload) -> Vec<u8> {
+pub fn encode_payload(payload: MethodPayload, config: &Config) -> Result<Vec<u8>, EncodeErrorKind> {
     use self::MethodPayload::*;
+    if !config.role.can_send(payload.sent_by()) {
+        return Err(unsupported_method(payload.class_id(), payload.method_id()));
+    }
     match payload {
-        Connection(class) => encode_connection_class(class),
-        Channel(class) => encode_channel_class(class),
-        Exchange(class) => encode_exchange_class(class),
-        Queue(class) => encode_queue_class(class),
-        Basic(class) => encode_basic_class(class),
-        Tx(class) => encode_tx_class(class),
+        Connection(class) => encode_connection_class(class, config),
+        Channel(class) => encode_channel_class(class, config),
+        Access(class) => encode_access_class(class, config),
+        Exchange(class) => encode_exchange_class(class, config),
+        Queue(class) => encode_queue_class(class, config),
+        Basic(class) => encode_basic_class(class, config),
+        Confirm(class) => encode_confirm_class(class, config),
+        Tx(class) => encode_tx_class(class, config),
+        Unknown {
+            class_id,
+            method_id,
+            arguments,
+        } => {
+            let mut buf = InnerEncoder::class_and_method_id(class_id, method_id).finish()?;
+            buf.extend_from_slice(arguments.as_ref());
+            Ok(buf)
+        }
     }
 }
 
 
+/// Builder of encoded method bytes.
+/// The first error found while encoding is kept and returned by `finish`.
 struct InnerEncoder {
     buf: Vec<u8>,
+    err: Option<EncodeErrorKind>,
 }
 
 
 // Encode Connection Class {{{
-fn encode_connection_class(class: ConnectionClass) -> Vec<u8> {
+fn encode_connection_class(
+    class: ConnectionClass,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     const CLASS_ID: u16 = 10;
     use self::ConnectionClass::*;
+    let method_id = class.method_id_in(config.protocol_version);
     match class {
+        Start(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 10)
+                .encode_octet(m.version_major)
+                .encode_octet(m.version_minor)
+                .encode_field_table(m.server_properties, config)
+                .encode_long_str(m.mechanisms)
+                .encode_long_str(m.locales)
+                .finish()
+        }
         StartOk(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 11)
-                .encode_field_table(m.client_properties)
+                .encode_field_table(m.client_properties, config)
                 .encode_short_str(m.mechanism)
                 .encode_long_str(m.response)
                 .encode_short_str(m.locale)
-                .vec()
+                .finish()
+        }
+        Secure(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 20)
+                .encode_long_str(m.challenge)
+                .finish()
         }
         SecureOk(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 21)
                 .encode_long_str(m.response)
-                .vec()
+                .finish()
+        }
+        Tune(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 30)
+                .encode_short(m.channel_max)
+                .encode_long(m.frame_max)
+                .encode_short(m.heartbeat)
+                .finish()
         }
         TuneOk(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 31)
                 .encode_short(m.channel_max)
                 .encode_long(m.frame_max)
                 .encode_short(m.heartbeat)
-                .vec()
+                .finish()
         }
         Open(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 40)
                 .encode_short_str(m.virtual_host)
                 .encode_short_str(m.reserved1)
-                .encode_bit_1(m.reserved2)
-                .vec()
+                .encode_bits(&[m.reserved2])
+                .finish()
+        }
+        OpenOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 41)
+                .encode_short_str(m.reserved1)
+                .finish()
         }
         Close(m) => {
-            InnerEncoder::class_and_method_id(CLASS_ID, 50)
+            InnerEncoder::class_and_method_id(CLASS_ID, method_id)
                 .encode_short(m.reply_code)
                 .encode_short_str(m.reply_text)
                 .encode_short(m.class_id)
                 .encode_short(m.method_id)
-                .vec()
+                .finish()
+        }
+        CloseOk => InnerEncoder::class_and_method_id(CLASS_ID, method_id).finish(),
+        Redirect(m) => {
+            if config.protocol_version == ProtocolVersion::Amqp0_9_1 {
+                return Err(unsupported_method(CLASS_ID, method_id));
+            }
+            InnerEncoder::class_and_method_id(CLASS_ID, method_id)
+                .encode_short_str(m.host)
+                .encode_short_str(m.known_hosts)
+                .finish()
+        }
+        // Their ids are used by Close / CloseOk in 0-8.
+        Blocked(_) | Unblocked if config.protocol_version == ProtocolVersion::Amqp0_8 => {
+            Err(unsupported_method(CLASS_ID, method_id))
+        }
+        Blocked(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 60)
+                .encode_short_str(m.reason)
+                .finish()
+        }
+        Unblocked => InnerEncoder::class_and_method_id(CLASS_ID, 61).finish(),
+        UpdateSecret(_) | UpdateSecretOk
+            if config.protocol_version != ProtocolVersion::Amqp0_9_1 => {
+            Err(unsupported_method(CLASS_ID, method_id))
+        }
+        UpdateSecret(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 70)
+                .encode_long_str(m.new_secret)
+                .encode_short_str(m.reason)
+                .finish()
         }
-        CloseOk => InnerEncoder::class_and_method_id(CLASS_ID, 51).vec(),
-        _ => unreachable!("Others methods are never be sent by client"),
+        UpdateSecretOk => InnerEncoder::class_and_method_id(CLASS_ID, 71).finish(),
     }
 }
 // }}}
 
 
 // Encode Channel Class  {{{
-fn encode_channel_class(class: ChannelClass) -> Vec<u8> {
+fn encode_channel_class(
+    class: ChannelClass,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     const CLASS_ID: u16 = 20;
     use self::ChannelClass::*;
+    let is_0_8 = config.protocol_version == ProtocolVersion::Amqp0_8;
     match class {
         Open(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 10)
                 .encode_short_str(m.reserved1)
-                .vec()
+                .finish()
+        }
+        OpenOk(_) if is_0_8 => InnerEncoder::class_and_method_id(CLASS_ID, 11).finish(),
+        OpenOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 11)
+                .encode_long_str(m.reserved1)
+                .finish()
         }
         Flow(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 20)
-                .encode_bit_1(m.active)
-                .vec()
+                .encode_bits(&[m.active])
+                .finish()
         }
         FlowOk(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 21)
-                .encode_bit_1(m.active)
-                .vec()
+                .encode_bits(&[m.active])
+                .finish()
         }
         Close(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 40)
@@ -98,74 +188,133 @@ fn encode_channel_class(class: ChannelClass) -> Vec<u8> {
                 .encode_short_str(m.reply_text)
                 .encode_short(m.class_id)
                 .encode_short(m.method_id)
-                .vec()
+                .finish()
+        }
+        CloseOk => InnerEncoder::class_and_method_id(CLASS_ID, 41).finish(),
+        Alert(m) => {
+            if !is_0_8 {
+                return Err(unsupported_method(CLASS_ID, 30));
+            }
+            InnerEncoder::class_and_method_id(CLASS_ID, 30)
+                .encode_short(m.reply_code)
+                .encode_short_str(m.reply_text)
+                .encode_field_table(m.details, config)
+                .finish()
+        }
+    }
+}
+// }}}
+
+
+// Encode Access Class {{{
+fn encode_access_class(
+    class: AccessClass,
+    _config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
+    const CLASS_ID: u16 = 30;
+    use self::AccessClass::*;
+    match class {
+        Request(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 10)
+                .encode_short_str(m.realm)
+                .encode_bits(&[m.exclusive, m.passive, m.active, m.write, m.read])
+                .finish()
+        }
+        RequestOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 11)
+                .encode_short(m.ticket)
+                .finish()
         }
-        CloseOk => InnerEncoder::class_and_method_id(CLASS_ID, 41).vec(),
-        _ => unreachable!("Others methods are never be sent by client"),
     }
 }
 // }}}
 
 
 // Encode Exchange Class {{{
-fn encode_exchange_class(class: ExchangeClass) -> Vec<u8> {
+fn encode_exchange_class(
+    class: ExchangeClass,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     const CLASS_ID: u16 = 40;
     use self::ExchangeClass::*;
+    let method_id = class.method_id();
+    let is_0_8 = config.protocol_version == ProtocolVersion::Amqp0_8;
     match class {
+        // rabbitmq-specific extension of 0-9-1
+        Bind(_) | BindOk | Unbind(_) | UnbindOk if is_0_8 => {
+            Err(unsupported_method(CLASS_ID, method_id))
+        }
         Declare(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 10)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.exchange)
                 .encode_short_str(m.typ)
-                .encode_bit_5(m.passive, m.durable, m.auto_delete, m.internal, m.no_wait)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_bits(&[m.passive, m.durable, m.auto_delete, m.internal, m.no_wait])
+                .encode_field_table(m.arguments, config)
+                .finish()
         }
+        DeclareOk => InnerEncoder::class_and_method_id(CLASS_ID, 11).finish(),
         Delete(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 20)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.exchange)
-                .encode_bit_2(m.if_unused, m.no_wait)
-                .vec()
+                .encode_bits(&[m.if_unused, m.no_wait])
+                .finish()
         }
+        DeleteOk => InnerEncoder::class_and_method_id(CLASS_ID, 21).finish(),
         Bind(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 30)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.destination)
                 .encode_short_str(m.source)
                 .encode_short_str(m.routing_key)
-                .encode_bit_1(m.no_wait)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_bits(&[m.no_wait])
+                .encode_field_table(m.arguments, config)
+                .finish()
         }
+        BindOk => InnerEncoder::class_and_method_id(CLASS_ID, 31).finish(),
         Unbind(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 40)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.destination)
                 .encode_short_str(m.source)
                 .encode_short_str(m.routing_key)
-                .encode_bit_1(m.no_wait)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_bits(&[m.no_wait])
+                .encode_field_table(m.arguments, config)
+                .finish()
         }
-        _ => unreachable!("Others methods are never be sent by client"),
+        UnbindOk => InnerEncoder::class_and_method_id(CLASS_ID, 51).finish(),
     }
 }
 // }}}
 
 
 // Encode Queue Class {{{
-fn encode_queue_class(class: QueueClass) -> Vec<u8> {
+fn encode_queue_class(
+    class: QueueClass,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     const CLASS_ID: u16 = 50;
     use self::QueueClass::*;
+    let method_id = class.method_id();
     match class {
+        Unbind(_) | UnbindOk if config.protocol_version == ProtocolVersion::Amqp0_8 => {
+            Err(unsupported_method(CLASS_ID, method_id))
+        }
         Declare(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 10)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.queue)
-                .encode_bit_5(m.passive, m.durable, m.exclusive, m.auto_delete, m.no_wait)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_bits(&[m.passive, m.durable, m.exclusive, m.auto_delete, m.no_wait])
+                .encode_field_table(m.arguments, config)
+                .finish()
+        }
+        DeclareOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 11)
+                .encode_short_str(m.queue)
+                .encode_long(m.message_count)
+                .encode_long(m.consumer_count)
+                .finish()
         }
         Bind(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 20)
@@ -173,41 +322,55 @@ fn encode_queue_class(class: QueueClass) -> Vec<u8> {
                 .encode_short_str(m.queue)
                 .encode_short_str(m.exchange)
                 .encode_short_str(m.routing_key)
-                .encode_bit_1(m.no_wait)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_bits(&[m.no_wait])
+                .encode_field_table(m.arguments, config)
+                .finish()
         }
+        BindOk => InnerEncoder::class_and_method_id(CLASS_ID, 21).finish(),
         Unbind(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 50)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.queue)
                 .encode_short_str(m.exchange)
                 .encode_short_str(m.routing_key)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_field_table(m.arguments, config)
+                .finish()
         }
+        UnbindOk => InnerEncoder::class_and_method_id(CLASS_ID, 51).finish(),
         Purge(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 30)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.queue)
-                .encode_bit_1(m.no_wait)
-                .vec()
+                .encode_bits(&[m.no_wait])
+                .finish()
+        }
+        PurgeOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 31)
+                .encode_long(m.message_count)
+                .finish()
         }
         Delete(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 40)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.queue)
-                .encode_bit_3(m.if_unused, m.if_empty, m.no_wait)
-                .vec()
+                .encode_bits(&[m.if_unused, m.if_empty, m.no_wait])
+                .finish()
+        }
+        DeleteOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 41)
+                .encode_long(m.message_count)
+                .finish()
         }
-        _ => unreachable!("Others methods are never be sent by client"),
     }
 }
 // }}}
 
 
 // Encode Basic Class {{{
-fn encode_basic_class(class: BasicClass) -> Vec<u8> {
+fn encode_basic_class(
+    class: BasicClass,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     const CLASS_ID: u16 = 60;
     use self::BasicClass::*;
     match class {
@@ -215,88 +378,171 @@ fn encode_basic_class(class: BasicClass) -> Vec<u8> {
             InnerEncoder::class_and_method_id(CLASS_ID, 10)
                 .encode_long(m.prefetch_size)
                 .encode_short(m.prefetch_count)
-                .encode_bit_1(m.global)
-                .vec()
+                .encode_bits(&[m.global])
+                .finish()
         }
+        QosOk => InnerEncoder::class_and_method_id(CLASS_ID, 11).finish(),
         Consume(m) => {
-            InnerEncoder::class_and_method_id(CLASS_ID, 20)
+            let encoder = InnerEncoder::class_and_method_id(CLASS_ID, 20)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.queue)
                 .encode_short_str(m.consumer_tag)
-                .encode_bit_4(m.no_local, m.no_ack, m.exclusive, m.no_wait)
-                .encode_field_table(m.arguments)
-                .vec()
+                .encode_bits(&[m.no_local, m.no_ack, m.exclusive, m.no_wait]);
+            match config.protocol_version {
+                // 0-8 has no arguments for Consume
+                ProtocolVersion::Amqp0_8 if m.arguments.is_empty() => encoder.finish(),
+                ProtocolVersion::Amqp0_8 => {
+                    Err(EncodeErrorKind::UnencodableField("consume arguments do not exist in 0-8"))
+                }
+                _ => encoder.encode_field_table(m.arguments, config).finish(),
+            }
+        }
+        ConsumeOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 21)
+                .encode_short_str(m.consumer_tag)
+                .finish()
         }
         Cancel(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 30)
                 .encode_short_str(m.consumer_tag)
-                .encode_bit_1(m.no_wait)
-                .vec()
+                .encode_bits(&[m.no_wait])
+                .finish()
+        }
+        CancelOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 31)
+                .encode_short_str(m.consumer_tag)
+                .finish()
         }
         Publish(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 40)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.exchange)
                 .encode_short_str(m.routing_key)
-                .encode_bit_2(m.mandatory, m.immediate)
-                .vec()
+                .encode_bits(&[m.mandatory, m.immediate])
+                .finish()
+        }
+        Return(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 50)
+                .encode_short(m.reply_code)
+                .encode_short_str(m.reply_text)
+                .encode_short_str(m.exchange)
+                .encode_short_str(m.routing_key)
+                .finish()
+        }
+        Deliver(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 60)
+                .encode_short_str(m.consumer_tag)
+                .encode_longlong(m.delivery_tag)
+                .encode_bits(&[m.redeliverd])
+                .encode_short_str(m.exchange)
+                .encode_short_str(m.routing_key)
+                .finish()
         }
         Get(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 70)
                 .encode_short(m.reserved1)
                 .encode_short_str(m.queue)
-                .encode_bit_1(m.no_ack)
-                .vec()
+                .encode_bits(&[m.no_ack])
+                .finish()
+        }
+        GetOk(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 71)
+                .encode_longlong(m.delivery_tag)
+                .encode_bits(&[m.redeliverd])
+                .encode_short_str(m.exchange)
+                .encode_short_str(m.routing_key)
+                .encode_long(m.message_count)
+                .finish()
+        }
+        GetEmpty(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 72)
+                .encode_short_str(m.reserved1)
+                .finish()
         }
         Ack(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 80)
                 .encode_longlong(m.delivery_tag)
-                .encode_bit_1(m.multiple)
-                .vec()
+                .encode_bits(&[m.multiple])
+                .finish()
         }
         Reject(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 90)
                 .encode_longlong(m.delivery_tag)
-                .encode_bit_1(m.requeue)
-                .vec()
+                .encode_bits(&[m.requeue])
+                .finish()
         }
         Nack(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 120)
                 .encode_longlong(m.delivery_tag)
-                .encode_bit_1(m.multiple)
-                .vec()
+                .encode_bits(&[m.multiple, m.requeue])
+                .finish()
         }
         RecoverAsync(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 100)
-                .encode_bit_1(m.requeue)
-                .vec()
+                .encode_bits(&[m.requeue])
+                .finish()
         }
         Recover(m) => {
             InnerEncoder::class_and_method_id(CLASS_ID, 110)
-                .encode_bit_1(m.requeue)
-                .vec()
+                .encode_bits(&[m.requeue])
+                .finish()
         }
-        _ => unreachable!("Others methods are never be sent by client"),
+        RecoverOk => InnerEncoder::class_and_method_id(CLASS_ID, 111).finish(),
+    }
+}
+// }}}
+
+
+// Encode Confirm Class {{{
+fn encode_confirm_class(
+    class: ConfirmClass,
+    config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
+    const CLASS_ID: u16 = 85;
+    use self::ConfirmClass::*;
+    if config.protocol_version == ProtocolVersion::Amqp0_8 {
+        return Err(unsupported_method(CLASS_ID, class.method_id()));
+    }
+    match class {
+        Select(m) => {
+            InnerEncoder::class_and_method_id(CLASS_ID, 10)
+                .encode_bits(&[m.no_wait])
+                .finish()
+        }
+        SelectOk => InnerEncoder::class_and_method_id(CLASS_ID, 11).finish(),
     }
 }
 // }}}
 
 
 // Encode Tx Class {{{
-fn encode_tx_class(class: TxClass) -> Vec<u8> {
+fn encode_tx_class(
+    class: TxClass,
+    _config: &Config,
+) -> Result<Vec<u8>, EncodeErrorKind> {
     const CLASS_ID: u16 = 90;
     use self::TxClass::*;
     match class {
-        Select => InnerEncoder::class_and_method_id(CLASS_ID, 10).vec(),
-        Commit => InnerEncoder::class_and_method_id(CLASS_ID, 20).vec(),
-        Rollback => InnerEncoder::class_and_method_id(CLASS_ID, 30).vec(),
-        _ => unreachable!("Others methods are never be sent by client"),
+        Select => InnerEncoder::class_and_method_id(CLASS_ID, 10).finish(),
+        SelectOk => InnerEncoder::class_and_method_id(CLASS_ID, 11).finish(),
+        Commit => InnerEncoder::class_and_method_id(CLASS_ID, 20).finish(),
+        CommitOk => InnerEncoder::class_and_method_id(CLASS_ID, 21).finish(),
+        Rollback => InnerEncoder::class_and_method_id(CLASS_ID, 30).finish(),
+        RollbackOk => InnerEncoder::class_and_method_id(CLASS_ID, 31).finish(),
     }
 }
 // }}}
 
 
 
+fn unsupported_method(class_id: u16, method_id: u16) -> EncodeErrorKind {
+    EncodeErrorKind::UnsupportedMethod {
+        class_id: class_id,
+        method_id: method_id,
+    }
+}
+
+
 // impl InnerEncoder {{{
 impl InnerEncoder {
     fn class_and_method_id(class_id: u16, method_id: u16) -> InnerEncoder {
@@ -306,11 +552,12 @@ impl InnerEncoder {
         buf.put_u16::<BigEndian>(class_id);
         buf.put_u16::<BigEndian>(method_id);
 
-        InnerEncoder { buf: buf }
+        InnerEncoder {
+            buf: buf,
+            err: None,
+        }
     }
 
-    // For now, any encoded method does not have octet field
-    #[allow(dead_code)]
     fn encode_octet(mut self, octet: u8) -> InnerEncoder {
         self.buf.put_u8(octet);
         self
@@ -331,169 +578,173 @@ impl InnerEncoder {
         self
     }
 
-    fn encode_bit_1(self, bit: bool) -> InnerEncoder {
-        self.encode_bit_5(bit, false, false, false, false)
-    }
-
-    fn encode_bit_2(self, bit1: bool, bit2: bool) -> InnerEncoder {
-        self.encode_bit_5(bit1, bit2, false, false, false)
+    /// Encode consecutive bit fields. Do not split consecutive bits into several calls,
+    /// otherwise they are not packed into the same octet.
+    fn encode_bits(mut self, bits: &[bool]) -> InnerEncoder {
+        pack_bits(bits, &mut self.buf);
+        self
     }
 
-    fn encode_bit_3(self, bit1: bool, bit2: bool, bit3: bool) -> InnerEncoder {
-        self.encode_bit_5(bit1, bit2, bit3, false, false)
+    fn encode_short_str(mut self, string: AmqpString) -> InnerEncoder {
+        if let Err(e) = encode_short_str_0(&string, &mut self.buf) {
+            self.err = self.err.or(Some(e));
+        }
+        self
     }
 
-    fn encode_bit_4(self, bit1: bool, bit2: bool, bit3: bool, bit4: bool) -> InnerEncoder {
-        self.encode_bit_5(bit1, bit2, bit3, bit4, false)
+    fn encode_long_str(mut self, string: AmqpString) -> InnerEncoder {
+        if let Err(e) = encode_long_str_0(&string, &mut self.buf) {
+            self.err = self.err.or(Some(e));
+        }
+        self
     }
 
-    fn encode_bit_5(
+    fn encode_field_table(
         mut self,
-        bit1: bool,
-        bit2: bool,
-        bit3: bool,
-        bit4: bool,
-        bit5: bool,
+        table: HashMap<AmqpString, FieldArgument>,
+        config: &Config,
     ) -> InnerEncoder {
-        let byte = (bit1 as u8 * 0b_0000_0001) + (bit2 as u8 * 0b_0000_0010) +
-            (bit3 as u8 * 0b_0000_0100) + (bit4 as u8 * 0b_0000_1000) +
-            (bit5 as u8 * 0b_0001_0000);
-        self.buf.put_u8(byte);
+        if let Err(e) = encode_field_table_0(&table, &mut self.buf, config) {
+            self.err = self.err.or(Some(e));
+        }
         self
     }
 
-    fn encode_short_str(mut self, string: AmqpString) -> InnerEncoder {
-        self.buf.put_u8(string.len() as u8);
-        self.buf.put(string.as_bytes());
-        self
+    fn finish(self) -> Result<Vec<u8>, EncodeErrorKind> {
+        match self.err {
+            Some(e) => Err(e),
+            None => Ok(self.buf),
+        }
     }
+}
+// }}}
 
-    fn encode_long_str(mut self, string: AmqpString) -> InnerEncoder {
-        self.buf.put_u32::<BigEndian>(string.len() as u32);
-        self.buf.put(string.as_bytes());
-        self
-    }
 
-    fn encode_field_table(mut self, table: HashMap<AmqpString, FieldArgument>) -> InnerEncoder {
-        encode_field_table_0(&table, &mut self.buf);
-        self
+// Encode strings, field-table and field-array {{{
+pub(crate) fn encode_short_str_0(
+    string: &AmqpString,
+    dst: &mut Vec<u8>,
+) -> Result<(), EncodeErrorKind> {
+    let bytes = string.as_bytes();
+    if bytes.len() > u8::max_value() as usize {
+        return Err(EncodeErrorKind::ShortStringTooLong(bytes.len()));
     }
+    dst.put_u8(bytes.len() as u8);
+    dst.put(bytes);
+    Ok(())
+}
+
 
-    fn vec(self) -> Vec<u8> {
-        self.buf
+pub(crate) fn encode_long_str_0(
+    string: &AmqpString,
+    dst: &mut Vec<u8>,
+) -> Result<(), EncodeErrorKind> {
+    let bytes = string.as_bytes();
+    if bytes.len() > u32::max_value() as usize {
+        return Err(EncodeErrorKind::LongStringTooLong(bytes.len()));
     }
+    dst.put_u32::<BigEndian>(bytes.len() as u32);
+    dst.put(bytes);
+    Ok(())
 }
-// }}}
 
 
-// Encode field-table and field-array {{{
-pub(crate) fn encode_field_table_0(table: &HashMap<AmqpString, FieldArgument>, dst: &mut Vec<u8>) {
+pub(crate) fn encode_field_table_0(
+    table: &HashMap<AmqpString, FieldArgument>,
+    dst: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), EncodeErrorKind> {
     let mut bytes = {
         let mut buf = Vec::new();
         for (item_name, item_value) in table.iter() {
-            buf.put_u8(item_name.len() as u8);
-            buf.put_slice(item_name.as_bytes());
-            encode_field_item(item_value, &mut buf);
+            encode_short_str_0(item_name, &mut buf)?;
+            encode_field_item(item_value, &mut buf, config)?;
         }
         buf
     };
 
+    if bytes.len() > u32::max_value() as usize {
+        return Err(EncodeErrorKind::UnencodableField("field table is too long"));
+    }
     dst.put_u32::<BigEndian>(bytes.len() as u32);
     dst.append(&mut bytes);
+    Ok(())
 }
 
 
-/*
-fn encode_field_array(array: &FieldArray, dst: &mut Vec<u8>) {
+fn encode_field_array_0(
+    array: &[FieldArgument],
+    dst: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), EncodeErrorKind> {
     let mut bytes = {
         let mut buf = Vec::new();
         for item in array.iter() {
-            encode_field_item(item, &mut buf);
+            encode_field_item(item, &mut buf, config)?;
         }
         buf
     };
 
+    if bytes.len() > u32::max_value() as usize {
+        return Err(EncodeErrorKind::UnencodableField("field array is too long"));
+    }
     dst.put_u32::<BigEndian>(bytes.len() as u32);
     dst.append(&mut bytes);
+    Ok(())
 }
-*/
 
 
-fn encode_field_item(item: &FieldArgument, dst: &mut Vec<u8>) {
-    match item {
-        &FieldArgument::Boolean(b) => {
-            dst.put_u8(b't');
-            dst.put_u8(b as u8);
-        }
-        &FieldArgument::SignedOctet(byte) => {
-            dst.put_u8(b'b');
-            dst.put_i8(byte);
-        }
-        &FieldArgument::UnsignedOctet(byte) => {
-            dst.put_u8(b'B');
-            dst.put_u8(byte);
-        }
-        &FieldArgument::SignedShort(short) => {
-            dst.put_u8(b'U');
-            dst.put_i16::<BigEndian>(short);
-        }
-        &FieldArgument::UnsignedShort(short) => {
-            dst.put_u8(b'u');
-            dst.put_u16::<BigEndian>(short);
-        }
-        &FieldArgument::SignedLong(long) => {
-            dst.put_u8(b'I');
-            dst.put_i32::<BigEndian>(long);
-        }
-        &FieldArgument::UnsignedLong(long) => {
-            dst.put_u8(b'i');
-            dst.put_u32::<BigEndian>(long);
-        }
-        &FieldArgument::SignedLongLong(longlong) => {
-            dst.put_u8(b'L');
-            dst.put_i64::<BigEndian>(longlong);
-        }
-        &FieldArgument::UnsignedLongLong(longlong) => {
-            dst.put_u8(b'l');
-            dst.put_u64::<BigEndian>(longlong);
-        }
-        &FieldArgument::Float(float) => {
-            dst.put_u8(b'f');
-            dst.put_f32::<BigEndian>(float);
-        }
-        &FieldArgument::Double(double) => {
-            dst.put_u8(b'd');
-            dst.put_f64::<BigEndian>(double);
+/// Encode a field value with the tag of `config.field_dialect`.
+/// If the dialect does not support the value, it is widened (e.g. unsigned short into
+/// signed long) so that the peer reads the same value.
+fn encode_field_item(
+    item: &FieldArgument,
+    dst: &mut Vec<u8>,
+    config: &Config,
+) -> Result<(), EncodeErrorKind> {
+    let tag = match tag_of(config.field_dialect, FieldKind::of(item)) {
+        Some(tag) => tag,
+        None => {
+            return match widen(item) {
+                Some(widened) => encode_field_item(&widened, dst, config),
+                None => Err(EncodeErrorKind::UnencodableField(
+                    "value is not supported by the field dialect",
+                )),
+            };
         }
+    };
+    dst.put_u8(tag);
+
+    match item {
+        &FieldArgument::Boolean(b) => dst.put_u8(b as u8),
+        &FieldArgument::SignedOctet(byte) => dst.put_i8(byte),
+        &FieldArgument::UnsignedOctet(byte) => dst.put_u8(byte),
+        &FieldArgument::SignedShort(short) => dst.put_i16::<BigEndian>(short),
+        &FieldArgument::UnsignedShort(short) => dst.put_u16::<BigEndian>(short),
+        &FieldArgument::SignedLong(long) => dst.put_i32::<BigEndian>(long),
+        &FieldArgument::UnsignedLong(long) => dst.put_u32::<BigEndian>(long),
+        &FieldArgument::SignedLongLong(longlong) => dst.put_i64::<BigEndian>(longlong),
+        &FieldArgument::UnsignedLongLong(longlong) => dst.put_u64::<BigEndian>(longlong),
+        &FieldArgument::Float(float) => dst.put_f32::<BigEndian>(float),
+        &FieldArgument::Double(double) => dst.put_f64::<BigEndian>(double),
         &FieldArgument::Decimal(decimal) => {
-            dst.put_u8(b'D');
-            dst.put_i64::<BigEndian>(decimal);
-        }
-        &FieldArgument::ShortString(ref s) => {
-            dst.put_u8(b's');
-            dst.put_u8(s.len() as u8);
-            dst.put(s.as_bytes());
-        }
-        &FieldArgument::LongString(ref s) => {
-            dst.put_u8(b'S');
-            dst.put_u32::<BigEndian>(s.len() as u32);
-            dst.put(s.as_bytes());
-        }
-        &FieldArgument::Timestamp(ts) => {
-            dst.put_u8(b'T');
-            dst.put_u64::<BigEndian>(ts);
-        }
-        &FieldArgument::NestedTable(ref table) => {
-            dst.put_u8(b'F');
-            encode_field_table_0(table, dst);
-        }
-        &FieldArgument::Void => {
-            dst.put_u8(b'V');
-        }
-        &FieldArgument::ByteArray(ref _array) => {
-            dst.put_u8(b'x');
-            panic!("Fail to parse ByteArray") // I don't know how should I treat it
+            dst.put_u8(decimal.scale);
+            dst.put_i32::<BigEndian>(decimal.value);
+        }
+        &FieldArgument::ShortString(ref s) => encode_short_str_0(s, dst)?,
+        &FieldArgument::LongString(ref s) => encode_long_str_0(s, dst)?,
+        &FieldArgument::Array(ref array) => encode_field_array_0(array, dst, config)?,
+        &FieldArgument::Timestamp(ts) => dst.put_u64::<BigEndian>(ts),
+        &FieldArgument::NestedTable(ref table) => encode_field_table_0(table, dst, config)?,
+        &FieldArgument::Void => {}
+        &FieldArgument::ByteArray(ref array) => {
+            if array.len() > u32::max_value() as usize {
+                return Err(EncodeErrorKind::UnencodableField("byte array is too long"));
+            }
+            dst.put_u32::<BigEndian>(array.len() as u32);
+            dst.put_slice(array);
         }
     }
+    Ok(())
 }
 // }}}
@@ -0,0 +1,204 @@
+//! Type tags of field values in each `FieldDialect`.
+//!
+//! The spec and brokers disagree on some tags (see
+//! https://www.rabbitmq.com/amqp-0-9-1-errata.html#section_3). Most importantly 's' is a
+//! short string in the spec but a signed 16-bit integer in RabbitMQ and Qpid, and 'l' is an
+//! unsigned 64-bit integer in the spec but a signed one in RabbitMQ and Qpid.
+//!
+//! A tag which is not in the table of a dialect is rejected while decoding. A value whose kind
+//! is not in the table is widened into a kind which can hold it while encoding.
+
+use args::FieldArgument;
+use config::FieldDialect;
+
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub(crate) enum FieldKind {
+    Boolean,
+    SignedOctet,
+    UnsignedOctet,
+    SignedShort,
+    UnsignedShort,
+    SignedLong,
+    UnsignedLong,
+    SignedLongLong,
+    UnsignedLongLong,
+    Float,
+    Double,
+    Decimal,
+    ShortString,
+    LongString,
+    Array,
+    Timestamp,
+    Table,
+    Void,
+    ByteArray,
+}
+
+
+use self::FieldKind::*;
+
+const SPEC_0_9_1: &'static [(u8, FieldKind)] = &[
+    (b't', Boolean),
+    (b'b', SignedOctet),
+    (b'B', UnsignedOctet),
+    (b'U', SignedShort),
+    (b'u', UnsignedShort),
+    (b'I', SignedLong),
+    (b'i', UnsignedLong),
+    (b'L', SignedLongLong),
+    (b'l', UnsignedLongLong),
+    (b'f', Float),
+    (b'd', Double),
+    (b'D', Decimal),
+    (b's', ShortString),
+    (b'S', LongString),
+    (b'A', Array),
+    (b'T', Timestamp),
+    (b'F', Table),
+    (b'V', Void),
+    // Not in the spec, but widely used and never conflicts.
+    (b'x', ByteArray),
+];
+
+const RABBITMQ: &'static [(u8, FieldKind)] = &[
+    (b't', Boolean),
+    (b'b', SignedOctet),
+    (b'B', UnsignedOctet),
+    (b's', SignedShort),
+    (b'I', SignedLong),
+    (b'l', SignedLongLong),
+    (b'f', Float),
+    (b'd', Double),
+    (b'D', Decimal),
+    (b'S', LongString),
+    (b'A', Array),
+    (b'T', Timestamp),
+    (b'F', Table),
+    (b'V', Void),
+    (b'x', ByteArray),
+];
+
+const QPID: &'static [(u8, FieldKind)] = &[
+    (b't', Boolean),
+    (b'b', SignedOctet),
+    (b's', SignedShort),
+    (b'I', SignedLong),
+    (b'i', UnsignedLong),
+    (b'l', SignedLongLong),
+    (b'f', Float),
+    (b'd', Double),
+    (b'D', Decimal),
+    (b'S', LongString),
+    (b'A', Array),
+    (b'T', Timestamp),
+    (b'F', Table),
+    (b'V', Void),
+    (b'x', ByteArray),
+];
+
+
+fn tag_table(dialect: FieldDialect) -> &'static [(u8, FieldKind)] {
+    match dialect {
+        FieldDialect::Spec0_9_1 => SPEC_0_9_1,
+        FieldDialect::RabbitMQ => RABBITMQ,
+        FieldDialect::Qpid => QPID,
+    }
+}
+
+
+/// Kind of value which `tag` means in `dialect`.
+pub(crate) fn kind_of(dialect: FieldDialect, tag: u8) -> Option<FieldKind> {
+    tag_table(dialect).iter().find(|&&(t, _)| t == tag).map(|&(_, k)| k)
+}
+
+
+/// Tag which `dialect` uses for `kind`.
+pub(crate) fn tag_of(dialect: FieldDialect, kind: FieldKind) -> Option<u8> {
+    tag_table(dialect).iter().find(|&&(_, k)| k == kind).map(|&(t, _)| t)
+}
+
+
+impl FieldKind {
+    pub(crate) fn of(value: &FieldArgument) -> FieldKind {
+        match value {
+            &FieldArgument::Boolean(_) => Boolean,
+            &FieldArgument::SignedOctet(_) => SignedOctet,
+            &FieldArgument::UnsignedOctet(_) => UnsignedOctet,
+            &FieldArgument::SignedShort(_) => SignedShort,
+            &FieldArgument::UnsignedShort(_) => UnsignedShort,
+            &FieldArgument::SignedLong(_) => SignedLong,
+            &FieldArgument::UnsignedLong(_) => UnsignedLong,
+            &FieldArgument::SignedLongLong(_) => SignedLongLong,
+            &FieldArgument::UnsignedLongLong(_) => UnsignedLongLong,
+            &FieldArgument::Float(_) => Float,
+            &FieldArgument::Double(_) => Double,
+            &FieldArgument::Decimal(_) => Decimal,
+            &FieldArgument::ShortString(_) => ShortString,
+            &FieldArgument::LongString(_) => LongString,
+            &FieldArgument::Array(_) => Array,
+            &FieldArgument::Timestamp(_) => Timestamp,
+            &FieldArgument::NestedTable(_) => Table,
+            &FieldArgument::Void => Void,
+            &FieldArgument::ByteArray(_) => ByteArray,
+        }
+    }
+}
+
+
+/// Convert `value` into a wider kind of value without losing its meaning.
+/// Returns `None` if there is no such kind.
+pub(crate) fn widen(value: &FieldArgument) -> Option<FieldArgument> {
+    match value {
+        &FieldArgument::UnsignedOctet(v) => Some(FieldArgument::SignedShort(v as i16)),
+        &FieldArgument::UnsignedShort(v) => Some(FieldArgument::SignedLong(v as i32)),
+        &FieldArgument::UnsignedLong(v) => Some(FieldArgument::SignedLongLong(v as i64)),
+        &FieldArgument::UnsignedLongLong(v) if v <= i64::max_value() as u64 => {
+            Some(FieldArgument::SignedLongLong(v as i64))
+        }
+        &FieldArgument::ShortString(ref s) => Some(FieldArgument::LongString(s.clone())),
+        _ => None,
+    }
+}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_dialect_can_encode_every_kind() {
+        use args::AmqpString;
+
+        let values = vec![
+            FieldArgument::UnsignedOctet(1),
+            FieldArgument::UnsignedShort(1),
+            FieldArgument::UnsignedLong(1),
+            FieldArgument::UnsignedLongLong(1),
+            FieldArgument::ShortString(AmqpString::from("a")),
+        ];
+        for &dialect in [FieldDialect::Spec0_9_1, FieldDialect::RabbitMQ, FieldDialect::Qpid]
+            .iter()
+        {
+            for value in values.iter() {
+                let mut v = value.clone();
+                while tag_of(dialect, FieldKind::of(&v)).is_none() {
+                    v = widen(&v).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tags_are_unique_in_each_dialect() {
+        for table in [SPEC_0_9_1, RABBITMQ, QPID].iter() {
+            for &(tag, kind) in table.iter() {
+                assert_eq!(table.iter().filter(|&&(t, _)| t == tag).count(), 1);
+                assert_eq!(table.iter().filter(|&&(_, k)| k == kind).count(), 1);
+            }
+        }
+    }
+}
+// }}}
@@ -13,16 +13,23 @@
 
 pub mod decoder;
 pub mod encoder;
+mod bits;
+mod field_tag;
 
 use std::collections::HashMap;
 
+use bytes::Bytes;
+
 use args::{FieldArgument, AmqpString};
+use config::{ProtocolVersion, Role};
 
 pub use self::connection::ConnectionClass;
 pub use self::channel::ChannelClass;
+pub use self::access::AccessClass;
 pub use self::exchange::ExchangeClass;
 pub use self::queue::QueueClass;
 pub use self::basic::BasicClass;
+pub use self::confirm::ConfirmClass;
 pub use self::tx::TxClass;
 
 
@@ -30,15 +37,68 @@ pub use self::tx::TxClass;
 pub enum MethodPayload {
     Connection(ConnectionClass),
     Channel(ChannelClass),
+    Access(AccessClass),
     Exchange(ExchangeClass),
     Queue(QueueClass),
     Basic(BasicClass),
+    Confirm(ConfirmClass),
     Tx(TxClass),
+
+    /// Method which this crate does not know. `arguments` holds raw bytes after method id.
+    /// This is decoded only if `Config::passthrough_unknown` is set.
+    Unknown {
+        class_id: u16,
+        method_id: u16,
+        arguments: Bytes,
+    },
 }
 
 
 // Implementation of MethodPayload {{{
 impl MethodPayload {
+    pub fn class_id(&self) -> u16 {
+        match self {
+            &MethodPayload::Connection(_) => 10,
+            &MethodPayload::Channel(_) => 20,
+            &MethodPayload::Access(_) => 30,
+            &MethodPayload::Exchange(_) => 40,
+            &MethodPayload::Queue(_) => 50,
+            &MethodPayload::Basic(_) => 60,
+            &MethodPayload::Confirm(_) => 85,
+            &MethodPayload::Tx(_) => 90,
+            &MethodPayload::Unknown { class_id, .. } => class_id,
+        }
+    }
+
+    pub fn method_id(&self) -> u16 {
+        match self {
+            &MethodPayload::Connection(ref c) => c.method_id(),
+            &MethodPayload::Channel(ref c) => c.method_id(),
+            &MethodPayload::Access(ref c) => c.method_id(),
+            &MethodPayload::Exchange(ref c) => c.method_id(),
+            &MethodPayload::Queue(ref c) => c.method_id(),
+            &MethodPayload::Basic(ref c) => c.method_id(),
+            &MethodPayload::Confirm(ref c) => c.method_id(),
+            &MethodPayload::Tx(ref c) => c.method_id(),
+            &MethodPayload::Unknown { method_id, .. } => method_id,
+        }
+    }
+
+    /// Which side sends this method. Unknown methods can be sent by both sides.
+    pub fn sent_by(&self) -> Role {
+        match self {
+            &MethodPayload::Connection(ref c) => c.sent_by(),
+            &MethodPayload::Channel(ref c) => c.sent_by(),
+            &MethodPayload::Access(ref c) => c.sent_by(),
+            &MethodPayload::Exchange(ref c) => c.sent_by(),
+            &MethodPayload::Queue(ref c) => c.sent_by(),
+            &MethodPayload::Basic(ref c) => c.sent_by(),
+            &MethodPayload::Confirm(ref c) => c.sent_by(),
+            &MethodPayload::Tx(ref c) => c.sent_by(),
+            &MethodPayload::Unknown { .. } => Role::Both,
+        }
+    }
+
     pub fn connection(&self) -> Option<&ConnectionClass> {
         match self {
             &MethodPayload::Connection(ref c) => Some(c),
@@ -53,6 +113,13 @@ impl MethodPayload {
         }
     }
 
+    pub fn access(&self) -> Option<&AccessClass> {
+        match self {
+            &MethodPayload::Access(ref c) => Some(c),
+            _ => None,
+        }
+    }
+
     pub fn exchange(&self) -> Option<&ExchangeClass> {
         match self {
             &MethodPayload::Exchange(ref c) => Some(c),
@@ -74,6 +141,13 @@ impl MethodPayload {
         }
     }
 
+    pub fn confirm(&self) -> Option<&ConfirmClass> {
+        match self {
+            &MethodPayload::Confirm(ref c) => Some(c),
+            _ => None,
+        }
+    }
+
     pub fn tx(&self) -> Option<&TxClass> {
         match self {
             &MethodPayload::Tx(ref c) => Some(c),
@@ -102,13 +176,69 @@ pub mod connection {
         Close(CloseMethod),
         CloseOk,
 
+        // Only in 0-8 and 0-9
+        Redirect(RedirectMethod),
+
         // These are rabbitmq-specific extensions
         Blocked(BlockedMethod),
         Unblocked,
+        UpdateSecret(UpdateSecretMethod),
+        UpdateSecretOk,
     }
 
     // Implementation of ConnectionClass {{{
     impl ConnectionClass {
+        /// Method id in 0-9-1 (`Redirect` is 42 as in 0-9).
+        /// Use `method_id_in` to get the id in other versions.
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &ConnectionClass::Start(_) => 10,
+                &ConnectionClass::StartOk(_) => 11,
+                &ConnectionClass::Secure(_) => 20,
+                &ConnectionClass::SecureOk(_) => 21,
+                &ConnectionClass::Tune(_) => 30,
+                &ConnectionClass::TuneOk(_) => 31,
+                &ConnectionClass::Open(_) => 40,
+                &ConnectionClass::OpenOk(_) => 41,
+                &ConnectionClass::Close(_) => 50,
+                &ConnectionClass::CloseOk => 51,
+                &ConnectionClass::Redirect(_) => 42,
+                &ConnectionClass::Blocked(_) => 60,
+                &ConnectionClass::Unblocked => 61,
+                &ConnectionClass::UpdateSecret(_) => 70,
+                &ConnectionClass::UpdateSecretOk => 71,
+            }
+        }
+
+        pub fn method_id_in(&self, version: ProtocolVersion) -> u16 {
+            match (self, version) {
+                (&ConnectionClass::Close(_), ProtocolVersion::Amqp0_8) => 60,
+                (&ConnectionClass::CloseOk, ProtocolVersion::Amqp0_8) => 61,
+                (&ConnectionClass::Redirect(_), ProtocolVersion::Amqp0_8) => 50,
+                _ => self.method_id(),
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &ConnectionClass::Start(_) |
+                &ConnectionClass::Secure(_) |
+                &ConnectionClass::Tune(_) |
+                &ConnectionClass::OpenOk(_) |
+                &ConnectionClass::Redirect(_) |
+                &ConnectionClass::UpdateSecretOk => Role::Server,
+                &ConnectionClass::StartOk(_) |
+                &ConnectionClass::SecureOk(_) |
+                &ConnectionClass::TuneOk(_) |
+                &ConnectionClass::Open(_) |
+                &ConnectionClass::UpdateSecret(_) => Role::Client,
+                &ConnectionClass::Close(_) |
+                &ConnectionClass::CloseOk |
+                &ConnectionClass::Blocked(_) |
+                &ConnectionClass::Unblocked => Role::Both,
+            }
+        }
+
         pub fn start(&self) -> Option<&StartMethod> {
             match self {
                 &ConnectionClass::Start(ref m) => Some(m),
@@ -179,6 +309,13 @@ pub mod connection {
             }
         }
 
+        pub fn redirect(&self) -> Option<&RedirectMethod> {
+            match self {
+                &ConnectionClass::Redirect(ref m) => Some(m),
+                _ => None,
+            }
+        }
+
         pub fn blocked(&self) -> Option<&BlockedMethod> {
             match self {
                 &ConnectionClass::Blocked(ref m) => Some(m),
@@ -192,6 +329,20 @@ pub mod connection {
                 _ => None,
             }
         }
+
+        pub fn update_secret(&self) -> Option<&UpdateSecretMethod> {
+            match self {
+                &ConnectionClass::UpdateSecret(ref m) => Some(m),
+                _ => None,
+            }
+        }
+
+        pub fn update_secret_ok(&self) -> Option<()> {
+            match self {
+                &ConnectionClass::UpdateSecretOk => Some(()),
+                _ => None,
+            }
+        }
     }
     // }}}
 
@@ -236,13 +387,28 @@ pub mod connection {
         pub heartbeat: u16,
     }
 
+    /// Reserved fields are "capabilities" and "insist" in 0-8 and 0-9.
     #[derive(PartialEq, Clone, Debug)]
     pub struct OpenMethod {
         pub virtual_host: AmqpString,
+        /// "capabilities" in 0-8 and 0-9.
         pub reserved1: AmqpString,
+        /// "insist" in 0-8 and 0-9.
         pub reserved2: bool,
     }
 
+    impl OpenMethod {
+        /// Capabilities required by the client (0-8 and 0-9).
+        pub fn capabilities(&self) -> &AmqpString {
+            &self.reserved1
+        }
+
+        /// Whether the client refuses to be redirected (0-8 and 0-9).
+        pub fn insist(&self) -> bool {
+            self.reserved2
+        }
+    }
+
     #[derive(PartialEq, Clone, Debug)]
     pub struct OpenOkMethod {
         pub reserved1: AmqpString,
@@ -256,10 +422,23 @@ pub mod connection {
         pub method_id: u16,
     }
 
+    #[derive(PartialEq, Clone, Debug)]
+    pub struct RedirectMethod {
+        pub host: AmqpString,
+        pub known_hosts: AmqpString,
+    }
+
     #[derive(PartialEq, Clone, Debug)]
     pub struct BlockedMethod {
         pub reason: AmqpString,
     }
+
+    /// Sent by client to refresh its credential (e.g. OAuth2 token) without reconnecting.
+    #[derive(PartialEq, Clone, Debug)]
+    pub struct UpdateSecretMethod {
+        pub new_secret: AmqpString,
+        pub reason: AmqpString,
+    }
 }
 // }}}
 
@@ -276,10 +455,36 @@ pub mod channel {
         FlowOk(FlowOkMethod),
         Close(CloseMethod),
         CloseOk,
+
+        // Only in 0-8
+        Alert(AlertMethod),
     }
 
     // Implementation of ChannelClass {{{
     impl ChannelClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &ChannelClass::Open(_) => 10,
+                &ChannelClass::OpenOk(_) => 11,
+                &ChannelClass::Flow(_) => 20,
+                &ChannelClass::FlowOk(_) => 21,
+                &ChannelClass::Close(_) => 40,
+                &ChannelClass::CloseOk => 41,
+                &ChannelClass::Alert(_) => 30,
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &ChannelClass::Open(_) => Role::Client,
+                &ChannelClass::OpenOk(_) | &ChannelClass::Alert(_) => Role::Server,
+                &ChannelClass::Flow(_) |
+                &ChannelClass::FlowOk(_) |
+                &ChannelClass::Close(_) |
+                &ChannelClass::CloseOk => Role::Both,
+            }
+        }
+
         pub fn open(&self) -> Option<&OpenMethod> {
             match self {
                 &ChannelClass::Open(ref m) => Some(m),
@@ -321,6 +526,13 @@ pub mod channel {
                 _ => None,
             }
         }
+
+        pub fn alert(&self) -> Option<&AlertMethod> {
+            match self {
+                &ChannelClass::Alert(ref m) => Some(m),
+                _ => None,
+            }
+        }
     }
     // }}}
 
@@ -329,6 +541,8 @@ pub mod channel {
         pub reserved1: AmqpString,
     }
 
+    /// `reserved1` does not exist in 0-8, so it is empty when decoded from 0-8 and
+    /// is not encoded into 0-8.
     #[derive(PartialEq, Clone, Debug)]
     pub struct OpenOkMethod {
         pub reserved1: AmqpString,
@@ -351,6 +565,74 @@ pub mod channel {
         pub class_id: u16,
         pub method_id: u16,
     }
+
+    #[derive(PartialEq, Clone, Debug)]
+    pub struct AlertMethod {
+        pub reply_code: u16,
+        pub reply_text: AmqpString,
+        pub details: HashMap<AmqpString, FieldArgument>,
+    }
+}
+// }}}
+
+
+// Access module {{{
+/// Access class is removed in 0-9-1, but some old brokers still require it.
+pub mod access {
+    use super::*;
+
+    #[derive(PartialEq, Clone, Debug)]
+    pub enum AccessClass {
+        Request(RequestMethod),
+        RequestOk(RequestOkMethod),
+    }
+
+    // Implementation of AccessClass {{{
+    impl AccessClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &AccessClass::Request(_) => 10,
+                &AccessClass::RequestOk(_) => 11,
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &AccessClass::Request(_) => Role::Client,
+                &AccessClass::RequestOk(_) => Role::Server,
+            }
+        }
+
+        pub fn request(&self) -> Option<&RequestMethod> {
+            match self {
+                &AccessClass::Request(ref m) => Some(m),
+                _ => None,
+            }
+        }
+
+        pub fn request_ok(&self) -> Option<&RequestOkMethod> {
+            match self {
+                &AccessClass::RequestOk(ref m) => Some(m),
+                _ => None,
+            }
+        }
+    }
+    // }}}
+
+    #[derive(PartialEq, Clone, Debug)]
+    pub struct RequestMethod {
+        pub realm: AmqpString,
+        pub exclusive: bool,
+        pub passive: bool,
+        pub active: bool,
+        pub write: bool,
+        pub read: bool,
+    }
+
+    #[derive(PartialEq, Clone, Debug)]
+    pub struct RequestOkMethod {
+        pub ticket: u16,
+    }
 }
 // }}}
 
@@ -373,6 +655,32 @@ pub mod exchange {
 
     // Implementation of ExchangeClass {{{
     impl ExchangeClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &ExchangeClass::Declare(_) => 10,
+                &ExchangeClass::DeclareOk => 11,
+                &ExchangeClass::Delete(_) => 20,
+                &ExchangeClass::DeleteOk => 21,
+                &ExchangeClass::Bind(_) => 30,
+                &ExchangeClass::BindOk => 31,
+                &ExchangeClass::Unbind(_) => 40,
+                &ExchangeClass::UnbindOk => 51,
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &ExchangeClass::Declare(_) |
+                &ExchangeClass::Delete(_) |
+                &ExchangeClass::Bind(_) |
+                &ExchangeClass::Unbind(_) => Role::Client,
+                &ExchangeClass::DeclareOk |
+                &ExchangeClass::DeleteOk |
+                &ExchangeClass::BindOk |
+                &ExchangeClass::UnbindOk => Role::Server,
+            }
+        }
+
         pub fn declare(&self) -> Option<&DeclareMethod> {
             match self {
                 &ExchangeClass::Declare(ref m) => Some(m),
@@ -495,6 +803,36 @@ pub mod queue {
 
     // Implementation of QueueClass {{{
     impl QueueClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &QueueClass::Declare(_) => 10,
+                &QueueClass::DeclareOk(_) => 11,
+                &QueueClass::Bind(_) => 20,
+                &QueueClass::BindOk => 21,
+                &QueueClass::Purge(_) => 30,
+                &QueueClass::PurgeOk(_) => 31,
+                &QueueClass::Delete(_) => 40,
+                &QueueClass::DeleteOk(_) => 41,
+                &QueueClass::Unbind(_) => 50,
+                &QueueClass::UnbindOk => 51,
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &QueueClass::Declare(_) |
+                &QueueClass::Bind(_) |
+                &QueueClass::Unbind(_) |
+                &QueueClass::Purge(_) |
+                &QueueClass::Delete(_) => Role::Client,
+                &QueueClass::DeclareOk(_) |
+                &QueueClass::BindOk |
+                &QueueClass::UnbindOk |
+                &QueueClass::PurgeOk(_) |
+                &QueueClass::DeleteOk(_) => Role::Server,
+            }
+        }
+
         pub fn declare(&self) -> Option<&DeclareMethod> {
             match self {
                 &QueueClass::Declare(ref m) => Some(m),
@@ -639,7 +977,7 @@ pub mod queue {
 pub mod basic {
     use super::*;
 
-    /// # Sent by client ( need to be encoded )
+    /// # Sent by client
     /// - Qos
     /// - Consume
     /// - Cancel
@@ -650,13 +988,17 @@ pub mod basic {
     /// - Nack
     /// - RecoverAsync
     /// - Recover
+    /// - CancelOk
     ///
-    /// # Received by client ( need to be decoded )
+    /// # Sent by server
     /// - QosOk
     /// - ConsumeOk
+    /// - Cancel
     /// - CancelOk
     /// - Return
     /// - Deliver
+    /// - GetOk
+    /// - GetEmpty
     /// - Ack
     /// - Nack
     /// - RecoverOk
@@ -684,6 +1026,54 @@ pub mod basic {
 
     // Implementation of BasicClass {{{
     impl BasicClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &BasicClass::Qos(_) => 10,
+                &BasicClass::QosOk => 11,
+                &BasicClass::Consume(_) => 20,
+                &BasicClass::ConsumeOk(_) => 21,
+                &BasicClass::Cancel(_) => 30,
+                &BasicClass::CancelOk(_) => 31,
+                &BasicClass::Publish(_) => 40,
+                &BasicClass::Return(_) => 50,
+                &BasicClass::Deliver(_) => 60,
+                &BasicClass::Get(_) => 70,
+                &BasicClass::GetOk(_) => 71,
+                &BasicClass::GetEmpty(_) => 72,
+                &BasicClass::Ack(_) => 80,
+                &BasicClass::Reject(_) => 90,
+                &BasicClass::RecoverAsync(_) => 100,
+                &BasicClass::Recover(_) => 110,
+                &BasicClass::RecoverOk => 111,
+                &BasicClass::Nack(_) => 120,
+            }
+        }
+
+        /// `Cancel` / `CancelOk` are sent by server too for consumer cancel notification,
+        /// and `Ack` / `Nack` are sent by server too for publisher confirms.
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &BasicClass::Qos(_) |
+                &BasicClass::Consume(_) |
+                &BasicClass::Publish(_) |
+                &BasicClass::Get(_) |
+                &BasicClass::Reject(_) |
+                &BasicClass::RecoverAsync(_) |
+                &BasicClass::Recover(_) => Role::Client,
+                &BasicClass::QosOk |
+                &BasicClass::ConsumeOk(_) |
+                &BasicClass::Return(_) |
+                &BasicClass::Deliver(_) |
+                &BasicClass::GetOk(_) |
+                &BasicClass::GetEmpty(_) |
+                &BasicClass::RecoverOk => Role::Server,
+                &BasicClass::Cancel(_) |
+                &BasicClass::CancelOk(_) |
+                &BasicClass::Ack(_) |
+                &BasicClass::Nack(_) => Role::Both,
+            }
+        }
+
         pub fn qos(&self) -> Option<&QosMethod> {
             match self {
                 &BasicClass::Qos(ref m) => Some(m),
@@ -922,6 +1312,63 @@ pub mod basic {
     pub struct NackMethod {
         pub delivery_tag: u64,
         pub multiple: bool,
+        pub requeue: bool,
+    }
+}
+// }}}
+
+
+// Confirm module {{{
+/// Publisher confirms (rabbitmq-specific extension).
+pub mod confirm {
+    use super::*;
+
+    /// # Sent by client
+    /// - Select
+    ///
+    /// # Sent by server
+    /// - SelectOk
+    #[derive(PartialEq, Clone, Debug)]
+    pub enum ConfirmClass {
+        Select(SelectMethod),
+        SelectOk,
+    }
+
+    // Implementation of ConfirmClass {{{
+    impl ConfirmClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &ConfirmClass::Select(_) => 10,
+                &ConfirmClass::SelectOk => 11,
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &ConfirmClass::Select(_) => Role::Client,
+                &ConfirmClass::SelectOk => Role::Server,
+            }
+        }
+
+        pub fn select(&self) -> Option<&SelectMethod> {
+            match self {
+                &ConfirmClass::Select(ref m) => Some(m),
+                _ => None,
+            }
+        }
+
+        pub fn select_ok(&self) -> Option<()> {
+            match self {
+                &ConfirmClass::SelectOk => Some(()),
+                _ => None,
+            }
+        }
+    }
+    // }}}
+
+    #[derive(PartialEq, Clone, Debug)]
+    pub struct SelectMethod {
+        pub no_wait: bool,
     }
 }
 // }}}
@@ -929,12 +1376,14 @@ pub mod basic {
 
 // Tx module {{{
 pub mod tx {
-    /// # Sent by client ( need to be encoded )
+    use super::*;
+
+    /// # Sent by client
     /// - Select
     /// - Commit
     /// - Rollback
     ///
-    /// # Receive by client ( need to be decoded )
+    /// # Sent by server
     /// - SelectOk
     /// - CommitOk
     /// - RollbackOk
@@ -950,6 +1399,24 @@ pub mod tx {
 
     // Implementation of TxClass {{{
     impl TxClass {
+        pub fn method_id(&self) -> u16 {
+            match self {
+                &TxClass::Select => 10,
+                &TxClass::SelectOk => 11,
+                &TxClass::Commit => 20,
+                &TxClass::CommitOk => 21,
+                &TxClass::Rollback => 30,
+                &TxClass::RollbackOk => 31,
+            }
+        }
+
+        pub fn sent_by(&self) -> Role {
+            match self {
+                &TxClass::Select | &TxClass::Commit | &TxClass::Rollback => Role::Client,
+                &TxClass::SelectOk | &TxClass::CommitOk | &TxClass::RollbackOk => Role::Server,
+            }
+        }
+
         pub fn select(&self) -> Option<()> {
             match self {
                 &TxClass::Select => Some(()),
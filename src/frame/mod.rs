@@ -4,15 +4,29 @@ pub mod encoder;
 pub mod method;
 pub mod content_body;
 pub mod content_header;
+pub mod protocol_header;
+pub mod sequence;
 
 
 use self::method::MethodPayload;
 use self::content_header::ContentHeaderPayload;
 use self::content_body::ContentBodyPayload;
 
+use bytes::Bytes;
+
+use errors::ChannelViolation;
+
 
 pub const FRAME_END_OCTET: u8 = 0xCE;
 
+pub const METHOD_TYPE_BYTE: u8 = 1;
+pub const CONTENT_HEADER_TYPE_BYTE: u8 = 2;
+pub const CONTENT_BODY_TYPE_BYTE: u8 = 3;
+/// Heartbeat frame type defined in the spec. RabbitMQ also uses this.
+pub const HEARTBEAT_TYPE_BYTE: u8 = 8;
+/// Heartbeat frame type used by some old brokers and test servers.
+pub const LEGACY_HEARTBEAT_TYPE_BYTE: u8 = 4;
+
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Frame {
@@ -27,12 +41,43 @@ pub struct FrameHeader {
 }
 
 
-#[derive(Eq, PartialEq, Clone, Debug)]
+/// Type of frame.
+///
+/// Conversion from `u8` follows the spec strictly, so legacy heartbeat type byte becomes
+/// `Unknown`. Use `Config::frame_type_of` to know how a codec treats a type byte.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
 pub enum FrameType {
     Method,
     ContentHeader,
     ContentBody,
     Heartbeat,
+    Unknown(u8),
+}
+
+
+impl From<u8> for FrameType {
+    fn from(byte: u8) -> FrameType {
+        match byte {
+            METHOD_TYPE_BYTE => FrameType::Method,
+            CONTENT_HEADER_TYPE_BYTE => FrameType::ContentHeader,
+            CONTENT_BODY_TYPE_BYTE => FrameType::ContentBody,
+            HEARTBEAT_TYPE_BYTE => FrameType::Heartbeat,
+            b => FrameType::Unknown(b),
+        }
+    }
+}
+
+
+impl From<FrameType> for u8 {
+    fn from(typ: FrameType) -> u8 {
+        match typ {
+            FrameType::Method => METHOD_TYPE_BYTE,
+            FrameType::ContentHeader => CONTENT_HEADER_TYPE_BYTE,
+            FrameType::ContentBody => CONTENT_BODY_TYPE_BYTE,
+            FrameType::Heartbeat => HEARTBEAT_TYPE_BYTE,
+            FrameType::Unknown(b) => b,
+        }
+    }
 }
 
 
@@ -41,7 +86,12 @@ pub enum FramePayload {
     Method(MethodPayload),
     ContentHeader(ContentHeaderPayload),
     ContentBody(ContentBodyPayload),
-    Heartbeat,
+    /// Heartbeat with the type byte it arrived with (8, or legacy 4 if accepted by `Config`).
+    /// When encoding, `Config::heartbeat_frame_type` is written regardless of this byte.
+    Heartbeat(u8),
+
+    /// Frame of unknown type. This is decoded only if `Config::passthrough_unknown` is set.
+    Unknown { frame_type: u8, payload: Bytes },
 }
 
 
@@ -67,7 +117,7 @@ impl Frame {
     }
 
     pub fn new_heartbeat(channel: u16) -> Frame {
-        Frame::new(channel, FramePayload::Heartbeat)
+        Frame::new(channel, FramePayload::Heartbeat(HEARTBEAT_TYPE_BYTE))
     }
 
 
@@ -94,9 +144,68 @@ impl Frame {
 
     pub fn heartbeat(&self) -> Option<()> {
         match &self.payload {
-            &FramePayload::Heartbeat => Some(()),
+            &FramePayload::Heartbeat(_) => Some(()),
             _ => None,
         }
     }
+
+    pub fn frame_type(&self) -> FrameType {
+        self.payload.frame_type()
+    }
+
+    /// Check whether this frame is placed on a channel where the spec allows it.
+    ///
+    /// - Connection class methods and heartbeats must be on channel 0.
+    /// - Other methods and content frames must be on a non-zero channel.
+    pub fn check_channel(&self) -> Result<(), ChannelViolation> {
+        const CONNECTION_CLASS_ID: u16 = 10;
+        let channel = self.header.channel;
+        match &self.payload {
+            &FramePayload::Method(ref m) if m.class_id() == CONNECTION_CLASS_ID => {
+                if channel != 0 {
+                    let method_id = m.method_id();
+                    return Err(ChannelViolation::ConnectionMethodOnChannel {
+                        method_id: method_id,
+                    });
+                }
+            }
+            &FramePayload::Method(ref m) => {
+                if channel == 0 {
+                    return Err(ChannelViolation::MethodOnChannelZero {
+                        class_id: m.class_id(),
+                        method_id: m.method_id(),
+                    });
+                }
+            }
+            &FramePayload::ContentHeader(_) |
+            &FramePayload::ContentBody(_) => {
+                if channel == 0 {
+                    return Err(ChannelViolation::ContentOnChannelZero);
+                }
+            }
+            &FramePayload::Heartbeat(_) => {
+                if channel != 0 {
+                    return Err(ChannelViolation::HeartbeatOnChannel);
+                }
+            }
+            &FramePayload::Unknown { .. } => {}
+        }
+        Ok(())
+    }
+}
+// }}}
+
+
+// Implementation of FramePayload {{{
+impl FramePayload {
+    pub fn frame_type(&self) -> FrameType {
+        match self {
+            &FramePayload::Method(_) => FrameType::Method,
+            &FramePayload::ContentHeader(_) => FrameType::ContentHeader,
+            &FramePayload::ContentBody(_) => FrameType::ContentBody,
+            &FramePayload::Heartbeat(_) => FrameType::Heartbeat,
+            &FramePayload::Unknown { frame_type, .. } => FrameType::Unknown(frame_type),
+        }
+    }
 }
 // }}}
@@ -0,0 +1,120 @@
+//! Protocol header which opens every connection.
+//!
+//! # Byte architecture of protocol header
+//!
+//! position   0        4             5       6       7
+//!            +--------+-------------+-------+-------+----------+
+//!            | "AMQP" | protocol_id | major | minor | revision |
+//!            +--------+-------------+-------+-------+----------+
+//! length         4           1          1       1        1
+//!
+//! Client sends this header at first. If server does not support the version, server sends
+//! back a header of the version it supports and closes the connection.
+
+use bytes::{BufMut, BytesMut};
+
+use config::ProtocolVersion;
+
+pub const PROTOCOL_HEADER_SIZE: usize = 8;
+
+const AMQP_LITERAL: &'static [u8] = b"AMQP";
+
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct ProtocolHeader {
+    pub protocol_id: u8,
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u8,
+}
+
+
+impl ProtocolHeader {
+    /// Protocol header of AMQP 0-9-1.
+    pub fn amqp_0_9_1() -> ProtocolHeader {
+        ProtocolHeader {
+            protocol_id: 0,
+            major: 0,
+            minor: 9,
+            revision: 1,
+        }
+    }
+
+    /// Protocol header of AMQP 0-9.
+    pub fn amqp_0_9() -> ProtocolHeader {
+        ProtocolHeader {
+            protocol_id: 1,
+            major: 1,
+            minor: 0,
+            revision: 9,
+        }
+    }
+
+    /// Protocol header of AMQP 0-8. The layout of header was different at that time
+    /// (protocol class, protocol instance, major and minor), so it looks odd.
+    pub fn amqp_0_8() -> ProtocolHeader {
+        ProtocolHeader {
+            protocol_id: 1,
+            major: 1,
+            minor: 8,
+            revision: 0,
+        }
+    }
+
+    /// Protocol version of this header if this crate supports it.
+    pub fn version(&self) -> Option<ProtocolVersion> {
+        [ProtocolVersion::Amqp0_8, ProtocolVersion::Amqp0_9, ProtocolVersion::Amqp0_9_1]
+            .iter()
+            .find(|v| v.protocol_header() == *self)
+            .cloned()
+    }
+
+    pub fn to_bytes(&self) -> [u8; PROTOCOL_HEADER_SIZE] {
+        [
+            b'A',
+            b'M',
+            b'Q',
+            b'P',
+            self.protocol_id,
+            self.major,
+            self.minor,
+            self.revision,
+        ]
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        dst.reserve(PROTOCOL_HEADER_SIZE);
+        dst.put_slice(&self.to_bytes());
+    }
+}
+
+
+impl Default for ProtocolHeader {
+    fn default() -> ProtocolHeader {
+        ProtocolHeader::amqp_0_9_1()
+    }
+}
+
+
+/// Check whether `src` starts with a protocol header (not a frame).
+/// This can be decided by only the first byte because "A" is not a valid frame type.
+pub fn starts_with_protocol_header(src: &BytesMut) -> bool {
+    src.len() > 0 && src[0] == AMQP_LITERAL[0]
+}
+
+
+/// Decode a protocol header.
+/// Returns `None` if there is not enough length or `src` does not start with "AMQP".
+pub fn decode_protocol_header(src: &mut BytesMut) -> Option<ProtocolHeader> {
+    if src.len() < PROTOCOL_HEADER_SIZE || !src.starts_with(AMQP_LITERAL) {
+        return None;
+    }
+
+    let bytes = src.split_to(PROTOCOL_HEADER_SIZE);
+    Some(ProtocolHeader {
+        protocol_id: bytes[4],
+        major: bytes[5],
+        minor: bytes[6],
+        revision: bytes[7],
+    })
+}
@@ -0,0 +1,259 @@
+//! Per-channel checker of content frame sequence.
+//!
+//! A content-bearing method (`Basic.Publish`, `Basic.Return`, `Basic.Deliver` and
+//! `Basic.GetOk`) must be followed by exactly one content header frame of the same class.
+//! After that, content body frames follow until the sum of their sizes reaches `body_size`
+//! in the content header. No other method can be sent on the channel meanwhile.
+//!
+//! `ContentSequence` does not care about direction, so use one for frames you receive and
+//! another for frames you send.
+
+use std::collections::HashMap;
+
+use errors::SequenceError;
+use frame::{Frame, FramePayload};
+use frame::method::{MethodPayload, BasicClass, ChannelClass};
+
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum ChannelState {
+    /// Waiting for content header of `class_id`.
+    ExpectHeader { class_id: u16 },
+    /// Waiting for content body. `received` bytes of `body_size` have been received.
+    ExpectBody { body_size: u64, received: u64 },
+}
+
+
+/// Next state of a channel, which is decided by `ContentSequence::peek`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub(crate) struct Transition {
+    channel: u16,
+    next: Option<ChannelState>,
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct ContentSequence {
+    // Channels which are not in the middle of content are not stored.
+    channels: HashMap<u16, ChannelState>,
+}
+
+
+impl ContentSequence {
+    pub fn new() -> ContentSequence {
+        ContentSequence { channels: HashMap::new() }
+    }
+
+    /// Check the next frame and update the state of its channel.
+    /// If an error is returned, the state of the channel is left untouched.
+    pub fn check(&mut self, frame: &Frame) -> Result<(), SequenceError> {
+        let transition = self.peek(frame)?;
+        self.commit(transition);
+        Ok(())
+    }
+
+    /// Check the next frame without updating any state. Pass the result to `commit` once the
+    /// frame is really sent or received.
+    pub(crate) fn peek(&self, frame: &Frame) -> Result<Transition, SequenceError> {
+        let channel = frame.header.channel;
+        let state = self.channels.get(&channel).cloned();
+
+        let next = match (state, &frame.payload) {
+            // Closing a channel discards the content in progress.
+            (_, &FramePayload::Method(MethodPayload::Channel(ChannelClass::Close(_)))) |
+            (_, &FramePayload::Method(MethodPayload::Channel(ChannelClass::CloseOk))) => None,
+
+            (None, &FramePayload::Method(ref m)) => {
+                if is_content_bearing(m) {
+                    Some(ChannelState::ExpectHeader { class_id: m.class_id() })
+                } else {
+                    None
+                }
+            }
+            (Some(_), &FramePayload::Method(ref m)) => {
+                return Err(SequenceError::InterleavedMethod {
+                    class_id: m.class_id(),
+                    method_id: m.method_id(),
+                });
+            }
+
+            (None, &FramePayload::ContentHeader(_)) => return Err(SequenceError::OrphanHeader),
+            (Some(ChannelState::ExpectHeader { class_id }),
+             &FramePayload::ContentHeader(ref h)) => {
+                if h.class_id != class_id {
+                    return Err(SequenceError::ClassMismatch {
+                        expected: class_id,
+                        found: h.class_id,
+                    });
+                }
+                if h.body_size == 0 {
+                    None
+                } else {
+                    Some(ChannelState::ExpectBody {
+                        body_size: h.body_size,
+                        received: 0,
+                    })
+                }
+            }
+            (Some(ChannelState::ExpectBody { body_size, received }),
+             &FramePayload::ContentHeader(_)) => {
+                return Err(SequenceError::BodyUnderrun {
+                    body_size: body_size,
+                    received: received,
+                });
+            }
+
+            (Some(ChannelState::ExpectBody { body_size, received }),
+             &FramePayload::ContentBody(ref b)) => {
+                let received = received + b.bytes.len() as u64;
+                if received > body_size {
+                    return Err(SequenceError::BodyOverrun {
+                        body_size: body_size,
+                        received: received,
+                    });
+                }
+                if received == body_size {
+                    None
+                } else {
+                    Some(ChannelState::ExpectBody {
+                        body_size: body_size,
+                        received: received,
+                    })
+                }
+            }
+            (_, &FramePayload::ContentBody(_)) => return Err(SequenceError::OrphanBody),
+
+            // Heartbeat and unknown frames do not affect content sequence.
+            (state, _) => state,
+        };
+
+        Ok(Transition {
+            channel: channel,
+            next: next,
+        })
+    }
+
+    pub(crate) fn commit(&mut self, transition: Transition) {
+        match transition.next {
+            Some(s) => self.channels.insert(transition.channel, s),
+            None => self.channels.remove(&transition.channel),
+        };
+    }
+
+    /// Check whether `channel` is in the middle of content.
+    pub fn is_in_content(&self, channel: u16) -> bool {
+        self.channels.contains_key(&channel)
+    }
+
+    /// Forget the state of `channel` (e.g. after the channel is closed abnormally).
+    pub fn reset_channel(&mut self, channel: u16) {
+        self.channels.remove(&channel);
+    }
+}
+
+
+fn is_content_bearing(method: &MethodPayload) -> bool {
+    match method {
+        &MethodPayload::Basic(BasicClass::Publish(_)) |
+        &MethodPayload::Basic(BasicClass::Return(_)) |
+        &MethodPayload::Basic(BasicClass::Deliver(_)) |
+        &MethodPayload::Basic(BasicClass::GetOk(_)) => true,
+        _ => false,
+    }
+}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use args::AmqpString;
+    use bytes::Bytes;
+    use frame::content_header::{ContentHeaderPayload, Properties};
+    use frame::content_body::ContentBodyPayload;
+    use frame::method::basic::PublishMethod;
+    use frame::method::tx::TxClass;
+
+    fn publish(channel: u16) -> Frame {
+        let publish = PublishMethod {
+            reserved1: 0,
+            exchange: AmqpString::from(""),
+            routing_key: AmqpString::from("queue"),
+            mandatory: false,
+            immediate: false,
+        };
+        Frame::new_method(channel, MethodPayload::Basic(BasicClass::Publish(publish)))
+    }
+
+    fn header(channel: u16, class_id: u16, body_size: u64) -> Frame {
+        let header = ContentHeaderPayload {
+            class_id: class_id,
+            body_size: body_size,
+            properties: Properties::new(),
+            raw_properties: None,
+        };
+        Frame::new_content_header(channel, header)
+    }
+
+    fn body(channel: u16, size: usize) -> Frame {
+        Frame::new_content_body(channel, ContentBodyPayload { bytes: Bytes::from(vec![0; size]) })
+    }
+
+    #[test]
+    fn valid_sequence_on_multiple_channels() {
+        let mut seq = ContentSequence::new();
+
+        seq.check(&publish(1)).unwrap();
+        seq.check(&publish(2)).unwrap();
+        seq.check(&header(1, 60, 10)).unwrap();
+        seq.check(&header(2, 60, 0)).unwrap();
+        seq.check(&body(1, 4)).unwrap();
+        seq.check(&Frame::new_heartbeat(0)).unwrap();
+        seq.check(&body(1, 6)).unwrap();
+
+        assert!(!seq.is_in_content(1));
+        assert!(!seq.is_in_content(2));
+    }
+
+    #[test]
+    fn invalid_sequences() {
+        let mut seq = ContentSequence::new();
+        assert_eq!(seq.check(&header(1, 60, 1)), Err(SequenceError::OrphanHeader));
+        assert_eq!(seq.check(&body(1, 1)), Err(SequenceError::OrphanBody));
+
+        seq.check(&publish(1)).unwrap();
+        assert_eq!(
+            seq.check(&Frame::new_method(1, MethodPayload::Tx(TxClass::Commit))),
+            Err(SequenceError::InterleavedMethod {
+                class_id: 90,
+                method_id: 20,
+            })
+        );
+        assert_eq!(
+            seq.check(&header(1, 50, 1)),
+            Err(SequenceError::ClassMismatch {
+                expected: 60,
+                found: 50,
+            })
+        );
+
+        seq.check(&header(1, 60, 3)).unwrap();
+        seq.check(&body(1, 2)).unwrap();
+        assert_eq!(
+            seq.check(&body(1, 2)),
+            Err(SequenceError::BodyOverrun {
+                body_size: 3,
+                received: 4,
+            })
+        );
+        assert_eq!(
+            seq.check(&header(1, 60, 3)),
+            Err(SequenceError::BodyUnderrun {
+                body_size: 3,
+                received: 2,
+            })
+        );
+    }
+}
+// }}}
@@ -10,6 +10,11 @@
 //!
 //! "payload" is defined for each frame type.
 //!
+//! # Features
+//! - "arbitrary" : implements `arbitrary::Arbitrary` for `Frame`, every payload and method,
+//!   `Properties` and `FieldArgument`. Generated frames always round-trip through the codec.
+//! - "mock-broker" : enables `mock_broker` module, an in-memory AMQP broker for tests.
+//!
 extern crate tokio_io;
 
 extern crate bytes;
@@ -17,35 +22,295 @@ extern crate bytes;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+
 pub mod frame;
+pub mod errors;
+pub mod config;
+pub mod routing;
+pub mod queue_store;
 mod args;
+#[cfg(feature = "arbitrary")]
+mod arbitrary_impls;
+#[cfg(feature = "mock-broker")]
+pub mod mock_broker;
 
-pub use args::FieldArgument;
-pub use frame::{Frame, FrameHeader, FramePayload};
+pub use args::{FieldArgument, Decimal, ParseDecimalError};
+pub use config::{Config, DecodeLimits, FieldDialect, InvalidHeartbeatType, ProtocolVersion,
+                 Role};
+pub use errors::{DecodeError, DecodeErrorKind, EncodeError, EncodeErrorKind, ReplyCode,
+                 ChannelViolation, SequenceError};
+pub use frame::{Frame, FrameHeader, FramePayload, FrameType};
+pub use frame::protocol_header::ProtocolHeader;
+pub use frame::sequence::ContentSequence;
 pub use frame::method;
 pub use frame::content_header;
 pub use frame::content_body;
 
 
 use bytes::BytesMut;
-use std::io::Error as IoError;
 
-pub struct Codec;
+use frame::method::connection::TuneOkMethod;
+use frame::protocol_header::{PROTOCOL_HEADER_SIZE, starts_with_protocol_header,
+                             decode_protocol_header};
+
+
+/// Encoder / Decoder of AMQP frames.
+///
+/// Use `Codec::tune` to apply values negotiated by `Connection.Tune` / `Connection.TuneOk`.
+///
+/// # Pre-handshake mode
+/// A codec made by `Codec::pre_handshake` expects that server may send back a protocol header
+/// instead of the first frame. In that case, `decode` returns
+/// `DecodeErrorKind::ProtocolVersionMismatch` which holds the version server proposes.
+/// After the first frame is decoded, the codec leaves pre-handshake mode.
+///
+/// # Role
+/// By default the codec works for a client. Set `Config::role` to `Role::Server` to build a
+/// server, or to `Role::Both` to build a proxy which handles methods in both directions.
+///
+/// # Content sequence
+/// If `Config::validate_content_sequence` is set, the codec tracks content frames of each
+/// channel separately for received and sent frames, and rejects frames out of order.
+#[derive(Clone, Debug, Default)]
+pub struct Codec {
+    config: Config,
+    pre_handshake: bool,
+    inbound: ContentSequence,
+    outbound: ContentSequence,
+}
+
+
+impl Codec {
+    pub fn new() -> Codec {
+        Codec::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Codec {
+        debug_assert!(config.is_valid(), "Invalid config : {:?}", config);
+        Codec {
+            config: config,
+            pre_handshake: false,
+            inbound: ContentSequence::new(),
+            outbound: ContentSequence::new(),
+        }
+    }
+
+    /// Make a codec in pre-handshake mode.
+    pub fn pre_handshake() -> Codec {
+        let mut codec = Codec::new();
+        codec.set_pre_handshake(true);
+        codec
+    }
+
+    pub fn set_pre_handshake(&mut self, pre_handshake: bool) {
+        self.pre_handshake = pre_handshake;
+    }
+
+    pub fn is_pre_handshake(&self) -> bool {
+        self.pre_handshake
+    }
+
+    /// Write the protocol header which client must send at first.
+    /// Its version follows `Config::protocol_version`.
+    pub fn encode_protocol_header(&self, dst: &mut BytesMut) {
+        self.config.protocol_version.protocol_header().encode(dst);
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// Apply channel_max and frame_max which client sends to server by `TuneOkMethod`.
+    pub fn tune(&mut self, tune_ok: &TuneOkMethod) {
+        self.config.channel_max = tune_ok.channel_max;
+        self.config.frame_max = tune_ok.frame_max;
+    }
+
+    /// Forget content in progress on `channel` in both directions.
+    pub fn reset_channel(&mut self, channel: u16) {
+        self.inbound.reset_channel(channel);
+        self.outbound.reset_channel(channel);
+    }
+}
+
 
 impl tokio_io::codec::Decoder for Codec {
     type Item = Frame;
-    type Error = IoError;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        if self.pre_handshake && starts_with_protocol_header(src) {
+            if src.len() < PROTOCOL_HEADER_SIZE {
+                return Ok(None);
+            }
+            if let Some(header) = decode_protocol_header(src) {
+                let kind = DecodeErrorKind::ProtocolVersionMismatch(header);
+                return Err(DecodeError::new(0, 0, kind));
+            }
+            // Not a protocol header. It is reported as an invalid frame below.
+        }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, IoError> {
-        Ok(frame::decoder::decode_frame(src))
+        let frame = frame::decoder::decode_frame(src, &self.config)?;
+        if let Some(ref f) = frame {
+            self.pre_handshake = false;
+            if self.config.validate_content_sequence {
+                self.inbound.check(f).map_err(|e| {
+                    DecodeError::new(f.header.channel, 0, DecodeErrorKind::ContentSequence(e))
+                })?;
+            }
+        }
+        Ok(frame)
     }
 }
 
 impl tokio_io::codec::Encoder for Codec {
     type Item = Frame;
-    type Error = IoError;
+    type Error = EncodeError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        if !self.config.validate_content_sequence {
+            return frame::encoder::encode_frame(item, dst, &self.config);
+        }
+
+        // The sequence goes forward only if the frame is really encoded.
+        let transition = self.outbound.peek(&item).map_err(|e| {
+            EncodeError::new(item.header.channel, EncodeErrorKind::ContentSequence(e))
+        })?;
+        frame::encoder::encode_frame(item, dst, &self.config)?;
+        self.outbound.commit(transition);
+        Ok(())
+    }
+}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_io::codec::Decoder;
+
+    #[test]
+    fn decode_protocol_header_in_pre_handshake_mode() {
+        let mut codec = Codec::pre_handshake();
+        let mut src = BytesMut::from(&b"AMQP\x00\x00\x09"[..]);
+
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(&[0x00]);
+        let err = codec.decode(&mut src).unwrap_err();
+
+        match err.kind {
+            DecodeErrorKind::ProtocolVersionMismatch(h) => {
+                assert_eq!((h.minor, h.revision), (9, 0))
+            }
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+    }
+
+    #[test]
+    fn leave_pre_handshake_mode_after_first_frame() {
+        let mut codec = Codec::pre_handshake();
+        let mut src = BytesMut::from(&[8, 0, 0, 0, 0, 0, 0, frame::FRAME_END_OCTET][..]);
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Frame::new_heartbeat(0)));
+        assert!(!codec.is_pre_handshake());
+    }
+
+    #[test]
+    fn encode_protocol_header() {
+        let mut dst = BytesMut::new();
+
+        Codec::new().encode_protocol_header(&mut dst);
+
+        assert_eq!(dst.as_ref(), b"AMQP\x00\x00\x09\x01");
+    }
+
+    #[test]
+    fn encode_protocol_header_of_configured_version() {
+        let config = Config {
+            protocol_version: ProtocolVersion::Amqp0_8,
+            ..Config::default()
+        };
+        let mut dst = BytesMut::new();
+
+        Codec::with_config(config).encode_protocol_header(&mut dst);
+
+        assert_eq!(dst.as_ref(), b"AMQP\x01\x01\x08\x00");
+        assert_eq!(ProtocolHeader::amqp_0_8().version(), Some(ProtocolVersion::Amqp0_8));
+    }
+
+    #[test]
+    fn orphan_content_body_is_not_encoded() {
+        use tokio_io::codec::Encoder;
+        use content_body::ContentBodyPayload;
+
+        let config = Config {
+            validate_content_sequence: true,
+            ..Config::default()
+        };
+        let mut codec = Codec::with_config(config);
+        let mut dst = BytesMut::new();
+        let body = ContentBodyPayload { bytes: bytes::Bytes::from(&b"body"[..]) };
+
+        let err = codec.encode(Frame::new_content_body(1, body), &mut dst).unwrap_err();
+
+        match err.kind {
+            EncodeErrorKind::ContentSequence(SequenceError::OrphanBody) => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
+        assert_eq!(dst.len(), 0);
+    }
+
+    #[test]
+    fn failed_content_header_can_be_sent_again() {
+        use std::collections::HashMap;
+        use tokio_io::codec::Encoder;
+        use args::AmqpString;
+        use content_body::ContentBodyPayload;
+        use content_header::{ContentHeaderPayload, Properties};
+        use method::MethodPayload;
+        use method::basic::{BasicClass, PublishMethod};
+
+        let config = Config {
+            frame_max: 4096,
+            validate_content_sequence: true,
+            ..Config::default()
+        };
+        let mut codec = Codec::with_config(config);
+        let mut dst = BytesMut::new();
+        let publish = PublishMethod {
+            reserved1: 0,
+            exchange: AmqpString::from(""),
+            routing_key: AmqpString::from("queue"),
+            mandatory: false,
+            immediate: false,
+        };
+        let method = MethodPayload::Basic(BasicClass::Publish(publish));
+        codec.encode(Frame::new_method(1, method), &mut dst).unwrap();
+
+        let mut headers = HashMap::new();
+        let large = FieldArgument::LongString(AmqpString::from(vec![b'a'; 5000]));
+        headers.insert(AmqpString::from("large"), large);
+        let mut properties = Properties::new();
+        properties.headers = Some(headers);
+        let header = ContentHeaderPayload::basic(4, properties);
+        let err = codec.encode(Frame::new_content_header(1, header), &mut dst).unwrap_err();
+        match err.kind {
+            EncodeErrorKind::FrameTooLarge { .. } => {}
+            ref k => panic!("Unexpected error {:?}", k),
+        }
 
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), IoError> {
-        Ok(frame::encoder::encode_frame(item, dst))
+        let header = ContentHeaderPayload::basic(4, Properties::new());
+        codec.encode(Frame::new_content_header(1, header), &mut dst).unwrap();
+        let body = ContentBodyPayload { bytes: bytes::Bytes::from(&b"body"[..]) };
+        codec.encode(Frame::new_content_body(1, body), &mut dst).unwrap();
     }
 }
+// }}}
@@ -0,0 +1,605 @@
+//! Serving one client connection of `MockBroker`.
+//!
+//! Received frames are handled on the thread calling `serve`. Frames to send are passed to
+//! a writer thread through a channel, because deliveries are also made by other connections.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use bytes::{Bytes, BytesMut};
+use tokio_io::codec::{Decoder, Encoder};
+
+use {Codec, Config, ProtocolVersion, Role};
+use args::{AmqpString, FieldArgument};
+use config::DEFAULT_FRAME_MAX;
+use errors::ReplyCode;
+use frame::{Frame, FramePayload};
+use frame::protocol_header::{PROTOCOL_HEADER_SIZE, decode_protocol_header};
+use frame::method::MethodPayload;
+use frame::method::access::{AccessClass, RequestOkMethod};
+use frame::method::basic::{BasicClass, CancelOkMethod, PublishMethod};
+use frame::method::channel::{self, ChannelClass};
+use frame::method::confirm::ConfirmClass;
+use frame::method::connection::{self, ConnectionClass};
+use frame::method::exchange::ExchangeClass;
+use frame::method::queue::{self, QueueClass};
+use frame::method::tx::TxClass;
+use frame::content_header::ContentHeaderPayload;
+use frame::content_body::ContentBodyPayload;
+
+use super::state::{AmqpError, AmqpResult, Broker, Message, FRAME_MIN_SIZE};
+
+
+const CHANNEL_MAX: u16 = 2047;
+
+
+fn server_config() -> Config {
+    Config {
+        role: Role::Server,
+        validate_channels: true,
+        validate_content_sequence: true,
+        ..Config::default()
+    }
+}
+
+
+/// Serve a connection until the client closes it or the stream reaches EOF.
+pub fn serve<R: Read, W: Write + Send + 'static>(
+    broker: Arc<Mutex<Broker>>,
+    mut reader: R,
+    mut writer: W,
+) -> io::Result<()> {
+    let mut header = [0; PROTOCOL_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let version = decode_protocol_header(&mut BytesMut::from(&header[..])).and_then(|h| {
+        h.version()
+    });
+    if version != Some(ProtocolVersion::Amqp0_9_1) {
+        // Tell the version we support and close the connection as the spec says.
+        let mut dst = BytesMut::new();
+        ProtocolVersion::Amqp0_9_1.protocol_header().encode(&mut dst);
+        return writer.write_all(&dst);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let write_thread = thread::spawn(move || write_frames(writer, rx));
+    let conn_id = broker.lock().unwrap().add_connection(tx);
+
+    let result = {
+        let mut session = Session {
+            broker: broker.clone(),
+            conn_id: conn_id,
+            codec: Codec::with_config(server_config()),
+            state: State::AwaitStartOk,
+            publishes: HashMap::new(),
+            closing_channels: HashSet::new(),
+        };
+        session.start();
+        session.run(&mut reader)
+    };
+
+    // Dropping the sender lets the writer thread flush remaining frames and finish.
+    broker.lock().unwrap().remove_connection(conn_id);
+    let written = write_thread.join().unwrap_or_else(|_| {
+        Err(io::Error::new(io::ErrorKind::Other, "writer thread panicked"))
+    });
+    result.and(written)
+}
+
+
+fn write_frames<W: Write>(mut writer: W, frames: Receiver<Frame>) -> io::Result<()> {
+    let mut codec = Codec::with_config(Config {
+        role: Role::Server,
+        frame_max: 0,
+        ..Config::default()
+    });
+    let mut buf = BytesMut::new();
+
+    while let Ok(frame) = frames.recv() {
+        let mut next = Some(frame);
+        // Write all queued frames at once.
+        while let Some(frame) = next {
+            codec.encode(frame, &mut buf).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            })?;
+            next = frames.try_recv().ok();
+        }
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        buf.clear();
+    }
+    Ok(())
+}
+
+
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum State {
+    AwaitStartOk,
+    AwaitTuneOk,
+    AwaitOpen,
+    Open,
+    /// We sent `Connection.Close` and wait for `Connection.CloseOk`.
+    Closing,
+    Closed,
+}
+
+
+/// Content which is being received after `Basic.Publish`.
+struct PendingPublish {
+    method: PublishMethod,
+    header: Option<ContentHeaderPayload>,
+    body: BytesMut,
+}
+
+
+struct Session {
+    broker: Arc<Mutex<Broker>>,
+    conn_id: u64,
+    codec: Codec,
+    state: State,
+    publishes: HashMap<u16, PendingPublish>,
+    /// Channels on which we sent `Channel.Close` and wait for `Channel.CloseOk`.
+    closing_channels: HashSet<u16>,
+}
+
+
+impl Session {
+    fn broker(&self) -> MutexGuard<'_, Broker> {
+        self.broker.lock().unwrap()
+    }
+
+    fn send_method(&self, channel: u16, method: MethodPayload) {
+        self.broker().send_method(self.conn_id, channel, method);
+    }
+
+    fn start(&mut self) {
+        let mut capabilities = HashMap::new();
+        for cap in &[
+            "publisher_confirms",
+            "basic.nack",
+            "consumer_cancel_notify",
+            "exchange_exchange_bindings",
+            "per_consumer_qos",
+        ]
+        {
+            capabilities.insert(AmqpString::from(*cap), FieldArgument::Boolean(true));
+        }
+        let mut server_properties = HashMap::new();
+        server_properties.insert(
+            AmqpString::from("product"),
+            FieldArgument::LongString(AmqpString::from("amqpr-codec mock broker")),
+        );
+        server_properties.insert(
+            AmqpString::from("capabilities"),
+            FieldArgument::NestedTable(capabilities),
+        );
+
+        let start = connection::StartMethod {
+            version_major: 0,
+            version_minor: 9,
+            server_properties: server_properties,
+            mechanisms: AmqpString::from("PLAIN AMQPLAIN"),
+            locales: AmqpString::from("en_US"),
+        };
+        self.send_method(0, MethodPayload::Connection(ConnectionClass::Start(start)));
+    }
+
+    fn run<R: Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        let mut chunk = [0; 4096];
+        loop {
+            loop {
+                match self.codec.decode(&mut buf) {
+                    Ok(Some(frame)) => self.handle_frame(frame),
+                    Ok(None) => break,
+                    Err(e) => {
+                        // We can not know where the next frame starts, so stop reading.
+                        debug!("Close connection by decode error : {}", e);
+                        let close = ConnectionClass::Close(e.close_method());
+                        self.send_method(0, MethodPayload::Connection(close));
+                        return Ok(());
+                    }
+                }
+                if self.state == State::Closed {
+                    return Ok(());
+                }
+            }
+
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn handle_frame(&mut self, frame: Frame) {
+        let channel = frame.header.channel;
+        if self.state == State::Closing {
+            // Everything but Connection.Close / CloseOk is discarded.
+            match frame.payload {
+                FramePayload::Method(MethodPayload::Connection(ConnectionClass::Close(_))) => {
+                    let close_ok = MethodPayload::Connection(ConnectionClass::CloseOk);
+                    self.send_method(0, close_ok);
+                    self.state = State::Closed;
+                }
+                FramePayload::Method(MethodPayload::Connection(ConnectionClass::CloseOk)) => {
+                    self.state = State::Closed;
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        let result = match frame.payload {
+            FramePayload::Method(m) => {
+                let (class_id, method_id) = (m.class_id(), m.method_id());
+                self.handle_method(channel, m).map_err(|e| (e, class_id, method_id))
+            }
+            FramePayload::ContentHeader(header) => {
+                self.handle_content_header(channel, header).map_err(|e| (e, 60, 40))
+            }
+            FramePayload::ContentBody(body) => {
+                self.handle_content_body(channel, body).map_err(|e| (e, 60, 40))
+            }
+            _ => Ok(()),
+        };
+
+        match result {
+            Ok(()) => {}
+            Err((AmqpError::Channel(code, text), class_id, method_id)) => {
+                debug!("Close channel {} : {}", channel, text);
+                let close = channel::CloseMethod {
+                    reply_code: code,
+                    reply_text: AmqpString::from(text),
+                    class_id: class_id,
+                    method_id: method_id,
+                };
+                self.send_method(channel, MethodPayload::Channel(ChannelClass::Close(close)));
+                self.forget_channel(channel);
+                self.closing_channels.insert(channel);
+            }
+            Err((AmqpError::Connection(code, text), class_id, method_id)) => {
+                debug!("Close connection : {}", text);
+                let close = connection::CloseMethod {
+                    reply_code: code,
+                    reply_text: AmqpString::from(text),
+                    class_id: class_id,
+                    method_id: method_id,
+                };
+                self.send_method(0, MethodPayload::Connection(ConnectionClass::Close(close)));
+                self.state = State::Closing;
+            }
+        }
+    }
+
+    fn forget_channel(&mut self, channel: u16) {
+        self.publishes.remove(&channel);
+        self.codec.reset_channel(channel);
+        self.broker().close_channel(self.conn_id, channel);
+    }
+
+
+    // Methods {{{
+    fn handle_method(&mut self, channel: u16, method: MethodPayload) -> AmqpResult<()> {
+        if channel == 0 {
+            return self.handle_connection_method(method);
+        }
+        if self.state != State::Open {
+            return Err(AmqpError::connection(
+                ReplyCode::CommandInvalid,
+                "connection is not open",
+            ));
+        }
+
+        if self.closing_channels.contains(&channel) {
+            // Everything but Channel.Close / CloseOk is discarded.
+            match method {
+                MethodPayload::Channel(ChannelClass::Close(_)) => {
+                    self.closing_channels.remove(&channel);
+                    self.send_method(channel, MethodPayload::Channel(ChannelClass::CloseOk));
+                }
+                MethodPayload::Channel(ChannelClass::CloseOk) => {
+                    self.closing_channels.remove(&channel);
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match method {
+            MethodPayload::Channel(ChannelClass::Open(_)) => {
+                self.broker().open_channel(self.conn_id, channel)?;
+                let open_ok = channel::OpenOkMethod { reserved1: AmqpString::from("") };
+                self.send_method(channel, MethodPayload::Channel(ChannelClass::OpenOk(open_ok)));
+                Ok(())
+            }
+            _ if !self.broker().is_channel_open(self.conn_id, channel) => Err(
+                AmqpError::connection(ReplyCode::ChannelError, "expected 'channel.open'"),
+            ),
+            MethodPayload::Channel(c) => self.handle_channel_method(channel, c),
+            MethodPayload::Access(AccessClass::Request(_)) => {
+                let ok = RequestOkMethod { ticket: 1 };
+                self.send_method(channel, MethodPayload::Access(AccessClass::RequestOk(ok)));
+                Ok(())
+            }
+            MethodPayload::Exchange(c) => self.handle_exchange_method(channel, c),
+            MethodPayload::Queue(c) => self.handle_queue_method(channel, c),
+            MethodPayload::Basic(c) => self.handle_basic_method(channel, c),
+            MethodPayload::Confirm(ConfirmClass::Select(m)) => {
+                self.broker().confirm_select(self.conn_id, channel)?;
+                if !m.no_wait {
+                    let ok = MethodPayload::Confirm(ConfirmClass::SelectOk);
+                    self.send_method(channel, ok);
+                }
+                Ok(())
+            }
+            MethodPayload::Tx(c) => self.handle_tx_method(channel, c),
+            _ => Err(AmqpError::connection(
+                ReplyCode::CommandInvalid,
+                "unexpected method",
+            )),
+        }
+    }
+
+    fn handle_connection_method(&mut self, method: MethodPayload) -> AmqpResult<()> {
+        let class = match method {
+            MethodPayload::Connection(c) => c,
+            _ => unreachable!("Validated by codec"),
+        };
+
+        match (self.state, class) {
+            (State::AwaitStartOk, ConnectionClass::StartOk(_)) => {
+                // Any credential is accepted.
+                let tune = connection::TuneMethod {
+                    channel_max: CHANNEL_MAX,
+                    frame_max: DEFAULT_FRAME_MAX,
+                    heartbeat: 0,
+                };
+                self.send_method(0, MethodPayload::Connection(ConnectionClass::Tune(tune)));
+                self.state = State::AwaitTuneOk;
+            }
+            (State::AwaitTuneOk, ConnectionClass::TuneOk(tune_ok)) => {
+                // Zero means no limit, which would raise the frame_max we proposed.
+                let frame_max = tune_ok.frame_max;
+                if frame_max < FRAME_MIN_SIZE || frame_max > DEFAULT_FRAME_MAX {
+                    return Err(AmqpError::connection(
+                        ReplyCode::NotAllowed,
+                        "frame_max is out of range",
+                    ));
+                }
+                self.codec.tune(&tune_ok);
+                if let Some(conn) = self.broker().connection_mut(self.conn_id) {
+                    conn.frame_max = tune_ok.frame_max;
+                }
+                self.state = State::AwaitOpen;
+            }
+            (State::AwaitOpen, ConnectionClass::Open(_)) => {
+                let open_ok = connection::OpenOkMethod { reserved1: AmqpString::from("") };
+                let method = MethodPayload::Connection(ConnectionClass::OpenOk(open_ok));
+                self.send_method(0, method);
+                self.state = State::Open;
+            }
+            (State::Open, ConnectionClass::UpdateSecret(_)) => {
+                // Any new secret is accepted.
+                let method = MethodPayload::Connection(ConnectionClass::UpdateSecretOk);
+                self.send_method(0, method);
+            }
+            (_, ConnectionClass::Close(_)) => {
+                self.send_method(0, MethodPayload::Connection(ConnectionClass::CloseOk));
+                self.state = State::Closed;
+            }
+            (_, ConnectionClass::Blocked(_)) |
+            (_, ConnectionClass::Unblocked) => {}
+            (State::Open, _) => {
+                return Err(AmqpError::connection(
+                    ReplyCode::CommandInvalid,
+                    "unexpected connection method",
+                ))
+            }
+            _ => {
+                return Err(AmqpError::connection(
+                    ReplyCode::CommandInvalid,
+                    "unexpected method while handshaking",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_channel_method(&mut self, channel: u16, class: ChannelClass) -> AmqpResult<()> {
+        match class {
+            ChannelClass::Flow(m) => {
+                self.broker().flow(self.conn_id, channel, m.active)?;
+                let ok = channel::FlowOkMethod { active: m.active };
+                self.send_method(channel, MethodPayload::Channel(ChannelClass::FlowOk(ok)));
+            }
+            ChannelClass::Close(_) => {
+                self.forget_channel(channel);
+                self.send_method(channel, MethodPayload::Channel(ChannelClass::CloseOk));
+            }
+            // We never send Channel.Flow nor Channel.Close without closing_channels.
+            ChannelClass::FlowOk(_) |
+            ChannelClass::CloseOk => {}
+            _ => unreachable!("Validated by codec"),
+        }
+        Ok(())
+    }
+
+    fn handle_exchange_method(&mut self, channel: u16, class: ExchangeClass) -> AmqpResult<()> {
+        let (no_wait, ok) = match class {
+            ExchangeClass::Declare(m) => {
+                self.broker().declare_exchange(&m)?;
+                (m.no_wait, ExchangeClass::DeclareOk)
+            }
+            ExchangeClass::Delete(m) => {
+                self.broker().delete_exchange(&m)?;
+                (m.no_wait, ExchangeClass::DeleteOk)
+            }
+            ExchangeClass::Bind(m) => {
+                self.broker().bind_exchange(&m)?;
+                (m.no_wait, ExchangeClass::BindOk)
+            }
+            ExchangeClass::Unbind(m) => {
+                self.broker().unbind_exchange(&m)?;
+                (m.no_wait, ExchangeClass::UnbindOk)
+            }
+            _ => unreachable!("Validated by codec"),
+        };
+        if !no_wait {
+            self.send_method(channel, MethodPayload::Exchange(ok));
+        }
+        Ok(())
+    }
+
+    fn handle_queue_method(&mut self, channel: u16, class: QueueClass) -> AmqpResult<()> {
+        let conn_id = self.conn_id;
+        let (no_wait, ok) = match class {
+            QueueClass::Declare(m) => {
+                let ok = self.broker().declare_queue(conn_id, &m)?;
+                (m.no_wait, QueueClass::DeclareOk(ok))
+            }
+            QueueClass::Bind(m) => {
+                self.broker().bind_queue(conn_id, &m)?;
+                (m.no_wait, QueueClass::BindOk)
+            }
+            QueueClass::Unbind(m) => {
+                self.broker().unbind_queue(conn_id, &m)?;
+                (false, QueueClass::UnbindOk)
+            }
+            QueueClass::Purge(m) => {
+                let count = self.broker().purge_queue(conn_id, &m)?;
+                (m.no_wait, QueueClass::PurgeOk(queue::PurgeOkMethod { message_count: count }))
+            }
+            QueueClass::Delete(m) => {
+                let count = self.broker().delete_queue(conn_id, &m)?;
+                (m.no_wait, QueueClass::DeleteOk(queue::DeleteOkMethod { message_count: count }))
+            }
+            _ => unreachable!("Validated by codec"),
+        };
+        if !no_wait {
+            self.send_method(channel, MethodPayload::Queue(ok));
+        }
+        Ok(())
+    }
+
+    fn handle_basic_method(&mut self, channel: u16, class: BasicClass) -> AmqpResult<()> {
+        let conn_id = self.conn_id;
+        match class {
+            BasicClass::Qos(m) => {
+                self.broker().qos(conn_id, channel, m.prefetch_count, m.global)?;
+                self.send_method(channel, MethodPayload::Basic(BasicClass::QosOk));
+            }
+            BasicClass::Consume(m) => self.broker().consume(conn_id, channel, &m)?,
+            BasicClass::Cancel(m) => {
+                let mut broker = self.broker();
+                broker.cancel(conn_id, channel, &m.consumer_tag)?;
+                if !m.no_wait {
+                    let ok = BasicClass::CancelOk(CancelOkMethod { consumer_tag: m.consumer_tag });
+                    broker.send_method(conn_id, channel, MethodPayload::Basic(ok));
+                }
+            }
+            BasicClass::Publish(m) => {
+                if m.immediate {
+                    return Err(AmqpError::connection(
+                        ReplyCode::NotImplemented,
+                        "immediate=true",
+                    ));
+                }
+                let pending = PendingPublish {
+                    method: m,
+                    header: None,
+                    body: BytesMut::new(),
+                };
+                self.publishes.insert(channel, pending);
+            }
+            BasicClass::Get(m) => self.broker().get(conn_id, channel, &m)?,
+            BasicClass::Ack(m) => {
+                self.broker().ack(conn_id, channel, m.delivery_tag, m.multiple)?
+            }
+            BasicClass::Reject(m) => {
+                self.broker().reject(conn_id, channel, m.delivery_tag, false, m.requeue)?
+            }
+            BasicClass::Nack(m) => {
+                self.broker().reject(conn_id, channel, m.delivery_tag, m.multiple, m.requeue)?
+            }
+            BasicClass::RecoverAsync(m) => self.broker().recover(conn_id, channel, m.requeue)?,
+            BasicClass::Recover(m) => {
+                self.broker().recover(conn_id, channel, m.requeue)?;
+                self.send_method(channel, MethodPayload::Basic(BasicClass::RecoverOk));
+            }
+            // Reply to Basic.Cancel sent when a queue is deleted.
+            BasicClass::CancelOk(_) => {}
+            _ => unreachable!("Validated by codec"),
+        }
+        Ok(())
+    }
+
+    fn handle_tx_method(&mut self, channel: u16, class: TxClass) -> AmqpResult<()> {
+        let conn_id = self.conn_id;
+        let ok = match class {
+            TxClass::Select => {
+                self.broker().tx_select(conn_id, channel)?;
+                TxClass::SelectOk
+            }
+            TxClass::Commit => {
+                self.broker().tx_commit(conn_id, channel)?;
+                TxClass::CommitOk
+            }
+            TxClass::Rollback => {
+                self.broker().tx_rollback(conn_id, channel)?;
+                TxClass::RollbackOk
+            }
+            _ => unreachable!("Validated by codec"),
+        };
+        self.send_method(channel, MethodPayload::Tx(ok));
+        Ok(())
+    }
+    // }}}
+
+
+    // Content {{{
+    // Order of content frames is validated by codec.
+    fn handle_content_header(
+        &mut self,
+        channel: u16,
+        header: ContentHeaderPayload,
+    ) -> AmqpResult<()> {
+        let complete = header.body_size == 0;
+        match self.publishes.get_mut(&channel) {
+            Some(p) => p.header = Some(header),
+            // Content of a method which was discarded while closing the channel.
+            None => return Ok(()),
+        }
+        if complete { self.publish(channel) } else { Ok(()) }
+    }
+
+    fn handle_content_body(&mut self, channel: u16, body: ContentBodyPayload) -> AmqpResult<()> {
+        let complete = match self.publishes.get_mut(&channel) {
+            Some(p) => {
+                p.body.extend_from_slice(&body.bytes);
+                let body_size = p.header.as_ref().map_or(0, |h| h.body_size);
+                p.body.len() as u64 >= body_size
+            }
+            None => return Ok(()),
+        };
+        if complete { self.publish(channel) } else { Ok(()) }
+    }
+
+    fn publish(&mut self, channel: u16) -> AmqpResult<()> {
+        let p = self.publishes.remove(&channel).expect("No publish in progress");
+        let header = p.header.expect("No content header");
+        let message = Message::new(
+            p.method.exchange.clone(),
+            p.method.routing_key.clone(),
+            header.properties,
+            Bytes::from(p.body),
+        );
+        self.broker().publish(self.conn_id, channel, p.method, message)
+    }
+    // }}}
+}
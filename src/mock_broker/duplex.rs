@@ -0,0 +1,181 @@
+//! In-memory byte stream connecting a client and `MockBroker` without sockets.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+/// Make a pair of connected streams. Bytes written into one are read from the other.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let a_to_b = Arc::new(Pipe::new());
+    let b_to_a = Arc::new(Pipe::new());
+    let a = DuplexStream {
+        reader: ReadHalf::new(b_to_a.clone()),
+        writer: WriteHalf { pipe: a_to_b.clone() },
+    };
+    let b = DuplexStream {
+        reader: ReadHalf::new(a_to_b),
+        writer: WriteHalf { pipe: b_to_a },
+    };
+    (a, b)
+}
+
+
+/// One end of an in-memory connection made by `duplex`.
+///
+/// Dropping the stream (or its write half) closes the connection, so the peer reads EOF.
+#[derive(Debug)]
+pub struct DuplexStream {
+    reader: ReadHalf,
+    writer: WriteHalf,
+}
+
+
+impl DuplexStream {
+    /// Split into halves which can be used from different threads.
+    pub fn split(self) -> (ReadHalf, WriteHalf) {
+        (self.reader, self.writer)
+    }
+
+    /// Make `read` fail with `TimedOut` if nothing arrives within `timeout`.
+    /// `None` (default) means blocking forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.reader.set_read_timeout(timeout);
+    }
+}
+
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+
+#[derive(Debug)]
+pub struct ReadHalf {
+    pipe: Arc<Pipe>,
+    timeout: Option<Duration>,
+}
+
+
+impl ReadHalf {
+    fn new(pipe: Arc<Pipe>) -> ReadHalf {
+        ReadHalf {
+            pipe: pipe,
+            timeout: None,
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+}
+
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let mut state = self.pipe.state.lock().unwrap();
+        while state.buf.is_empty() && !state.closed {
+            state = match deadline {
+                None => self.pipe.readable.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                    }
+                    self.pipe.readable.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+
+        let n = buf.len().min(state.buf.len());
+        for (dst, src) in buf.iter_mut().zip(state.buf.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+
+impl Drop for ReadHalf {
+    fn drop(&mut self) {
+        self.pipe.close();
+    }
+}
+
+
+#[derive(Debug)]
+pub struct WriteHalf {
+    pipe: Arc<Pipe>,
+}
+
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.pipe.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer is closed"));
+        }
+        state.buf.extend(buf);
+        self.pipe.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+impl Drop for WriteHalf {
+    fn drop(&mut self) {
+        self.pipe.close();
+    }
+}
+
+
+/// Bytes going in one direction.
+#[derive(Debug)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+
+#[derive(Debug)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            state: Mutex::new(PipeState {
+                buf: VecDeque::new(),
+                closed: false,
+            }),
+            readable: Condvar::new(),
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
@@ -0,0 +1,906 @@
+//! In-memory AMQP 0-9-1 broker for tests (requires "mock-broker" feature).
+//!
+//! `MockBroker` implements enough of a broker on top of `Codec` to run client tests offline.
+//!
+//! - Handshake (any credential is accepted, heartbeat is disabled)
+//! - Channel open / close / flow
+//! - Exchange declare / delete / bind / unbind of every kind, routed by `routing::Router`
+//! - Queue declare / bind / unbind / purge / delete, including exclusive and auto-delete queues
+//! - Basic qos / consume / cancel / publish / get / ack / nack / reject / recover
+//! - Publisher confirms and transactions
+//! - Message TTL, max length, priority and dead lettering of `queue_store::QueueStore`
+//!
+//! Nothing is persisted. Errors are reported by `Channel.Close` / `Connection.Close` with
+//! the reply codes RabbitMQ uses.
+//!
+//! ```no_run
+//! # extern crate amqpr_codec;
+//! # use amqpr_codec::mock_broker::MockBroker;
+//! # fn main() {
+//! let broker = MockBroker::new();
+//!
+//! // Connect over TCP
+//! let addr = broker.listen().unwrap();
+//!
+//! // Or without socket
+//! let stream = broker.connect_in_memory();
+//!
+//! // Stop background threads and close the listening socket
+//! broker.shutdown();
+//! # }
+//! ```
+
+mod connection;
+mod duplex;
+mod state;
+
+pub use self::duplex::{duplex, DuplexStream, ReadHalf, WriteHalf};
+
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use self::state::Broker;
+
+
+/// Handle of a mock broker. Clones share the same exchanges and queues.
+#[derive(Clone, Debug)]
+pub struct MockBroker {
+    broker: Arc<Mutex<Broker>>,
+    /// Set by `shutdown` to stop background threads.
+    stopped: Arc<AtomicBool>,
+    /// Accept loops started by `listen`, which `shutdown` wakes up and joins.
+    listeners: Arc<Mutex<Vec<(SocketAddr, JoinHandle<()>)>>>,
+}
+
+
+/// How often messages are checked for expiry in queues without consumers.
+const EXPIRY_INTERVAL_MS: u64 = 10;
+
+
+impl Default for MockBroker {
+    fn default() -> MockBroker {
+        let broker = Arc::new(Mutex::new(Broker::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let weak = Arc::downgrade(&broker);
+        let stop = stopped.clone();
+        // Runs until `shutdown` is called or every handle and connection of this broker is
+        // dropped.
+        thread::spawn(move || while !stop.load(Ordering::SeqCst) {
+            let broker = match weak.upgrade() {
+                Some(broker) => broker,
+                None => break,
+            };
+            if let Ok(mut broker) = broker.lock() {
+                broker.expire_messages();
+            }
+            drop(broker);
+            thread::sleep(Duration::from_millis(EXPIRY_INTERVAL_MS));
+        });
+        MockBroker {
+            broker: broker,
+            stopped: stopped,
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+
+impl MockBroker {
+    pub fn new() -> MockBroker {
+        MockBroker::default()
+    }
+
+    /// Accept connections on a loopback port chosen by OS, and return its address.
+    /// Each connection is served on its own threads. The port is closed by `shutdown`.
+    pub fn listen(&self) -> io::Result<SocketAddr> {
+        if self.is_shut_down() {
+            return Err(io::Error::new(io::ErrorKind::Other, "broker is shut down"));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let broker = self.clone();
+        let handle = thread::spawn(move || for stream in listener.incoming() {
+            if broker.is_shut_down() {
+                break;
+            }
+            match stream.and_then(|s| s.try_clone().map(|w| (s, w))) {
+                Ok((reader, writer)) => {
+                    broker.serve(reader, writer);
+                }
+                Err(e) => debug!("Failed to accept connection : {}", e),
+            }
+        });
+        self.listeners.lock().unwrap().push((addr, handle));
+        Ok(addr)
+    }
+
+    /// Stop the expiry thread and close every port opened by `listen`.
+    /// Connections already accepted are served until they are closed.
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let listeners: Vec<_> = self.listeners.lock().unwrap().drain(..).collect();
+        for (addr, handle) in listeners {
+            // Wake up the accept loop so that it sees the stop flag.
+            if let Err(e) = TcpStream::connect(addr) {
+                debug!("Failed to wake up listener at {} : {}", addr, e);
+            }
+            let _ = handle.join();
+        }
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Serve a connection over any stream, starting from the protocol header sent by client.
+    /// The returned thread finishes when the connection is closed.
+    pub fn serve<R, W>(&self, reader: R, writer: W) -> JoinHandle<io::Result<()>>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let broker = self.broker.clone();
+        thread::spawn(move || connection::serve(broker, reader, writer))
+    }
+
+    /// Connect to this broker by an in-memory stream. Client should send the protocol header
+    /// first as usual.
+    pub fn connect_in_memory(&self) -> DuplexStream {
+        let (client, server) = duplex();
+        let (reader, writer) = server.split();
+        self.serve(reader, writer);
+        client
+    }
+
+    /// Number of ready (not delivered) messages in the queue, or `None` if it does not exist.
+    pub fn message_count(&self, queue: &str) -> Option<usize> {
+        self.broker.lock().unwrap().queues.get(queue).map(
+            |q| q.messages.len(),
+        )
+    }
+
+    /// Number of consumers of the queue, or `None` if it does not exist.
+    pub fn consumer_count(&self, queue: &str) -> Option<usize> {
+        self.broker.lock().unwrap().queues.get(queue).map(
+            |q| q.consumers.len(),
+        )
+    }
+
+    pub fn exchange_exists(&self, exchange: &str) -> bool {
+        self.broker.lock().unwrap().exchanges.contains_key(exchange)
+    }
+}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use bytes::{Bytes, BytesMut};
+    use tokio_io::codec::{Decoder, Encoder};
+
+    use Codec;
+    use config::DEFAULT_FRAME_MAX;
+    use args::{AmqpString, FieldArgument};
+    use frame::Frame;
+    use frame::method::MethodPayload;
+    use frame::method::basic::*;
+    use frame::method::channel::{self, ChannelClass};
+    use frame::method::connection::{self, ConnectionClass};
+    use frame::method::exchange::{self, ExchangeClass};
+    use frame::method::queue::{self, QueueClass};
+    use frame::method::tx::TxClass;
+    use frame::method::confirm::{self, ConfirmClass};
+    use frame::content_header::{ContentHeaderPayload, Properties};
+    use frame::content_body::ContentBodyPayload;
+
+
+    trait IntoMethod {
+        fn into_method(self) -> MethodPayload;
+    }
+
+    macro_rules! impl_into_method {
+        ($($t:ty => $variant:ident),*) => {
+            $(impl IntoMethod for $t {
+                fn into_method(self) -> MethodPayload {
+                    MethodPayload::$variant(self)
+                }
+            })*
+        }
+    }
+
+    impl_into_method!(ConnectionClass => Connection, ChannelClass => Channel,
+                      ExchangeClass => Exchange, QueueClass => Queue, BasicClass => Basic,
+                      TxClass => Tx, ConfirmClass => Confirm);
+
+
+    struct Client<S> {
+        stream: S,
+        codec: Codec,
+        buf: BytesMut,
+    }
+
+
+    impl<S: Read + Write> Client<S> {
+        fn connect(stream: S) -> Client<S> {
+            let (mut client, tune) = Client::start(stream);
+            client.send(0, ConnectionClass::TuneOk(connection::TuneOkMethod {
+                channel_max: tune.channel_max,
+                frame_max: tune.frame_max,
+                heartbeat: 0,
+            }));
+            client.send(0, ConnectionClass::Open(connection::OpenMethod {
+                virtual_host: AmqpString::from("/"),
+                reserved1: AmqpString::from(""),
+                reserved2: false,
+            }));
+            assert!(client.recv_method().connection().unwrap().open_ok().is_some());
+            client
+        }
+
+        /// Handshake until `Connection.Tune` is received.
+        fn start(stream: S) -> (Client<S>, connection::TuneMethod) {
+            let mut client = Client {
+                stream: stream,
+                codec: Codec::new(),
+                buf: BytesMut::new(),
+            };
+            let mut header = BytesMut::new();
+            client.codec.encode_protocol_header(&mut header);
+            client.stream.write_all(&header).unwrap();
+
+            assert!(client.recv_method().connection().unwrap().start().is_some());
+            client.send(0, ConnectionClass::StartOk(connection::StartOkMethod {
+                client_properties: HashMap::new(),
+                mechanism: AmqpString::from("PLAIN"),
+                response: AmqpString::from("\x00guest\x00guest"),
+                locale: AmqpString::from("en_US"),
+            }));
+            let tune = client.recv_method().connection().unwrap().tune().unwrap().clone();
+            (client, tune)
+        }
+
+        fn send_frame(&mut self, frame: Frame) {
+            let mut buf = BytesMut::new();
+            self.codec.encode(frame, &mut buf).unwrap();
+            self.stream.write_all(&buf).unwrap();
+        }
+
+        fn send<M: IntoMethod>(&mut self, channel: u16, method: M) {
+            self.send_frame(Frame::new_method(channel, method.into_method()));
+        }
+
+        fn recv(&mut self) -> Frame {
+            let mut chunk = [0; 4096];
+            loop {
+                if let Some(frame) = self.codec.decode(&mut self.buf).unwrap() {
+                    return frame;
+                }
+                let n = self.stream.read(&mut chunk).unwrap();
+                assert!(n > 0, "Connection is closed");
+                self.buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+
+        fn recv_method(&mut self) -> MethodPayload {
+            match self.recv().method() {
+                Some(m) => m.clone(),
+                None => panic!("Method is expected"),
+            }
+        }
+
+        /// Receive content after `Basic.Deliver` or `Basic.GetOk`.
+        fn recv_body(&mut self) -> Bytes {
+            let size = self.recv().content_header().unwrap().body_size as usize;
+            let mut body = BytesMut::new();
+            while body.len() < size {
+                body.extend_from_slice(&self.recv().content_body().unwrap().bytes);
+            }
+            body.freeze()
+        }
+
+        fn open_channel(&mut self, channel: u16) {
+            self.send(channel, ChannelClass::Open(channel::OpenMethod {
+                reserved1: AmqpString::from(""),
+            }));
+            assert!(self.recv_method().channel().unwrap().open_ok().is_some());
+        }
+
+        fn declare_queue(&mut self, channel: u16, queue: &'static str) {
+            self.declare_queue_with(channel, queue, HashMap::new());
+        }
+
+        fn declare_queue_with(
+            &mut self,
+            channel: u16,
+            queue: &'static str,
+            arguments: HashMap<AmqpString, FieldArgument>,
+        ) {
+            self.send(channel, QueueClass::Declare(queue::DeclareMethod {
+                reserved1: 0,
+                queue: AmqpString::from(queue),
+                passive: false,
+                durable: false,
+                exclusive: false,
+                auto_delete: false,
+                no_wait: false,
+                arguments: arguments,
+            }));
+            let ok = self.recv_method();
+            assert_eq!(&*ok.queue().unwrap().declare_ok().unwrap().queue, queue);
+        }
+
+        fn publish(
+            &mut self,
+            channel: u16,
+            exchange: &'static str,
+            key: &'static str,
+            body: &'static [u8],
+        ) {
+            self.publish_mandatory(channel, exchange, key, body, false);
+        }
+
+        fn publish_mandatory(
+            &mut self,
+            channel: u16,
+            exchange: &'static str,
+            key: &'static str,
+            body: &'static [u8],
+            mandatory: bool,
+        ) {
+            let publish = PublishMethod {
+                reserved1: 0,
+                exchange: AmqpString::from(exchange),
+                routing_key: AmqpString::from(key),
+                mandatory: mandatory,
+                immediate: false,
+            };
+            self.publish_with(channel, publish, Properties::new(), body);
+        }
+
+        fn publish_with(
+            &mut self,
+            channel: u16,
+            publish: PublishMethod,
+            properties: Properties,
+            body: &'static [u8],
+        ) {
+            self.send(channel, BasicClass::Publish(publish));
+            let header = ContentHeaderPayload::basic(body.len() as u64, properties);
+            self.send_frame(Frame::new_content_header(channel, header));
+            let body = ContentBodyPayload { bytes: Bytes::from_static(body) };
+            self.send_frame(Frame::new_content_body(channel, body));
+        }
+
+        fn consume(&mut self, channel: u16, queue: &'static str) -> AmqpString {
+            self.send(channel, BasicClass::Consume(ConsumeMethod {
+                reserved1: 0,
+                queue: AmqpString::from(queue),
+                consumer_tag: AmqpString::from(""),
+                no_local: false,
+                no_ack: false,
+                exclusive: false,
+                no_wait: false,
+                arguments: HashMap::new(),
+            }));
+            let ok = self.recv_method();
+            ok.basic().unwrap().consume_ok().unwrap().consumer_tag.clone()
+        }
+
+        fn qos(&mut self, channel: u16, prefetch_count: u16, global: bool) {
+            self.send(channel, BasicClass::Qos(QosMethod {
+                prefetch_size: 0,
+                prefetch_count: prefetch_count,
+                global: global,
+            }));
+            assert!(self.recv_method().basic().unwrap().qos_ok().is_some());
+        }
+
+        fn recv_deliver(&mut self) -> (DeliverMethod, Bytes) {
+            let deliver = self.recv_method().basic().unwrap().deliver().unwrap().clone();
+            (deliver, self.recv_body())
+        }
+
+        fn ack(&mut self, channel: u16, delivery_tag: u64) {
+            self.send(channel, BasicClass::Ack(AckMethod {
+                delivery_tag: delivery_tag,
+                multiple: false,
+            }));
+        }
+
+        /// Make sure all methods sent before are handled.
+        fn sync(&mut self, channel: u16) {
+            self.send(channel, ExchangeClass::Declare(exchange::DeclareMethod {
+                reserved1: 0,
+                exchange: AmqpString::from("amq.direct"),
+                typ: AmqpString::from("direct"),
+                passive: true,
+                durable: true,
+                auto_delete: false,
+                internal: false,
+                no_wait: false,
+                arguments: HashMap::new(),
+            }));
+            assert!(self.recv_method().exchange().unwrap().declare_ok().is_some());
+        }
+    }
+
+
+    fn connect(broker: &MockBroker) -> Client<DuplexStream> {
+        let mut stream = broker.connect_in_memory();
+        stream.set_read_timeout(Some(Duration::from_secs(5)));
+        Client::connect(stream)
+    }
+
+
+    #[test]
+    fn publish_and_get() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+
+        client.publish(1, "", "q", b"hello");
+        client.send(1, BasicClass::Get(GetMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            no_ack: false,
+        }));
+
+        let get_ok = client.recv_method().basic().unwrap().get_ok().unwrap().clone();
+        assert_eq!((get_ok.delivery_tag, get_ok.message_count), (1, 0));
+        assert_eq!(&*get_ok.routing_key, "q");
+        assert_eq!(client.recv_body(), Bytes::from_static(b"hello"));
+
+        client.send(1, BasicClass::Get(GetMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            no_ack: false,
+        }));
+        assert!(client.recv_method().basic().unwrap().get_empty().is_some());
+        client.ack(1, 1);
+        client.sync(1);
+        assert_eq!(broker.message_count("q"), Some(0));
+    }
+
+    #[test]
+    fn consume_with_prefetch() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        client.send(1, BasicClass::Qos(QosMethod {
+            prefetch_size: 0,
+            prefetch_count: 1,
+            global: false,
+        }));
+        assert!(client.recv_method().basic().unwrap().qos_ok().is_some());
+        client.publish(1, "", "q", b"one");
+        client.publish(1, "", "q", b"two");
+
+        client.consume(1, "q");
+        let (deliver, body) = client.recv_deliver();
+        assert_eq!(body, Bytes::from_static(b"one"));
+        client.sync(1);
+        assert_eq!(broker.message_count("q"), Some(1));
+
+        client.ack(1, deliver.delivery_tag);
+        let (_, body) = client.recv_deliver();
+        assert_eq!(body, Bytes::from_static(b"two"));
+    }
+
+    #[test]
+    fn prefetch_per_consumer_and_per_channel() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        client.qos(1, 1, false);
+        for body in &[b"one", b"two", b"six", b"ten"] {
+            client.publish(1, "", "q", *body);
+        }
+
+        // Each consumer gets its own prefetch window.
+        let first = client.consume(1, "q");
+        let (deliver, _) = client.recv_deliver();
+        assert_eq!(deliver.consumer_tag, first);
+        let second = client.consume(1, "q");
+        client.recv_deliver();
+        client.sync(1);
+        assert_eq!(broker.message_count("q"), Some(2));
+
+        client.ack(1, deliver.delivery_tag);
+        let (deliver, _) = client.recv_deliver();
+        assert_eq!(deliver.consumer_tag, first);
+        assert_ne!(first, second);
+
+        // Global prefetch limits the channel as a whole.
+        client.qos(1, 2, true);
+        client.qos(1, 0, false);
+        client.sync(1);
+        assert_eq!(broker.message_count("q"), Some(1));
+    }
+
+    #[test]
+    fn nack_requeues_message_as_redelivered() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        client.publish(1, "", "q", b"body");
+        client.consume(1, "q");
+
+        let (deliver, _) = client.recv_deliver();
+        assert!(!deliver.redeliverd);
+        client.send(1, BasicClass::Nack(NackMethod {
+            delivery_tag: deliver.delivery_tag,
+            multiple: false,
+            requeue: true,
+        }));
+
+        let (deliver, _) = client.recv_deliver();
+        assert!(deliver.redeliverd);
+        assert_eq!(deliver.delivery_tag, 2);
+    }
+
+    #[test]
+    fn route_by_topic_and_return_unroutable() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        client.send(1, ExchangeClass::Declare(exchange::DeclareMethod {
+            reserved1: 0,
+            exchange: AmqpString::from("logs"),
+            typ: AmqpString::from("topic"),
+            passive: false,
+            durable: false,
+            auto_delete: false,
+            internal: false,
+            no_wait: false,
+            arguments: HashMap::new(),
+        }));
+        assert!(client.recv_method().exchange().unwrap().declare_ok().is_some());
+        client.send(1, QueueClass::Bind(queue::BindMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            exchange: AmqpString::from("logs"),
+            routing_key: AmqpString::from("app.*.error"),
+            no_wait: false,
+            arguments: HashMap::new(),
+        }));
+        assert!(client.recv_method().queue().unwrap().bind_ok().is_some());
+
+        client.publish(1, "logs", "app.web.error", b"routed");
+        client.publish_mandatory(1, "logs", "app.web.info", b"unroutable", true);
+
+        let ret = client.recv_method().basic().unwrap().return_().unwrap().clone();
+        assert_eq!((ret.reply_code, &*ret.routing_key), (312, "app.web.info"));
+        assert_eq!(client.recv_body(), Bytes::from_static(b"unroutable"));
+        assert_eq!(broker.message_count("q"), Some(1));
+    }
+
+    #[test]
+    fn route_through_exchange_to_exchange_binding() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        let mut arguments = HashMap::new();
+        let pdf = FieldArgument::LongString(AmqpString::from("pdf"));
+        arguments.insert(AmqpString::from("format"), pdf.clone());
+        client.send(1, QueueClass::Bind(queue::BindMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            exchange: AmqpString::from("amq.headers"),
+            routing_key: AmqpString::from(""),
+            no_wait: false,
+            arguments: arguments.clone(),
+        }));
+        assert!(client.recv_method().queue().unwrap().bind_ok().is_some());
+        client.send(1, ExchangeClass::Bind(exchange::BindMethod {
+            reserved1: 0,
+            destination: AmqpString::from("amq.headers"),
+            source: AmqpString::from("amq.fanout"),
+            routing_key: AmqpString::from(""),
+            no_wait: false,
+            arguments: HashMap::new(),
+        }));
+        assert!(client.recv_method().exchange().unwrap().bind_ok().is_some());
+
+        let publish = PublishMethod {
+            reserved1: 0,
+            exchange: AmqpString::from("amq.fanout"),
+            routing_key: AmqpString::from("any"),
+            mandatory: false,
+            immediate: false,
+        };
+        let mut properties = Properties::new();
+        client.publish_with(1, publish.clone(), properties.clone(), b"no headers");
+        properties.headers = Some(arguments);
+        client.publish_with(1, publish, properties, b"pdf");
+        client.sync(1);
+
+        assert_eq!(broker.message_count("q"), Some(1));
+    }
+
+    #[test]
+    fn channel_error_closes_only_the_channel() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.send(1, QueueClass::Declare(queue::DeclareMethod {
+            reserved1: 0,
+            queue: AmqpString::from("missing"),
+            passive: true,
+            durable: false,
+            exclusive: false,
+            auto_delete: false,
+            no_wait: false,
+            arguments: HashMap::new(),
+        }));
+
+        let close = client.recv_method().channel().unwrap().close().unwrap().clone();
+        assert_eq!((close.reply_code, close.class_id, close.method_id), (404, 50, 10));
+        client.send(1, ChannelClass::CloseOk);
+
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+    }
+
+    #[test]
+    fn out_of_range_frame_max_is_not_allowed() {
+        let broker = MockBroker::new();
+        for &frame_max in [0, 1, 8, 4095, DEFAULT_FRAME_MAX + 1].iter() {
+            let mut stream = broker.connect_in_memory();
+            stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let (mut client, tune) = Client::start(stream);
+            client.send(0, ConnectionClass::TuneOk(connection::TuneOkMethod {
+                channel_max: tune.channel_max,
+                frame_max: frame_max,
+                heartbeat: 0,
+            }));
+
+            let close = client.recv_method().connection().unwrap().close().unwrap().clone();
+            assert_eq!(close.reply_code, 530);
+        }
+
+        // The broker is still usable.
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+    }
+
+    #[test]
+    fn update_secret_after_open() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.send(0, ConnectionClass::UpdateSecret(connection::UpdateSecretMethod {
+            new_secret: AmqpString::from("token"),
+            reason: AmqpString::from("refresh"),
+        }));
+        assert!(client.recv_method().connection().unwrap().update_secret_ok().is_some());
+
+        // The connection is still usable.
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+    }
+
+    #[test]
+    fn transaction_publishes_on_commit() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        client.send(1, TxClass::Select);
+        assert!(client.recv_method().tx().unwrap().select_ok().is_some());
+
+        client.publish(1, "", "q", b"discarded");
+        client.send(1, TxClass::Rollback);
+        assert!(client.recv_method().tx().unwrap().rollback_ok().is_some());
+        assert_eq!(broker.message_count("q"), Some(0));
+
+        client.publish(1, "", "q", b"committed");
+        client.send(1, TxClass::Commit);
+        assert!(client.recv_method().tx().unwrap().commit_ok().is_some());
+        assert_eq!(broker.message_count("q"), Some(1));
+    }
+
+    #[test]
+    fn connection_close_requeues_unacked_messages() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        client.publish(1, "", "q", b"body");
+        client.consume(1, "q");
+        client.recv_deliver();
+
+        client.send(0, ConnectionClass::Close(connection::CloseMethod {
+            reply_code: 200,
+            reply_text: AmqpString::from("bye"),
+            class_id: 0,
+            method_id: 0,
+        }));
+        assert!(client.recv_method().connection().unwrap().close_ok().is_some());
+        drop(client);
+
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.consume(1, "q");
+        assert!(client.recv_deliver().0.redeliverd);
+        assert_eq!(broker.consumer_count("q"), Some(1));
+    }
+
+    fn dead_letter_to(
+        queue: &'static str,
+        ttl: Option<i32>,
+    ) -> HashMap<AmqpString, FieldArgument> {
+        let mut arguments = HashMap::new();
+        let exchange = FieldArgument::LongString(AmqpString::from(""));
+        let key = FieldArgument::LongString(AmqpString::from(queue));
+        arguments.insert(AmqpString::from("x-dead-letter-exchange"), exchange);
+        arguments.insert(AmqpString::from("x-dead-letter-routing-key"), key);
+        if let Some(ttl) = ttl {
+            arguments.insert(AmqpString::from("x-message-ttl"), FieldArgument::SignedLong(ttl));
+        }
+        arguments
+    }
+
+    #[test]
+    fn retry_through_dead_letter_queue_with_ttl() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue_with(1, "work", dead_letter_to("wait", None));
+        client.declare_queue_with(1, "wait", dead_letter_to("work", Some(20)));
+        client.publish(1, "", "work", b"job");
+        client.consume(1, "work");
+
+        let (deliver, _) = client.recv_deliver();
+        client.send(1, BasicClass::Reject(RejectMethod {
+            delivery_tag: deliver.delivery_tag,
+            requeue: false,
+        }));
+
+        // Expired in "wait" and dead-lettered back to "work".
+        let deliver = client.recv_method().basic().unwrap().deliver().unwrap().clone();
+        assert_eq!((&*deliver.exchange, &*deliver.routing_key), ("", "work"));
+        assert!(!deliver.redeliverd);
+        let header = client.recv().content_header().unwrap().clone();
+        let headers = header.properties.headers.clone().unwrap();
+        let reasons: Vec<(String, String)> = match headers[&AmqpString::from("x-death")] {
+            FieldArgument::Array(ref deaths) => {
+                deaths
+                    .iter()
+                    .map(|d| match *d {
+                        FieldArgument::NestedTable(ref t) => {
+                            let field = |name: &'static str| match t[&AmqpString::from(name)] {
+                                FieldArgument::LongString(ref s) => s.to_string(),
+                                ref v => panic!("Unexpected field {:?}", v),
+                            };
+                            (field("queue"), field("reason"))
+                        }
+                        ref d => panic!("Unexpected death {:?}", d),
+                    })
+                    .collect()
+            }
+            ref h => panic!("Unexpected x-death {:?}", h),
+        };
+        assert_eq!(
+            reasons,
+            vec![
+                ("wait".to_string(), "expired".to_string()),
+                ("work".to_string(), "rejected".to_string()),
+            ]
+        );
+        assert_eq!(broker.message_count("wait"), Some(0));
+    }
+
+    #[test]
+    fn reject_publish_nacks_in_confirm_mode() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        let mut arguments = HashMap::new();
+        arguments.insert(AmqpString::from("x-max-length"), FieldArgument::SignedLong(1));
+        arguments.insert(
+            AmqpString::from("x-overflow"),
+            FieldArgument::LongString(AmqpString::from("reject-publish")),
+        );
+        client.declare_queue_with(1, "q", arguments);
+        client.send(1, ConfirmClass::Select(confirm::SelectMethod { no_wait: false }));
+        assert!(client.recv_method().confirm().is_some());
+
+        client.publish(1, "", "q", b"one");
+        client.publish(1, "", "q", b"two");
+        let ack = client.recv_method().basic().unwrap().ack().unwrap().clone();
+        assert_eq!(ack.delivery_tag, 1);
+        let nack = client.recv_method().basic().unwrap().nack().unwrap().clone();
+        assert_eq!(nack.delivery_tag, 2);
+        assert_eq!(broker.message_count("q"), Some(1));
+    }
+
+    #[test]
+    fn invalid_expiration_closes_channel() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        let mut properties = Properties::new();
+        properties.expiration = Some(AmqpString::from("soon"));
+        let publish = PublishMethod {
+            reserved1: 0,
+            exchange: AmqpString::from(""),
+            routing_key: AmqpString::from("q"),
+            mandatory: false,
+            immediate: false,
+        };
+        client.publish_with(1, publish, properties, b"body");
+
+        let close = client.recv_method().channel().unwrap().close().unwrap().clone();
+        assert_eq!(close.reply_code, 406);
+        assert_eq!(broker.message_count("q"), Some(0));
+    }
+
+    #[test]
+    fn zero_ttl_is_delivered_only_immediately() {
+        let broker = MockBroker::new();
+        let mut client = connect(&broker);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        let mut properties = Properties::new();
+        properties.expiration = Some(AmqpString::from("0"));
+        let publish = PublishMethod {
+            reserved1: 0,
+            exchange: AmqpString::from(""),
+            routing_key: AmqpString::from("q"),
+            mandatory: false,
+            immediate: false,
+        };
+
+        // Nobody can take it now.
+        client.publish_with(1, publish.clone(), properties.clone(), b"expired");
+        client.send(1, BasicClass::Get(GetMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            no_ack: false,
+        }));
+        assert!(client.recv_method().basic().unwrap().get_empty().is_some());
+
+        client.consume(1, "q");
+        client.publish_with(1, publish, properties, b"delivered");
+        let (_, body) = client.recv_deliver();
+        assert_eq!(body, Bytes::from_static(b"delivered"));
+    }
+
+    #[test]
+    fn serve_over_tcp() {
+        let broker = MockBroker::new();
+        let addr = broker.listen().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut client = Client::connect(stream);
+        client.open_channel(1);
+        client.declare_queue(1, "q");
+        assert!(broker.exchange_exists("amq.direct"));
+
+        // The connection outlives the listener.
+        broker.shutdown();
+        assert!(TcpStream::connect(addr).is_err());
+        assert!(broker.listen().is_err());
+        client.declare_queue(1, "r");
+    }
+}
+// }}}
@@ -0,0 +1,1259 @@
+//! Exchanges, queues and channels shared by all connections of a `MockBroker`.
+//!
+//! Every operation runs while the broker lock is held, and frames are queued into the writer
+//! thread of each connection in the same critical section. So frames of one delivery are
+//! never interleaved with other frames of the same channel.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use args::AmqpString;
+use config::DEFAULT_FRAME_MAX;
+use errors::ReplyCode;
+use frame::Frame;
+use frame::method::MethodPayload;
+use frame::method::basic::{BasicClass, CancelMethod, ConsumeMethod, ConsumeOkMethod,
+                           DeliverMethod, GetEmptyMethod, GetMethod, GetOkMethod, AckMethod,
+                           NackMethod, PublishMethod, ReturnMethod};
+use frame::method::exchange;
+use frame::method::queue;
+use frame::content_header::ContentHeaderPayload;
+use frame::content_body::ContentBodyPayload;
+use queue_store::{parse_expiration, DeadLetter, DeadLetterReason, QueueArguments, QueueError,
+                  QueueStore};
+use routing::{ExchangeKind, Router, RoutingError};
+
+pub use queue_store::Message;
+
+
+// Reply codes which are not frame level errors, so `ReplyCode` does not have them.
+pub const NO_ROUTE: u16 = 312;
+pub const ACCESS_REFUSED: u16 = 403;
+pub const NOT_FOUND: u16 = 404;
+pub const RESOURCE_LOCKED: u16 = 405;
+pub const PRECONDITION_FAILED: u16 = 406;
+
+/// Smallest frame_max which a peer can negotiate ("frame-min-size" in the spec).
+pub const FRAME_MIN_SIZE: u32 = 4096;
+
+
+/// Error which closes a channel or the whole connection.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum AmqpError {
+    Channel(u16, String),
+    Connection(u16, String),
+}
+
+
+impl AmqpError {
+    pub fn not_found(what: &str, name: &str) -> AmqpError {
+        AmqpError::Channel(
+            NOT_FOUND,
+            format!("NOT_FOUND - no {} '{}' in vhost '/'", what, name),
+        )
+    }
+
+    pub fn precondition_failed(text: String) -> AmqpError {
+        AmqpError::Channel(PRECONDITION_FAILED, format!("PRECONDITION_FAILED - {}", text))
+    }
+
+    pub fn access_refused(text: String) -> AmqpError {
+        AmqpError::Channel(ACCESS_REFUSED, format!("ACCESS_REFUSED - {}", text))
+    }
+
+    pub fn connection(code: ReplyCode, text: &str) -> AmqpError {
+        AmqpError::Connection(code.code(), format!("{} - {}", code.name(), text))
+    }
+}
+
+
+impl From<RoutingError> for AmqpError {
+    fn from(err: RoutingError) -> AmqpError {
+        match err {
+            RoutingError::ExchangeNotFound(ref name) => AmqpError::not_found("exchange", name),
+            RoutingError::QueueNotFound(ref name) => AmqpError::not_found("queue", name),
+            RoutingError::DefaultExchange => AmqpError::access_refused(err.to_string()),
+            _ => AmqpError::precondition_failed(err.to_string()),
+        }
+    }
+}
+
+
+impl From<QueueError> for AmqpError {
+    fn from(err: QueueError) -> AmqpError {
+        AmqpError::precondition_failed(err.to_string())
+    }
+}
+
+
+pub type AmqpResult<T> = Result<T, AmqpError>;
+
+
+fn inequivalent(arg: &str, what: &str, name: &str, received: bool) -> AmqpError {
+    AmqpError::precondition_failed(format!(
+        "inequivalent arg '{}' for {} '{}' in vhost '/': received '{}' but current is '{}'",
+        arg,
+        what,
+        name,
+        received,
+        !received
+    ))
+}
+
+
+
+// Message {{{
+/// Frames of `method` followed by the message as content.
+fn content_frames(
+    message: &Message,
+    channel: u16,
+    method: BasicClass,
+    frame_max: u32,
+) -> Vec<Frame> {
+    let size = message.body.len() as u64;
+    let header = ContentHeaderPayload::basic(size, message.properties.clone());
+    let mut frames = vec![
+        Frame::new_method(channel, MethodPayload::Basic(method)),
+        Frame::new_content_header(channel, header),
+    ];
+
+    // 8 bytes are used by frame header and frame end.
+    // Connection.TuneOk rejects frame_max out of range, so the clamp is only a safeguard.
+    let max_size = frame_max.max(FRAME_MIN_SIZE) as usize - 8;
+    let mut start = 0;
+    while start < message.body.len() {
+        let end = (start + max_size).min(message.body.len());
+        let body = ContentBodyPayload { bytes: message.body.slice(start, end) };
+        frames.push(Frame::new_content_body(channel, body));
+        start = end;
+    }
+    frames
+}
+// }}}
+
+
+
+// Exchange {{{
+/// Settings of an exchange. Its bindings are held by `Router`.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub kind: ExchangeKind,
+    pub durable: bool,
+    pub auto_delete: bool,
+    pub internal: bool,
+    /// Whether any binding has been made from this exchange. An auto-delete exchange is
+    /// deleted when it loses its last binding, not before its first one.
+    bound: bool,
+}
+
+
+impl Exchange {
+    fn new(kind: ExchangeKind, durable: bool) -> Exchange {
+        Exchange {
+            kind: kind,
+            durable: durable,
+            auto_delete: false,
+            internal: false,
+            bound: false,
+        }
+    }
+}
+// }}}
+
+
+
+// Queue {{{
+#[derive(Clone, Debug)]
+pub struct Queue {
+    pub durable: bool,
+    /// Connection which declared this exclusive queue.
+    pub exclusive_owner: Option<u64>,
+    pub auto_delete: bool,
+    pub messages: QueueStore,
+    pub consumers: Vec<Consumer>,
+    /// Index of the consumer which gets the next message (round robin).
+    next_consumer: usize,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Consumer {
+    pub tag: String,
+    pub conn_id: u64,
+    pub channel: u16,
+    pub no_ack: bool,
+    pub exclusive: bool,
+}
+
+
+impl Queue {
+    fn new(name: &str, m: &queue::DeclareMethod, args: QueueArguments, conn_id: u64) -> Queue {
+        Queue {
+            durable: m.durable,
+            exclusive_owner: if m.exclusive { Some(conn_id) } else { None },
+            auto_delete: m.auto_delete,
+            messages: QueueStore::new(name, args),
+            consumers: Vec::new(),
+            next_consumer: 0,
+        }
+    }
+}
+// }}}
+
+
+
+// Connection and Channel {{{
+#[derive(Debug)]
+pub struct Connection {
+    out: Sender<Frame>,
+    /// Negotiated by `Connection.TuneOk`. Content bodies are split into this size.
+    pub frame_max: u32,
+    channels: HashMap<u16, Channel>,
+}
+
+
+impl Connection {
+    fn send(&self, frames: Vec<Frame>) {
+        for frame in frames {
+            // Writer thread has gone if the connection is being closed. Nothing to do then.
+            let _ = self.out.send(frame);
+        }
+    }
+}
+
+
+#[derive(Debug)]
+struct Channel {
+    /// Whether `Channel.Flow` allows deliveries.
+    active: bool,
+    /// Limit of unacked messages per consumer, set by `Basic.Qos` with `global` false.
+    prefetch_count: u16,
+    /// Limit of unacked messages on the whole channel, set by `Basic.Qos` with `global` true.
+    global_prefetch_count: u16,
+    last_delivery_tag: u64,
+    unacked: BTreeMap<u64, Unacked>,
+    /// Consumer tag => queue name.
+    consumers: HashMap<String, String>,
+    /// Sequence number of the last publish in confirm mode, or `None` if not in confirm mode.
+    confirm: Option<u64>,
+    /// Operations waiting for `Tx.Commit`, or `None` if not in tx mode.
+    tx: Option<Vec<TxOp>>,
+}
+
+
+#[derive(Debug)]
+struct Unacked {
+    queue: String,
+    /// Tag of the consumer the message was delivered to, or `None` for `Basic.Get`.
+    consumer: Option<String>,
+    message: Message,
+}
+
+
+#[derive(Debug)]
+enum TxOp {
+    Publish(PublishMethod, Message),
+    Ack(u64, bool),
+    Reject(u64, bool, bool),
+}
+
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            active: true,
+            prefetch_count: 0,
+            global_prefetch_count: 0,
+            last_delivery_tag: 0,
+            unacked: BTreeMap::new(),
+            consumers: HashMap::new(),
+            confirm: None,
+            tx: None,
+        }
+    }
+
+    fn can_deliver(&self, consumer: &Consumer) -> bool {
+        if !self.active {
+            return false;
+        }
+        if consumer.no_ack {
+            return true;
+        }
+        let within_global = self.global_prefetch_count == 0 ||
+            self.unacked.len() < self.global_prefetch_count as usize;
+        let within_consumer = self.prefetch_count == 0 ||
+            self.unacked
+                .values()
+                .filter(|u| u.consumer.as_ref() == Some(&consumer.tag))
+                .count() < self.prefetch_count as usize;
+        within_global && within_consumer
+    }
+
+    fn next_delivery_tag(&mut self) -> u64 {
+        self.last_delivery_tag += 1;
+        self.last_delivery_tag
+    }
+
+    /// Remove messages acknowledged by `delivery_tag` and `multiple`.
+    /// Zero with `multiple` means all unacked messages.
+    fn take_unacked(&mut self, delivery_tag: u64, multiple: bool) -> AmqpResult<Vec<Unacked>> {
+        if (delivery_tag != 0 || !multiple) && !self.unacked.contains_key(&delivery_tag) {
+            return Err(AmqpError::precondition_failed(
+                format!("unknown delivery tag {}", delivery_tag),
+            ));
+        }
+
+        let tags: Vec<u64> = if multiple {
+            self.unacked
+                .keys()
+                .cloned()
+                .filter(|&t| delivery_tag == 0 || t <= delivery_tag)
+                .collect()
+        } else {
+            vec![delivery_tag]
+        };
+        Ok(tags.iter().filter_map(|t| self.unacked.remove(t)).collect())
+    }
+}
+// }}}
+
+
+
+// Broker {{{
+#[derive(Debug)]
+pub struct Broker {
+    pub exchanges: HashMap<String, Exchange>,
+    pub queues: HashMap<String, Queue>,
+    router: Router,
+    connections: HashMap<u64, Connection>,
+    last_id: u64,
+}
+
+
+impl Default for Broker {
+    fn default() -> Broker {
+        let router = Router::with_default_exchanges();
+        let exchanges = ["", "amq.direct", "amq.fanout", "amq.topic", "amq.headers", "amq.match"]
+            .iter()
+            .map(|&name| {
+                let kind = router.exchange_kind(name).unwrap();
+                (name.into(), Exchange::new(kind, true))
+            })
+            .collect();
+        Broker {
+            exchanges: exchanges,
+            queues: HashMap::new(),
+            router: router,
+            connections: HashMap::new(),
+            last_id: 0,
+        }
+    }
+}
+
+
+impl Broker {
+    fn next_id(&mut self) -> u64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    pub fn add_connection(&mut self, out: Sender<Frame>) -> u64 {
+        let id = self.next_id();
+        let conn = Connection {
+            out: out,
+            frame_max: DEFAULT_FRAME_MAX,
+            channels: HashMap::new(),
+        };
+        self.connections.insert(id, conn);
+        id
+    }
+
+    /// Close all channels of the connection, and delete its exclusive queues.
+    pub fn remove_connection(&mut self, conn_id: u64) {
+        let channels: Vec<u16> = match self.connections.get(&conn_id) {
+            Some(conn) => conn.channels.keys().cloned().collect(),
+            None => return,
+        };
+        for ch in channels {
+            self.close_channel(conn_id, ch);
+        }
+        self.connections.remove(&conn_id);
+
+        let exclusive: Vec<String> = self.queues
+            .iter()
+            .filter(|&(_, q)| q.exclusive_owner == Some(conn_id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in exclusive {
+            self.remove_queue(&name);
+        }
+    }
+
+    pub fn connection_mut(&mut self, conn_id: u64) -> Option<&mut Connection> {
+        self.connections.get_mut(&conn_id)
+    }
+
+    pub fn send(&self, conn_id: u64, frames: Vec<Frame>) {
+        if let Some(conn) = self.connections.get(&conn_id) {
+            conn.send(frames);
+        }
+    }
+
+    pub fn send_method(&self, conn_id: u64, channel: u16, method: MethodPayload) {
+        self.send(conn_id, vec![Frame::new_method(channel, method)]);
+    }
+
+    fn channel_mut(&mut self, conn_id: u64, channel: u16) -> AmqpResult<&mut Channel> {
+        self.connections
+            .get_mut(&conn_id)
+            .and_then(|c| c.channels.get_mut(&channel))
+            .ok_or_else(|| {
+                AmqpError::connection(ReplyCode::ChannelError, "expected 'channel.open'")
+            })
+    }
+
+    pub fn is_channel_open(&self, conn_id: u64, channel: u16) -> bool {
+        self.connections.get(&conn_id).map_or(false, |c| {
+            c.channels.contains_key(&channel)
+        })
+    }
+
+
+    // Channel methods {{{
+    pub fn open_channel(&mut self, conn_id: u64, channel: u16) -> AmqpResult<()> {
+        let conn = self.connections.get_mut(&conn_id).expect("Unknown connection");
+        if conn.channels.contains_key(&channel) {
+            return Err(AmqpError::connection(
+                ReplyCode::ChannelError,
+                "second 'channel.open' seen",
+            ));
+        }
+        conn.channels.insert(channel, Channel::new());
+        Ok(())
+    }
+
+    /// Forget the channel. Its unacked messages are requeued and its consumers are cancelled.
+    pub fn close_channel(&mut self, conn_id: u64, channel: u16) {
+        let ch = match self.connections.get_mut(&conn_id).and_then(|c| {
+            c.channels.remove(&channel)
+        }) {
+            Some(ch) => ch,
+            None => return,
+        };
+
+        for (_, queue) in ch.consumers.iter() {
+            self.remove_consumers(queue, |c| c.conn_id == conn_id && c.channel == channel);
+        }
+        self.requeue(ch.unacked.into_iter().map(|(_, u)| u).collect());
+    }
+
+    pub fn flow(&mut self, conn_id: u64, channel: u16, active: bool) -> AmqpResult<()> {
+        self.channel_mut(conn_id, channel)?.active = active;
+        self.dispatch_all();
+        Ok(())
+    }
+
+    /// `Basic.Qos`. As RabbitMQ does, `global` false limits each consumer on the channel
+    /// and `global` true limits the channel as a whole.
+    pub fn qos(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        prefetch_count: u16,
+        global: bool,
+    ) -> AmqpResult<()> {
+        let ch = self.channel_mut(conn_id, channel)?;
+        if global {
+            ch.global_prefetch_count = prefetch_count;
+        } else {
+            ch.prefetch_count = prefetch_count;
+        }
+        self.dispatch_all();
+        Ok(())
+    }
+
+    pub fn confirm_select(&mut self, conn_id: u64, channel: u16) -> AmqpResult<()> {
+        let ch = self.channel_mut(conn_id, channel)?;
+        if ch.tx.is_some() {
+            return Err(AmqpError::precondition_failed(
+                "cannot switch from tx to confirm mode".into(),
+            ));
+        }
+        ch.confirm = Some(ch.confirm.unwrap_or(0));
+        Ok(())
+    }
+    // }}}
+
+
+    // Exchange methods {{{
+    pub fn declare_exchange(&mut self, m: &exchange::DeclareMethod) -> AmqpResult<()> {
+        let name: &str = &m.exchange;
+        if let Some(ex) = self.exchanges.get(name) {
+            if m.passive {
+                return Ok(());
+            }
+            if ExchangeKind::from_name(&m.typ) != Some(ex.kind) {
+                return Err(AmqpError::precondition_failed(format!(
+                    "inequivalent arg 'type' for exchange '{}' in vhost '/': \
+                     received '{}'",
+                    name,
+                    &*m.typ
+                )));
+            }
+            if m.durable != ex.durable {
+                return Err(inequivalent("durable", "exchange", name, m.durable));
+            }
+            return Ok(());
+        }
+
+        if m.passive {
+            return Err(AmqpError::not_found("exchange", name));
+        }
+        if name.starts_with("amq.") {
+            return Err(AmqpError::access_refused(format!(
+                "exchange name '{}' contains reserved prefix 'amq.*'",
+                name
+            )));
+        }
+        let kind = ExchangeKind::from_name(&m.typ).ok_or_else(|| {
+            AmqpError::connection(
+                ReplyCode::CommandInvalid,
+                &format!("unknown exchange type '{}'", &*m.typ),
+            )
+        })?;
+
+        self.router.declare_exchange(name, kind)?;
+        let mut ex = Exchange::new(kind, m.durable);
+        ex.auto_delete = m.auto_delete;
+        ex.internal = m.internal;
+        self.exchanges.insert(name.into(), ex);
+        Ok(())
+    }
+
+    pub fn delete_exchange(&mut self, m: &exchange::DeleteMethod) -> AmqpResult<()> {
+        let name: &str = &m.exchange;
+        if name == "" || name.starts_with("amq.") {
+            return Err(AmqpError::access_refused(
+                format!("operation not permitted on exchange '{}'", name),
+            ));
+        }
+        if m.if_unused && self.router.bindings(name).map_or(false, |b| !b.is_empty()) {
+            return Err(AmqpError::precondition_failed(
+                format!("exchange '{}' in vhost '/' in use", name),
+            ));
+        }
+        // Deleting a missing exchange succeeds as RabbitMQ does.
+        self.exchanges.remove(name);
+        self.router.delete_exchange(name)?;
+        self.delete_unused_exchanges();
+        Ok(())
+    }
+
+    pub fn bind_exchange(&mut self, m: &exchange::BindMethod) -> AmqpResult<()> {
+        self.router.bind_exchange(m)?;
+        self.exchanges.get_mut(&*m.source).unwrap().bound = true;
+        Ok(())
+    }
+
+    pub fn unbind_exchange(&mut self, m: &exchange::UnbindMethod) -> AmqpResult<()> {
+        self.router.unbind_exchange(m)?;
+        self.delete_unused_exchanges();
+        Ok(())
+    }
+
+    /// Delete auto-delete exchanges which have lost their last binding.
+    fn delete_unused_exchanges(&mut self) {
+        loop {
+            let unused: Vec<String> = {
+                let router = &self.router;
+                self.exchanges
+                    .iter()
+                    .filter(|&(name, e)| {
+                        e.auto_delete && e.bound &&
+                            router.bindings(name).map_or(true, |b| b.is_empty())
+                    })
+                    .map(|(name, _)| name.clone())
+                    .collect()
+            };
+            if unused.is_empty() {
+                return;
+            }
+            // Bindings to the deleted exchanges are removed too, which may make others unused.
+            for name in unused {
+                self.exchanges.remove(&name);
+                let _ = self.router.delete_exchange(&name);
+            }
+        }
+    }
+    // }}}
+
+
+    // Queue methods {{{
+    pub fn declare_queue(
+        &mut self,
+        conn_id: u64,
+        m: &queue::DeclareMethod,
+    ) -> AmqpResult<queue::DeclareOkMethod> {
+        let name = if m.queue.is_empty() {
+            format!("amq.gen-{}", self.next_id())
+        } else {
+            m.queue.to_string()
+        };
+
+        if self.queues.contains_key(&name) {
+            let q = self.queue_checked(conn_id, &name)?;
+            if !m.passive {
+                if m.durable != q.durable {
+                    return Err(inequivalent("durable", "queue", &name, m.durable));
+                }
+                if m.exclusive != q.exclusive_owner.is_some() {
+                    return Err(inequivalent("exclusive", "queue", &name, m.exclusive));
+                }
+                if m.auto_delete != q.auto_delete {
+                    return Err(inequivalent("auto_delete", "queue", &name, m.auto_delete));
+                }
+                if QueueArguments::from_table(&m.arguments)? != *q.messages.arguments() {
+                    return Err(AmqpError::precondition_failed(format!(
+                        "inequivalent arguments for queue '{}' in vhost '/'",
+                        name
+                    )));
+                }
+            }
+        } else {
+            if m.passive {
+                return Err(AmqpError::not_found("queue", &name));
+            }
+            if !m.queue.is_empty() && name.starts_with("amq.") {
+                return Err(AmqpError::access_refused(format!(
+                    "queue name '{}' contains reserved prefix 'amq.*'",
+                    name
+                )));
+            }
+            let args = QueueArguments::from_table(&m.arguments)?;
+            self.queues.insert(name.clone(), Queue::new(&name, m, args, conn_id));
+            self.router.declare_queue(&name);
+        }
+
+        let q = &self.queues[&name];
+        Ok(queue::DeclareOkMethod {
+            queue: AmqpString::from(name.clone()),
+            message_count: q.messages.len() as u32,
+            consumer_count: q.consumers.len() as u32,
+        })
+    }
+
+    /// Find a queue which the connection can use.
+    fn queue_checked(&mut self, conn_id: u64, name: &str) -> AmqpResult<&mut Queue> {
+        let q = self.queues.get_mut(name).ok_or_else(
+            || AmqpError::not_found("queue", name),
+        )?;
+        match q.exclusive_owner {
+            Some(owner) if owner != conn_id => Err(AmqpError::Channel(
+                RESOURCE_LOCKED,
+                format!(
+                    "RESOURCE_LOCKED - cannot obtain exclusive access to locked queue '{}' \
+                     in vhost '/'",
+                    name
+                ),
+            )),
+            _ => Ok(q),
+        }
+    }
+
+    pub fn bind_queue(&mut self, conn_id: u64, m: &queue::BindMethod) -> AmqpResult<()> {
+        self.queue_checked(conn_id, &m.queue)?;
+        self.router.bind_queue(m)?;
+        self.exchanges.get_mut(&*m.exchange).unwrap().bound = true;
+        Ok(())
+    }
+
+    pub fn unbind_queue(&mut self, conn_id: u64, m: &queue::UnbindMethod) -> AmqpResult<()> {
+        self.queue_checked(conn_id, &m.queue)?;
+        self.router.unbind_queue(m)?;
+        self.delete_unused_exchanges();
+        Ok(())
+    }
+
+    pub fn purge_queue(&mut self, conn_id: u64, m: &queue::PurgeMethod) -> AmqpResult<u32> {
+        let q = self.queue_checked(conn_id, &m.queue)?;
+        Ok(q.messages.purge() as u32)
+    }
+
+    pub fn delete_queue(&mut self, conn_id: u64, m: &queue::DeleteMethod) -> AmqpResult<u32> {
+        if !self.queues.contains_key(&*m.queue) {
+            // Deleting a missing queue succeeds as RabbitMQ does.
+            return Ok(0);
+        }
+        {
+            let q = self.queue_checked(conn_id, &m.queue)?;
+            if m.if_unused && !q.consumers.is_empty() {
+                return Err(AmqpError::precondition_failed(
+                    format!("queue '{}' in vhost '/' in use", &*m.queue),
+                ));
+            }
+            if m.if_empty && !q.messages.is_empty() {
+                return Err(AmqpError::precondition_failed(
+                    format!("queue '{}' in vhost '/' is not empty", &*m.queue),
+                ));
+            }
+        }
+        Ok(self.remove_queue(&m.queue))
+    }
+
+    /// Delete the queue with its bindings. Its consumers get `Basic.Cancel`.
+    /// Returns the number of messages which are dropped.
+    fn remove_queue(&mut self, name: &str) -> u32 {
+        let q = match self.queues.remove(name) {
+            Some(q) => q,
+            None => return 0,
+        };
+
+        for c in q.consumers.iter() {
+            if let Some(conn) = self.connections.get_mut(&c.conn_id) {
+                if let Some(ch) = conn.channels.get_mut(&c.channel) {
+                    ch.consumers.remove(&c.tag);
+                }
+                let cancel = CancelMethod {
+                    consumer_tag: AmqpString::from(c.tag.clone()),
+                    no_wait: true,
+                };
+                let method = MethodPayload::Basic(BasicClass::Cancel(cancel));
+                conn.send(vec![Frame::new_method(c.channel, method)]);
+            }
+        }
+
+        self.router.delete_queue(name);
+        self.delete_unused_exchanges();
+
+        q.messages.len() as u32
+    }
+
+    // }}}
+
+
+    // Basic methods {{{
+    /// Start consuming. `Basic.ConsumeOk` is sent before the first delivery unless no_wait.
+    pub fn consume(&mut self, conn_id: u64, channel: u16, m: &ConsumeMethod) -> AmqpResult<()> {
+        let tag = if m.consumer_tag.is_empty() {
+            format!("amq.ctag-{}", self.next_id())
+        } else {
+            m.consumer_tag.to_string()
+        };
+        if self.channel_mut(conn_id, channel)?.consumers.contains_key(&tag) {
+            return Err(AmqpError::connection(
+                ReplyCode::NotAllowed,
+                &format!("attempt to reuse consumer tag '{}'", tag),
+            ));
+        }
+
+        {
+            let q = self.queue_checked(conn_id, &m.queue)?;
+            let exclusive_used = q.consumers.iter().any(|c| c.exclusive);
+            if exclusive_used || (m.exclusive && !q.consumers.is_empty()) {
+                return Err(AmqpError::access_refused(
+                    format!("queue '{}' in vhost '/' in exclusive use", &*m.queue),
+                ));
+            }
+            q.consumers.push(Consumer {
+                tag: tag.clone(),
+                conn_id: conn_id,
+                channel: channel,
+                no_ack: m.no_ack,
+                exclusive: m.exclusive,
+            });
+        }
+        self.channel_mut(conn_id, channel)?.consumers.insert(
+            tag.clone(),
+            m.queue.to_string(),
+        );
+
+        if !m.no_wait {
+            let ok = ConsumeOkMethod { consumer_tag: AmqpString::from(tag) };
+            self.send_method(conn_id, channel, MethodPayload::Basic(BasicClass::ConsumeOk(ok)));
+        }
+        self.dispatch(&m.queue);
+        Ok(())
+    }
+
+    /// Cancel a consumer. Unknown tag is ignored as RabbitMQ does.
+    pub fn cancel(&mut self, conn_id: u64, channel: u16, tag: &str) -> AmqpResult<()> {
+        if let Some(queue) = self.channel_mut(conn_id, channel)?.consumers.remove(tag) {
+            self.remove_consumers(&queue, |c| {
+                c.conn_id == conn_id && c.channel == channel && c.tag == tag
+            });
+        }
+        Ok(())
+    }
+
+    /// Remove consumers matching `f`. An auto-delete queue is deleted when it loses its last
+    /// consumer.
+    fn remove_consumers<F: Fn(&Consumer) -> bool>(&mut self, queue: &str, f: F) {
+        let delete = match self.queues.get_mut(queue) {
+            Some(q) => {
+                let before = q.consumers.len();
+                q.consumers.retain(|c| !f(c));
+                q.next_consumer = 0;
+                q.auto_delete && before > 0 && q.consumers.is_empty()
+            }
+            None => false,
+        };
+        if delete {
+            self.remove_queue(queue);
+        }
+    }
+
+    /// Publish a message, or buffer it in tx mode.
+    pub fn publish(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        m: PublishMethod,
+        message: Message,
+    ) -> AmqpResult<()> {
+        if let Some(ref mut ops) = self.channel_mut(conn_id, channel)?.tx {
+            ops.push(TxOp::Publish(m, message));
+            return Ok(());
+        }
+        self.publish_now(conn_id, channel, m, message)
+    }
+
+    fn publish_now(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        m: PublishMethod,
+        message: Message,
+    ) -> AmqpResult<()> {
+        parse_expiration(&message.properties)?;
+        if self.exchanges.get(&*m.exchange).map_or(false, |e| e.internal) {
+            return Err(AmqpError::access_refused(format!(
+                "cannot publish to internal exchange '{}' in vhost '/'",
+                &*m.exchange
+            )));
+        }
+        let queues = self.router.route(&m, &message.properties)?;
+
+        if queues.is_empty() && m.mandatory {
+            let ret = ReturnMethod {
+                reply_code: NO_ROUTE,
+                reply_text: AmqpString::from("NO_ROUTE"),
+                exchange: m.exchange.clone(),
+                routing_key: m.routing_key.clone(),
+            };
+            let conn = &self.connections[&conn_id];
+            let method = BasicClass::Return(ret);
+            conn.send(content_frames(&message, channel, method, conn.frame_max));
+        }
+        let now = now_millis();
+        let mut accepted = true;
+        let mut dead_letters = Vec::new();
+        for name in queues.iter() {
+            // Messages waiting in the queue go first.
+            dead_letters.extend(self.deliver(name));
+            let message = match self.deliver_now(name, message.clone()) {
+                Some(message) => message,
+                None => continue,
+            };
+            let outcome = self.queues.get_mut(name).unwrap().messages.publish(message, now)?;
+            accepted &= outcome.accepted;
+            dead_letters.extend(outcome.dead_letters);
+            self.dispatch(name);
+        }
+        self.publish_dead_letters(dead_letters);
+
+        // A message refused by "x-overflow" of any queue is nacked.
+        let ch = self.channel_mut(conn_id, channel)?;
+        if let Some(seq) = ch.confirm {
+            ch.confirm = Some(seq + 1);
+            let method = if accepted {
+                BasicClass::Ack(AckMethod {
+                    delivery_tag: seq + 1,
+                    multiple: false,
+                })
+            } else {
+                BasicClass::Nack(NackMethod {
+                    delivery_tag: seq + 1,
+                    multiple: false,
+                    requeue: false,
+                })
+            };
+            self.send_method(conn_id, channel, MethodPayload::Basic(method));
+        }
+        Ok(())
+    }
+
+    /// Reply to `Basic.Get` by `Basic.GetOk` with content, or by `Basic.GetEmpty`.
+    pub fn get(&mut self, conn_id: u64, channel: u16, m: &GetMethod) -> AmqpResult<()> {
+        let (message, remaining, expired) = {
+            let q = self.queue_checked(conn_id, &m.queue)?;
+            let expired = q.messages.expire(now_millis());
+            (q.messages.pop(), q.messages.len() as u32, expired)
+        };
+        self.publish_dead_letters(expired);
+        let message = match message {
+            Some(message) => message,
+            None => {
+                let empty = GetEmptyMethod { reserved1: AmqpString::from("") };
+                let method = MethodPayload::Basic(BasicClass::GetEmpty(empty));
+                self.send_method(conn_id, channel, method);
+                return Ok(());
+            }
+        };
+
+        let conn = self.connections.get_mut(&conn_id).unwrap();
+        let ch = conn.channels.get_mut(&channel).unwrap();
+        let get_ok = GetOkMethod {
+            delivery_tag: ch.next_delivery_tag(),
+            redeliverd: message.redelivered,
+            exchange: message.exchange.clone(),
+            routing_key: message.routing_key.clone(),
+            message_count: remaining,
+        };
+        let tag = get_ok.delivery_tag;
+        let method = BasicClass::GetOk(get_ok);
+        let frames = content_frames(&message, channel, method, conn.frame_max);
+        if !m.no_ack {
+            ch.unacked.insert(
+                tag,
+                Unacked {
+                    queue: m.queue.to_string(),
+                    consumer: None,
+                    message: message,
+                },
+            );
+        }
+        conn.send(frames);
+        Ok(())
+    }
+
+    pub fn ack(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> AmqpResult<()> {
+        if let Some(ref mut ops) = self.channel_mut(conn_id, channel)?.tx {
+            ops.push(TxOp::Ack(delivery_tag, multiple));
+            return Ok(());
+        }
+        self.ack_now(conn_id, channel, delivery_tag, multiple)
+    }
+
+    fn ack_now(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> AmqpResult<()> {
+        self.channel_mut(conn_id, channel)?.take_unacked(
+            delivery_tag,
+            multiple,
+        )?;
+        self.dispatch_all();
+        Ok(())
+    }
+
+    /// Handle `Basic.Reject` and `Basic.Nack`.
+    pub fn reject(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        delivery_tag: u64,
+        multiple: bool,
+        requeue: bool,
+    ) -> AmqpResult<()> {
+        if let Some(ref mut ops) = self.channel_mut(conn_id, channel)?.tx {
+            ops.push(TxOp::Reject(delivery_tag, multiple, requeue));
+            return Ok(());
+        }
+        self.reject_now(conn_id, channel, delivery_tag, multiple, requeue)
+    }
+
+    fn reject_now(
+        &mut self,
+        conn_id: u64,
+        channel: u16,
+        delivery_tag: u64,
+        multiple: bool,
+        requeue: bool,
+    ) -> AmqpResult<()> {
+        let rejected = self.channel_mut(conn_id, channel)?.take_unacked(
+            delivery_tag,
+            multiple,
+        )?;
+        if requeue {
+            self.requeue(rejected);
+        } else {
+            let now = now_millis();
+            let dead_letters = rejected
+                .into_iter()
+                .filter_map(|u| {
+                    self.queues.get(&u.queue).and_then(|q| {
+                        q.messages.dead_letter(u.message, DeadLetterReason::Rejected, now)
+                    })
+                })
+                .collect();
+            self.publish_dead_letters(dead_letters);
+        }
+        self.dispatch_all();
+        Ok(())
+    }
+
+    /// Requeue all unacked messages of the channel. Only `requeue = true` is supported as
+    /// RabbitMQ.
+    pub fn recover(&mut self, conn_id: u64, channel: u16, requeue: bool) -> AmqpResult<()> {
+        if !requeue {
+            return Err(AmqpError::connection(ReplyCode::NotImplemented, "requeue=false"));
+        }
+        let unacked = ::std::mem::replace(
+            &mut self.channel_mut(conn_id, channel)?.unacked,
+            BTreeMap::new(),
+        );
+        self.requeue(unacked.into_iter().map(|(_, u)| u).collect());
+        Ok(())
+    }
+
+    /// Put messages back to the head of their queues in the original order.
+    /// Messages of deleted queues are dropped.
+    fn requeue(&mut self, unacked: Vec<Unacked>) {
+        let mut queues: HashMap<String, Vec<Message>> = HashMap::new();
+        for u in unacked {
+            queues.entry(u.queue).or_insert_with(Vec::new).push(u.message);
+        }
+        for (name, messages) in queues {
+            if let Some(q) = self.queues.get_mut(&name) {
+                q.messages.requeue(messages);
+            }
+            self.dispatch(&name);
+        }
+    }
+    // }}}
+
+
+    // Tx methods {{{
+    pub fn tx_select(&mut self, conn_id: u64, channel: u16) -> AmqpResult<()> {
+        let ch = self.channel_mut(conn_id, channel)?;
+        if ch.confirm.is_some() {
+            return Err(AmqpError::precondition_failed(
+                "cannot switch from confirm to tx mode".into(),
+            ));
+        }
+        if ch.tx.is_none() {
+            ch.tx = Some(Vec::new());
+        }
+        Ok(())
+    }
+
+    pub fn tx_commit(&mut self, conn_id: u64, channel: u16) -> AmqpResult<()> {
+        let ops = self.take_tx_ops(conn_id, channel)?;
+        for op in ops {
+            match op {
+                TxOp::Publish(m, message) => self.publish_now(conn_id, channel, m, message)?,
+                TxOp::Ack(tag, multiple) => self.ack_now(conn_id, channel, tag, multiple)?,
+                TxOp::Reject(tag, multiple, requeue) => {
+                    self.reject_now(conn_id, channel, tag, multiple, requeue)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn tx_rollback(&mut self, conn_id: u64, channel: u16) -> AmqpResult<()> {
+        self.take_tx_ops(conn_id, channel).map(|_| ())
+    }
+
+    fn take_tx_ops(&mut self, conn_id: u64, channel: u16) -> AmqpResult<Vec<TxOp>> {
+        match self.channel_mut(conn_id, channel)?.tx {
+            Some(ref mut ops) => Ok(::std::mem::replace(ops, Vec::new())),
+            None => Err(AmqpError::precondition_failed(
+                "channel is not transactional".into(),
+            )),
+        }
+    }
+    // }}}
+
+
+    // Dead lettering {{{
+    /// Dead-letter expired messages of all queues. Called periodically by `MockBroker`.
+    pub fn expire_messages(&mut self) {
+        let now = now_millis();
+        let dead_letters: Vec<DeadLetter> = self.queues
+            .values_mut()
+            .flat_map(|q| q.messages.expire(now))
+            .collect();
+        self.publish_dead_letters(dead_letters);
+    }
+
+    /// Route dead letters from their dead letter exchanges. Messages are dropped if the
+    /// exchange does not exist or if they would go around a cycle, as RabbitMQ does.
+    fn publish_dead_letters(&mut self, dead_letters: Vec<DeadLetter>) {
+        let now = now_millis();
+        let mut pending: VecDeque<DeadLetter> = dead_letters.into_iter().collect();
+        let mut routed = HashSet::new();
+        while let Some(d) = pending.pop_front() {
+            let empty = HashMap::new();
+            let queues = match self.router.route_with(
+                &d.message.exchange,
+                &d.message.routing_key,
+                d.message.properties.headers.as_ref().unwrap_or(&empty),
+            ) {
+                Ok(queues) => queues,
+                Err(e) => {
+                    debug!("Dead letter is dropped : {}", e);
+                    continue;
+                }
+            };
+            for name in queues {
+                if d.would_cycle(&name) {
+                    debug!("Dead letter is dropped by a cycle at queue '{}'", name);
+                    continue;
+                }
+                let outcome = match self.queues.get_mut(&name).unwrap().messages.publish(
+                    d.message.clone(),
+                    now,
+                ) {
+                    Ok(outcome) => outcome,
+                    Err(e) => {
+                        debug!("Dead letter is dropped : {}", e);
+                        continue;
+                    }
+                };
+                pending.extend(outcome.dead_letters);
+                routed.insert(name);
+            }
+        }
+        for name in routed {
+            self.dispatch(&name);
+        }
+    }
+    // }}}
+
+
+    // Dispatch {{{
+    fn dispatch_all(&mut self) {
+        let queues: Vec<String> = self.queues
+            .iter()
+            .filter(|&(_, q)| !q.consumers.is_empty() && !q.messages.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for q in queues {
+            self.dispatch(&q);
+        }
+    }
+
+    /// Deliver messages of the queue to its consumers in round robin, as long as their
+    /// channels accept more.
+    fn dispatch(&mut self, name: &str) {
+        let expired = self.deliver(name);
+        self.publish_dead_letters(expired);
+    }
+
+    /// Deliver ready messages of the queue. Returns dead letters of expired messages.
+    fn deliver(&mut self, name: &str) -> Vec<DeadLetter> {
+        let Broker {
+            ref mut queues,
+            ref mut connections,
+            ..
+        } = *self;
+        let q = match queues.get_mut(name) {
+            Some(q) => q,
+            None => return Vec::new(),
+        };
+        let expired = q.messages.expire(now_millis());
+
+        while !q.messages.is_empty() {
+            let i = match ready_consumer(q, connections) {
+                Some(i) => i,
+                None => break,
+            };
+            let message = q.messages.pop().unwrap();
+            send_delivery(q, i, name, connections, message);
+        }
+        expired
+    }
+
+    /// Hand a message just published to a consumer without enqueuing it, if no message is
+    /// waiting in the queue and a consumer can accept it. Otherwise the message is given back.
+    /// As RabbitMQ does, this is how a message of TTL 0 can be delivered at all.
+    fn deliver_now(&mut self, name: &str, message: Message) -> Option<Message> {
+        let Broker {
+            ref mut queues,
+            ref mut connections,
+            ..
+        } = *self;
+        let q = match queues.get_mut(name) {
+            Some(ref q) if !q.messages.is_empty() => return Some(message),
+            Some(q) => q,
+            None => return Some(message),
+        };
+        match ready_consumer(q, connections) {
+            Some(i) => {
+                send_delivery(q, i, name, connections, message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+    // }}}
+}
+
+
+/// Index of the next consumer in round robin whose channel accepts a delivery.
+fn ready_consumer(q: &Queue, connections: &HashMap<u64, Connection>) -> Option<usize> {
+    let n = q.consumers.len();
+    (0..n).map(|i| (q.next_consumer + i) % n).find(|&i| {
+        let c = &q.consumers[i];
+        connections
+            .get(&c.conn_id)
+            .and_then(|conn| conn.channels.get(&c.channel))
+            .map_or(false, |ch| ch.can_deliver(c))
+    })
+}
+
+
+/// Send `Basic.Deliver` of the message to `i`th consumer of the queue.
+fn send_delivery(
+    q: &mut Queue,
+    i: usize,
+    name: &str,
+    connections: &mut HashMap<u64, Connection>,
+    message: Message,
+) {
+    q.next_consumer = (i + 1) % q.consumers.len();
+
+    let c = &q.consumers[i];
+    let conn = connections.get_mut(&c.conn_id).unwrap();
+    let ch = conn.channels.get_mut(&c.channel).unwrap();
+    let deliver = DeliverMethod {
+        consumer_tag: AmqpString::from(c.tag.clone()),
+        delivery_tag: ch.next_delivery_tag(),
+        redeliverd: message.redelivered,
+        exchange: message.exchange.clone(),
+        routing_key: message.routing_key.clone(),
+    };
+    let tag = deliver.delivery_tag;
+    let method = BasicClass::Deliver(deliver);
+    let frames = content_frames(&message, c.channel, method, conn.frame_max);
+    if !c.no_ack {
+        ch.unacked.insert(
+            tag,
+            Unacked {
+                queue: name.into(),
+                consumer: Some(c.tag.clone()),
+                message: message,
+            },
+        );
+    }
+    conn.send(frames);
+}
+
+
+/// Milliseconds since UNIX epoch, which is the clock of `QueueStore`.
+fn now_millis() -> u64 {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+// }}}
@@ -0,0 +1,854 @@
+//! Storage of ready messages in a queue, following RabbitMQ.
+//!
+//! `QueueStore` applies the queue arguments given by `queue::DeclareMethod::arguments`.
+//!
+//! - "x-message-ttl" : messages expire after this many milliseconds. A message can also have
+//!   its own TTL in `Properties::expiration`, and the smaller one is used.
+//! - "x-max-length" / "x-max-length-bytes" : limits of ready messages and their body sizes.
+//! - "x-overflow" : what happens when a limit is reached. "drop-head" (default) drops the
+//!   message at the head, "reject-publish" refuses new messages and "reject-publish-dlx"
+//!   also dead-letters refused messages.
+//! - "x-max-priority" : messages with higher `Properties::priority` are delivered first.
+//!   Priority above the maximum is treated as the maximum.
+//! - "x-dead-letter-exchange" / "x-dead-letter-routing-key" : where expired, dropped and
+//!   rejected messages are republished. Their "x-death" header records why.
+//!
+//! Time is given by callers in milliseconds, so a topology can be simulated without waiting.
+//! As RabbitMQ does, only messages at the head of a queue expire. A message whose TTL is
+//! shorter than one before it waits until the earlier message leaves.
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+use bytes::Bytes;
+
+use args::{AmqpString, FieldArgument};
+use frame::content_header::Properties;
+
+
+// Message {{{
+#[derive(PartialEq, Clone, Debug)]
+pub struct Message {
+    pub exchange: AmqpString,
+    pub routing_key: AmqpString,
+    pub properties: Properties,
+    pub body: Bytes,
+    /// Whether this message has been delivered and requeued.
+    pub redelivered: bool,
+    /// When this message expires. Decided when it is enqueued and kept after requeue.
+    expires_at: Option<u64>,
+}
+
+
+impl Message {
+    pub fn new(
+        exchange: AmqpString,
+        routing_key: AmqpString,
+        properties: Properties,
+        body: Bytes,
+    ) -> Message {
+        Message {
+            exchange: exchange,
+            routing_key: routing_key,
+            properties: properties,
+            body: body,
+            redelivered: false,
+            expires_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |t| now >= t)
+    }
+}
+
+
+/// Read per-message TTL in milliseconds from `Properties::expiration`.
+/// It must be a non-negative integer written in decimal.
+pub fn parse_expiration(properties: &Properties) -> Result<Option<u64>, QueueError> {
+    match properties.expiration {
+        None => Ok(None),
+        Some(ref s) => {
+            let valid = !s.0.is_empty() && s.0.iter().all(|b| b.is_ascii_digit());
+            let ttl = if valid { s.parse().ok() } else { None };
+            ttl.map(Some).ok_or_else(|| {
+                QueueError::InvalidExpiration(String::from_utf8_lossy(&s.0).into_owned())
+            })
+        }
+    }
+}
+// }}}
+
+
+
+// QueueArguments {{{
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Overflow {
+    DropHead,
+    RejectPublish,
+    RejectPublishDlx,
+}
+
+
+impl Default for Overflow {
+    fn default() -> Overflow {
+        Overflow::DropHead
+    }
+}
+
+
+/// Queue arguments which `QueueStore` supports. Other arguments are ignored.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct QueueArguments {
+    pub message_ttl: Option<u64>,
+    pub max_length: Option<usize>,
+    pub max_length_bytes: Option<usize>,
+    pub overflow: Overflow,
+    pub max_priority: Option<u8>,
+    pub dead_letter_exchange: Option<String>,
+    pub dead_letter_routing_key: Option<String>,
+}
+
+
+impl QueueArguments {
+    /// Read arguments of `Queue.Declare`. Invalid values are errors as RabbitMQ.
+    pub fn from_table(
+        arguments: &HashMap<AmqpString, FieldArgument>,
+    ) -> Result<QueueArguments, QueueError> {
+        let get = |name: &'static str| arguments.get(&AmqpString::from(name));
+        let integer = |name: &'static str| -> Result<Option<u64>, QueueError> {
+            match get(name) {
+                None => Ok(None),
+                Some(v) => {
+                    non_negative_integer(v).map(Some).ok_or(
+                        QueueError::InvalidArgument(name),
+                    )
+                }
+            }
+        };
+        let string = |name: &'static str| -> Result<Option<String>, QueueError> {
+            match get(name) {
+                None => Ok(None),
+                Some(&FieldArgument::LongString(ref s)) |
+                Some(&FieldArgument::ShortString(ref s)) => {
+                    match String::from_utf8(s.as_bytes().to_vec()) {
+                        Ok(s) => Ok(Some(s)),
+                        Err(_) => Err(QueueError::InvalidArgument(name)),
+                    }
+                }
+                Some(_) => Err(QueueError::InvalidArgument(name)),
+            }
+        };
+
+        let overflow = match string("x-overflow")? {
+            None => Overflow::DropHead,
+            Some(ref s) if s == "drop-head" => Overflow::DropHead,
+            Some(ref s) if s == "reject-publish" => Overflow::RejectPublish,
+            Some(ref s) if s == "reject-publish-dlx" => Overflow::RejectPublishDlx,
+            Some(_) => return Err(QueueError::InvalidArgument("x-overflow")),
+        };
+        let max_priority = match integer("x-max-priority")? {
+            Some(p) if p > 255 => return Err(QueueError::InvalidArgument("x-max-priority")),
+            p => p.map(|p| p as u8),
+        };
+        let args = QueueArguments {
+            message_ttl: integer("x-message-ttl")?,
+            max_length: integer("x-max-length")?.map(|n| n as usize),
+            max_length_bytes: integer("x-max-length-bytes")?.map(|n| n as usize),
+            overflow: overflow,
+            max_priority: max_priority,
+            dead_letter_exchange: string("x-dead-letter-exchange")?,
+            dead_letter_routing_key: string("x-dead-letter-routing-key")?,
+        };
+        if args.dead_letter_routing_key.is_some() && args.dead_letter_exchange.is_none() {
+            return Err(QueueError::InvalidArgument("x-dead-letter-routing-key"));
+        }
+        Ok(args)
+    }
+}
+
+
+fn non_negative_integer(value: &FieldArgument) -> Option<u64> {
+    match *value {
+        FieldArgument::SignedOctet(v) if v >= 0 => Some(v as u64),
+        FieldArgument::UnsignedOctet(v) => Some(v as u64),
+        FieldArgument::SignedShort(v) if v >= 0 => Some(v as u64),
+        FieldArgument::UnsignedShort(v) => Some(v as u64),
+        FieldArgument::SignedLong(v) if v >= 0 => Some(v as u64),
+        FieldArgument::UnsignedLong(v) => Some(v as u64),
+        FieldArgument::SignedLongLong(v) if v >= 0 => Some(v as u64),
+        FieldArgument::UnsignedLongLong(v) => Some(v),
+        _ => None,
+    }
+}
+// }}}
+
+
+
+// Dead lettering {{{
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum DeadLetterReason {
+    /// Rejected by `Basic.Reject` / `Basic.Nack` without requeue.
+    Rejected,
+    Expired,
+    /// Dropped or refused by "x-max-length" / "x-max-length-bytes".
+    MaxLen,
+}
+
+
+impl DeadLetterReason {
+    /// Name used in "x-death" header.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DeadLetterReason::Rejected => "rejected",
+            DeadLetterReason::Expired => "expired",
+            DeadLetterReason::MaxLen => "maxlen",
+        }
+    }
+}
+
+
+/// A message to be republished to a dead letter exchange. Its `exchange` and `routing_key`
+/// are already replaced by the dead letter exchange and routing key.
+#[derive(PartialEq, Clone, Debug)]
+pub struct DeadLetter {
+    pub reason: DeadLetterReason,
+    pub message: Message,
+}
+
+
+impl DeadLetter {
+    /// Whether routing this message to `queue` makes a cycle which RabbitMQ drops. It is a
+    /// cycle if the message has died in `queue` before without being rejected since then.
+    pub fn would_cycle(&self, queue: &str) -> bool {
+        let deaths = match self.message.properties.headers.as_ref().and_then(|h| {
+            h.get(&AmqpString::from("x-death"))
+        }) {
+            Some(&FieldArgument::Array(ref deaths)) => deaths,
+            _ => return false,
+        };
+
+        for death in deaths.iter() {
+            if death_field(death, "reason") == Some(DeadLetterReason::Rejected.name()) {
+                return false;
+            }
+            if death_field(death, "queue") == Some(queue) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+
+fn death_field<'a>(death: &'a FieldArgument, name: &'static str) -> Option<&'a str> {
+    match *death {
+        FieldArgument::NestedTable(ref table) => {
+            match table.get(&AmqpString::from(name)) {
+                Some(&FieldArgument::LongString(ref s)) => str::from_utf8(s.as_bytes()).ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+
+fn long_str(s: &str) -> FieldArgument {
+    FieldArgument::LongString(AmqpString::from(s.to_string()))
+}
+
+
+/// Record a death in "x-death" and related headers as RabbitMQ does.
+///
+/// "x-death" is an array of tables, the most recent first. A table is kept for each pair of
+/// queue and reason, and its "count" is incremented when the message dies again for the same
+/// reason in the same queue.
+fn record_death(message: &mut Message, queue: &str, reason: DeadLetterReason, now: u64) {
+    let mut headers = message.properties.headers.take().unwrap_or_else(HashMap::new);
+    let mut deaths = match headers.remove(&AmqpString::from("x-death")) {
+        Some(FieldArgument::Array(deaths)) => deaths,
+        _ => Vec::new(),
+    };
+
+    let found = deaths.iter().position(|d| {
+        death_field(d, "queue") == Some(queue) && death_field(d, "reason") == Some(reason.name())
+    });
+    let death = match found {
+        Some(i) => {
+            let mut death = deaths.remove(i);
+            if let FieldArgument::NestedTable(ref mut table) = death {
+                let count = match table.get(&AmqpString::from("count")) {
+                    Some(&FieldArgument::SignedLongLong(n)) => n,
+                    _ => 0,
+                };
+                table.insert(AmqpString::from("count"), FieldArgument::SignedLongLong(count + 1));
+            }
+            death
+        }
+        None => {
+            let mut table = HashMap::new();
+            table.insert(AmqpString::from("count"), FieldArgument::SignedLongLong(1));
+            table.insert(AmqpString::from("reason"), long_str(reason.name()));
+            table.insert(AmqpString::from("queue"), long_str(queue));
+            table.insert(AmqpString::from("time"), FieldArgument::Timestamp(now / 1000));
+            table.insert(AmqpString::from("exchange"), long_str(&message.exchange));
+            table.insert(
+                AmqpString::from("routing-keys"),
+                FieldArgument::Array(vec![long_str(&message.routing_key)]),
+            );
+            if let Some(ref expiration) = message.properties.expiration {
+                table.insert(
+                    AmqpString::from("original-expiration"),
+                    FieldArgument::LongString(expiration.clone()),
+                );
+            }
+            FieldArgument::NestedTable(table)
+        }
+    };
+    deaths.insert(0, death);
+    headers.insert(AmqpString::from("x-death"), FieldArgument::Array(deaths));
+
+    if !headers.contains_key(&AmqpString::from("x-first-death-reason")) {
+        headers.insert(AmqpString::from("x-first-death-reason"), long_str(reason.name()));
+        headers.insert(AmqpString::from("x-first-death-queue"), long_str(queue));
+        headers.insert(
+            AmqpString::from("x-first-death-exchange"),
+            long_str(&message.exchange),
+        );
+    }
+    headers.insert(AmqpString::from("x-last-death-reason"), long_str(reason.name()));
+    headers.insert(AmqpString::from("x-last-death-queue"), long_str(queue));
+    headers.insert(AmqpString::from("x-last-death-exchange"), long_str(&message.exchange));
+
+    message.properties.headers = Some(headers);
+}
+// }}}
+
+
+
+// QueueStore {{{
+/// Result of `QueueStore::publish`.
+#[derive(PartialEq, Clone, Debug)]
+pub struct PublishOutcome {
+    /// False if the message is refused by "reject-publish" or "reject-publish-dlx".
+    /// A publisher in confirm mode should get `Basic.Nack` then.
+    pub accepted: bool,
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+
+/// Ready messages of a queue. Messages delivered to consumers are not held here.
+#[derive(Clone, Debug)]
+pub struct QueueStore {
+    name: String,
+    arguments: QueueArguments,
+    /// Messages of each priority. Index is the priority.
+    levels: Vec<VecDeque<Message>>,
+    len: usize,
+    bytes: usize,
+}
+
+
+impl QueueStore {
+    pub fn new(name: &str, arguments: QueueArguments) -> QueueStore {
+        let levels = arguments.max_priority.map_or(1, |p| p as usize + 1);
+        QueueStore {
+            name: name.into(),
+            arguments: arguments,
+            levels: (0..levels).map(|_| VecDeque::new()).collect(),
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arguments(&self) -> &QueueArguments {
+        &self.arguments
+    }
+
+    /// Number of ready messages.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Total body size of ready messages.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Enqueue a published message at `now`.
+    /// A message with invalid `expiration` is refused without changing the queue.
+    pub fn publish(&mut self, message: Message, now: u64) -> Result<PublishOutcome, QueueError> {
+        let message_ttl = parse_expiration(&message.properties)?;
+        let mut message = message;
+        message.redelivered = false;
+        let ttl = match (self.arguments.message_ttl, message_ttl) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        message.expires_at = ttl.map(|ttl| now.saturating_add(ttl));
+
+        let mut outcome = PublishOutcome {
+            accepted: true,
+            dead_letters: Vec::new(),
+        };
+        if self.arguments.overflow != Overflow::DropHead && self.is_full() {
+            outcome.accepted = false;
+            if self.arguments.overflow == Overflow::RejectPublishDlx {
+                outcome.dead_letters.extend(
+                    self.dead_letter(message, DeadLetterReason::MaxLen, now),
+                );
+            }
+            return Ok(outcome);
+        }
+
+        self.push_back(message);
+        while self.is_over_limit() {
+            let dropped = self.pop().unwrap();
+            outcome.dead_letters.extend(
+                self.dead_letter(dropped, DeadLetterReason::MaxLen, now),
+            );
+        }
+        Ok(outcome)
+    }
+
+    /// Take the next message to deliver. Call `expire` before this to drop expired messages.
+    pub fn pop(&mut self) -> Option<Message> {
+        let message = self.levels.iter_mut().rev().filter_map(|l| l.pop_front()).next();
+        if let Some(ref m) = message {
+            self.len -= 1;
+            self.bytes -= m.body.len();
+        }
+        message
+    }
+
+    /// Put delivered messages back to the head of the queue in the given order.
+    /// They keep their original expiry.
+    pub fn requeue(&mut self, messages: Vec<Message>) {
+        for mut message in messages.into_iter().rev() {
+            message.redelivered = true;
+            let level = self.level_of(&message);
+            self.len += 1;
+            self.bytes += message.body.len();
+            self.levels[level].push_front(message);
+        }
+    }
+
+    /// Remove all ready messages. Returns the number of removed messages.
+    pub fn purge(&mut self) -> usize {
+        let len = self.len;
+        for level in self.levels.iter_mut() {
+            level.clear();
+        }
+        self.len = 0;
+        self.bytes = 0;
+        len
+    }
+
+    /// Remove expired messages at the head of the queue, and dead-letter them.
+    pub fn expire(&mut self, now: u64) -> Vec<DeadLetter> {
+        let mut expired = Vec::new();
+        for level in self.levels.iter_mut() {
+            while level.front().map_or(false, |m| m.is_expired(now)) {
+                expired.push(level.pop_front().unwrap());
+            }
+        }
+        self.len -= expired.len();
+        self.bytes -= expired.iter().map(|m| m.body.len()).sum::<usize>();
+
+        expired
+            .into_iter()
+            .filter_map(|m| self.dead_letter(m, DeadLetterReason::Expired, now))
+            .collect()
+    }
+
+    /// The earliest time when a message at the head of the queue expires.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.levels
+            .iter()
+            .filter_map(|l| l.front().and_then(|m| m.expires_at))
+            .min()
+    }
+
+    /// Make a dead letter of a message which died in this queue (e.g. rejected without
+    /// requeue). `None` if this queue has no dead letter exchange, so the message is dropped.
+    pub fn dead_letter(
+        &self,
+        message: Message,
+        reason: DeadLetterReason,
+        now: u64,
+    ) -> Option<DeadLetter> {
+        let exchange = match self.arguments.dead_letter_exchange {
+            Some(ref exchange) => exchange.clone(),
+            None => return None,
+        };
+
+        let mut message = message;
+        record_death(&mut message, &self.name, reason, now);
+        if let Some(ref key) = self.arguments.dead_letter_routing_key {
+            message.routing_key = AmqpString::from(key.clone());
+        }
+        message.exchange = AmqpString::from(exchange);
+        // Expiration is moved into "x-death" so that the message does not expire again.
+        message.properties.expiration = None;
+        message.redelivered = false;
+        message.expires_at = None;
+        Some(DeadLetter {
+            reason: reason,
+            message: message,
+        })
+    }
+
+    fn level_of(&self, message: &Message) -> usize {
+        let priority = message.properties.priority.unwrap_or(0) as usize;
+        priority.min(self.levels.len() - 1)
+    }
+
+    fn push_back(&mut self, message: Message) {
+        let level = self.level_of(&message);
+        self.len += 1;
+        self.bytes += message.body.len();
+        self.levels[level].push_back(message);
+    }
+
+    fn is_full(&self) -> bool {
+        self.arguments.max_length.map_or(false, |max| self.len >= max) ||
+            self.arguments.max_length_bytes.map_or(false, |max| self.bytes >= max)
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.arguments.max_length.map_or(false, |max| self.len > max) ||
+            self.arguments.max_length_bytes.map_or(false, |max| self.bytes > max)
+    }
+}
+// }}}
+
+
+
+// QueueError {{{
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum QueueError {
+    /// Queue argument of this name has an invalid value.
+    InvalidArgument(&'static str),
+    /// `Properties::expiration` is not a non-negative integer.
+    InvalidExpiration(String),
+}
+
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueueError::InvalidArgument(name) => write!(f, "invalid arg '{}'", name),
+            QueueError::InvalidExpiration(ref s) => write!(f, "invalid expiration '{}'", s),
+        }
+    }
+}
+
+
+impl Error for QueueError {}
+// }}}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(entries: &[(&'static str, FieldArgument)]) -> QueueArguments {
+        let table = entries
+            .iter()
+            .map(|&(k, ref v)| (AmqpString::from(k), v.clone()))
+            .collect();
+        QueueArguments::from_table(&table).unwrap()
+    }
+
+    fn dlx(entries: &[(&'static str, FieldArgument)]) -> QueueArguments {
+        let mut args = arguments(entries);
+        args.dead_letter_exchange = Some("dlx".into());
+        args
+    }
+
+    fn message(body: &'static str) -> Message {
+        let mut properties = Properties::new();
+        properties.headers = Some(HashMap::new());
+        Message::new(
+            AmqpString::from("ex"),
+            AmqpString::from("key"),
+            properties,
+            Bytes::from_static(body.as_bytes()),
+        )
+    }
+
+    fn with_expiration(body: &'static str, expiration: &'static str) -> Message {
+        let mut m = message(body);
+        m.properties.expiration = Some(AmqpString::from(expiration));
+        m
+    }
+
+    fn with_priority(body: &'static str, priority: u8) -> Message {
+        let mut m = message(body);
+        m.properties.priority = Some(priority);
+        m
+    }
+
+    fn bodies(store: &mut QueueStore) -> Vec<Bytes> {
+        let mut bodies = Vec::new();
+        while let Some(m) = store.pop() {
+            bodies.push(m.body);
+        }
+        bodies
+    }
+
+    fn header<'a>(m: &'a Message, name: &'static str) -> Option<&'a FieldArgument> {
+        m.properties.headers.as_ref().unwrap().get(&AmqpString::from(name))
+    }
+
+    #[test]
+    fn parse_arguments() {
+        let args = arguments(&[
+            ("x-message-ttl", FieldArgument::SignedLong(1000)),
+            ("x-max-length", FieldArgument::UnsignedShort(10)),
+            ("x-overflow", long_str("reject-publish-dlx")),
+            ("x-max-priority", FieldArgument::UnsignedOctet(5)),
+            ("x-dead-letter-exchange", long_str("dlx")),
+            ("x-dead-letter-routing-key", long_str("retry")),
+            ("x-unknown", FieldArgument::Void),
+        ]);
+        assert_eq!(args.message_ttl, Some(1000));
+        assert_eq!(args.max_length, Some(10));
+        assert_eq!(args.overflow, Overflow::RejectPublishDlx);
+        assert_eq!(args.max_priority, Some(5));
+        assert_eq!(args.dead_letter_routing_key, Some("retry".into()));
+
+        let invalid = |name: &'static str, value: FieldArgument| {
+            let mut table = HashMap::new();
+            table.insert(AmqpString::from(name), value);
+            QueueArguments::from_table(&table)
+        };
+        assert_eq!(
+            invalid("x-message-ttl", FieldArgument::SignedLong(-1)),
+            Err(QueueError::InvalidArgument("x-message-ttl"))
+        );
+        assert_eq!(
+            invalid("x-overflow", long_str("drop-tail")),
+            Err(QueueError::InvalidArgument("x-overflow"))
+        );
+        assert_eq!(
+            invalid("x-max-priority", FieldArgument::SignedLong(256)),
+            Err(QueueError::InvalidArgument("x-max-priority"))
+        );
+        assert_eq!(
+            invalid("x-dead-letter-routing-key", long_str("key")),
+            Err(QueueError::InvalidArgument("x-dead-letter-routing-key"))
+        );
+    }
+
+    #[test]
+    fn parse_per_message_expiration() {
+        assert_eq!(parse_expiration(&with_expiration("", "60000").properties), Ok(Some(60000)));
+        assert_eq!(parse_expiration(&message("").properties), Ok(None));
+        assert_eq!(
+            parse_expiration(&with_expiration("", "-1").properties),
+            Err(QueueError::InvalidExpiration("-1".into()))
+        );
+    }
+
+    #[test]
+    fn shorter_ttl_wins_and_only_head_expires() {
+        let mut store = QueueStore::new(
+            "q",
+            dlx(&[("x-message-ttl", FieldArgument::SignedLong(100))]),
+        );
+        store.publish(message("queue ttl"), 0).unwrap();
+        store.publish(with_expiration("short", "10"), 0).unwrap();
+        store.publish(with_expiration("long", "500"), 0).unwrap();
+        assert_eq!(store.next_expiry(), Some(100));
+
+        // "short" has expired but waits behind "queue ttl".
+        assert!(store.expire(50).is_empty());
+        let dead = store.expire(101);
+        assert_eq!(dead.len(), 3);
+        assert!(dead.iter().all(|d| d.reason == DeadLetterReason::Expired));
+        assert!(store.is_empty());
+
+        // Per-message expiration is moved into x-death.
+        let short = &dead[1].message;
+        assert_eq!(short.properties.expiration, None);
+        match header(short, "x-death") {
+            Some(&FieldArgument::Array(ref deaths)) => {
+                assert_eq!(death_field(&deaths[0], "original-expiration"), Some("10"));
+            }
+            h => panic!("Unexpected x-death {:?}", h),
+        }
+    }
+
+    #[test]
+    fn invalid_expiration_is_refused() {
+        let mut store = QueueStore::new("q", dlx(&[]));
+        assert_eq!(
+            store.publish(with_expiration("a", "soon"), 0).err(),
+            Some(QueueError::InvalidExpiration("soon".into()))
+        );
+        assert!(store.is_empty());
+        assert_eq!(store.bytes(), 0);
+    }
+
+    #[test]
+    fn ttl_is_kept_after_requeue() {
+        let mut store = QueueStore::new("q", dlx(&[]));
+        store.publish(with_expiration("a", "100"), 0).unwrap();
+        let m = store.pop().unwrap();
+        store.requeue(vec![m]);
+        assert!(store.expire(99).is_empty());
+        assert_eq!(store.expire(100).len(), 1);
+    }
+
+    #[test]
+    fn zero_ttl_expires_at_once() {
+        let mut store = QueueStore::new("q", dlx(&[]));
+        store.publish(with_expiration("a", "0"), 5).unwrap();
+        assert_eq!(store.expire(5).len(), 1);
+        assert!(store.pop().is_none());
+    }
+
+    #[test]
+    fn drop_head_dead_letters_oldest_messages() {
+        let mut store = QueueStore::new(
+            "q",
+            dlx(&[
+                ("x-max-length", FieldArgument::SignedLong(2)),
+                ("x-max-length-bytes", FieldArgument::SignedLong(6)),
+            ]),
+        );
+        assert!(store.publish(message("aa"), 0).unwrap().dead_letters.is_empty());
+        assert!(store.publish(message("bb"), 0).unwrap().dead_letters.is_empty());
+
+        let outcome = store.publish(message("cc"), 0).unwrap();
+        assert!(outcome.accepted);
+        assert_eq!(outcome.dead_letters[0].message.body, Bytes::from_static(b"aa"));
+        assert_eq!(outcome.dead_letters[0].reason, DeadLetterReason::MaxLen);
+
+        let outcome = store.publish(message("dddddd"), 0).unwrap();
+        assert_eq!(outcome.dead_letters.len(), 2);
+        assert_eq!((store.len(), store.bytes()), (1, 6));
+    }
+
+    #[test]
+    fn reject_publish_refuses_new_messages() {
+        let mut store = QueueStore::new(
+            "q",
+            dlx(&[
+                ("x-max-length", FieldArgument::SignedLong(1)),
+                ("x-overflow", long_str("reject-publish")),
+            ]),
+        );
+        assert!(store.publish(message("a"), 0).unwrap().accepted);
+        let outcome = store.publish(message("b"), 0).unwrap();
+        assert!(!outcome.accepted);
+        assert!(outcome.dead_letters.is_empty());
+        assert_eq!(bodies(&mut store), vec![Bytes::from_static(b"a")]);
+
+        let mut store = QueueStore::new(
+            "q",
+            dlx(&[
+                ("x-max-length", FieldArgument::SignedLong(0)),
+                ("x-overflow", long_str("reject-publish-dlx")),
+            ]),
+        );
+        let outcome = store.publish(message("a"), 0).unwrap();
+        assert!(!outcome.accepted);
+        assert_eq!(outcome.dead_letters[0].reason, DeadLetterReason::MaxLen);
+    }
+
+    #[test]
+    fn higher_priority_first() {
+        let mut store = QueueStore::new(
+            "q",
+            arguments(&[("x-max-priority", FieldArgument::SignedLong(5))]),
+        );
+        store.publish(message("none"), 0).unwrap();
+        store.publish(with_priority("p2", 2), 0).unwrap();
+        store.publish(with_priority("p9", 9), 0).unwrap();
+        store.publish(with_priority("p5", 5), 0).unwrap();
+
+        let bodies: Vec<Bytes> = bodies(&mut store);
+        let expected: Vec<Bytes> = ["p9", "p5", "p2", "none"]
+            .iter()
+            .map(|b| Bytes::from_static(b.as_bytes()))
+            .collect();
+        assert_eq!(bodies, expected);
+
+        // Without x-max-priority, priority is ignored.
+        let mut store = QueueStore::new("q", QueueArguments::default());
+        store.publish(message("first"), 0).unwrap();
+        store.publish(with_priority("second", 9), 0).unwrap();
+        assert_eq!(store.pop().unwrap().body, Bytes::from_static(b"first"));
+    }
+
+    #[test]
+    fn x_death_counts_deaths_per_queue_and_reason() {
+        let mut args = dlx(&[]);
+        args.dead_letter_routing_key = Some("retry".into());
+        let work = QueueStore::new("work", args);
+        let wait = QueueStore::new("wait", dlx(&[]));
+
+        let first = work.dead_letter(message("m"), DeadLetterReason::Rejected, 5000).unwrap();
+        assert_eq!((&*first.message.exchange, &*first.message.routing_key), ("dlx", "retry"));
+        let second = wait.dead_letter(first.message, DeadLetterReason::Expired, 6000).unwrap();
+        let third = work.dead_letter(second.message, DeadLetterReason::Rejected, 7000).unwrap();
+
+        let m = &third.message;
+        let deaths = match header(m, "x-death") {
+            Some(&FieldArgument::Array(ref deaths)) => deaths.clone(),
+            h => panic!("Unexpected x-death {:?}", h),
+        };
+        assert_eq!(deaths.len(), 2);
+        assert_eq!(death_field(&deaths[0], "queue"), Some("work"));
+        match deaths[0] {
+            FieldArgument::NestedTable(ref t) => {
+                assert_eq!(t[&AmqpString::from("count")], FieldArgument::SignedLongLong(2));
+                assert_eq!(t[&AmqpString::from("time")], FieldArgument::Timestamp(5));
+                assert_eq!(t[&AmqpString::from("exchange")], long_str("ex"));
+                assert_eq!(
+                    t[&AmqpString::from("routing-keys")],
+                    FieldArgument::Array(vec![long_str("key")])
+                );
+            }
+            ref d => panic!("Unexpected death {:?}", d),
+        }
+        assert_eq!(death_field(&deaths[1], "reason"), Some("expired"));
+        assert_eq!(header(m, "x-first-death-reason"), Some(&long_str("rejected")));
+        assert_eq!(header(m, "x-first-death-exchange"), Some(&long_str("ex")));
+        assert_eq!(header(m, "x-last-death-queue"), Some(&long_str("work")));
+        assert_eq!(header(m, "x-last-death-exchange"), Some(&long_str("dlx")));
+
+        // Rejected in "work" since it expired in "wait", so going back to "wait" is fine.
+        assert!(!third.would_cycle("wait"));
+        assert!(!third.would_cycle("work"));
+        let expired = wait.dead_letter(third.message, DeadLetterReason::Expired, 8000).unwrap();
+        assert!(expired.would_cycle("wait"));
+    }
+
+    #[test]
+    fn no_dead_letter_exchange_drops_messages() {
+        let mut store = QueueStore::new(
+            "q",
+            arguments(&[("x-message-ttl", FieldArgument::SignedLong(0))]),
+        );
+        store.publish(message("a"), 0).unwrap();
+        assert!(store.expire(1).is_empty());
+        assert!(store.is_empty());
+    }
+}
+// }}}
@@ -0,0 +1,765 @@
+//! Routing of published messages through exchanges, following RabbitMQ.
+//!
+//! `Router` holds exchanges, queues and bindings made by `queue::BindMethod` and
+//! `exchange::BindMethod`, and resolves a `Basic.Publish` into its destination queues.
+//! It can be used to build a local broker or to check a routing topology offline.
+//!
+//! # Exchange kinds
+//! - direct : binding key equals to the routing key.
+//! - fanout : every binding matches.
+//! - topic : binding key is a pattern of words separated by ".", where "*" matches exactly
+//!   one word and "#" matches zero or more words.
+//! - headers : binding arguments are compared with `Properties::headers` as "x-match" says.
+//!   "all" (default) requires every argument to match and "any" requires at least one.
+//!   Arguments starting with "x-" are ignored unless "all-with-x" / "any-with-x" is used.
+//!   An argument whose value is `Void` matches if the header exists regardless of its value.
+//!
+//! The default exchange ("") routes a message to the queue whose name is the routing key.
+//! It can not be bound.
+//!
+//! # Exchange to exchange bindings
+//! A message routed to an exchange is routed again by that exchange with the same routing key
+//! and headers. Each exchange is visited at most once per message, so cycles are harmless.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+
+use args::{AmqpString, FieldArgument};
+use frame::method::basic::PublishMethod;
+use frame::method::exchange;
+use frame::method::queue;
+use frame::content_header::Properties;
+
+
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum ExchangeKind {
+    Direct,
+    Fanout,
+    Topic,
+    Headers,
+}
+
+
+impl ExchangeKind {
+    /// Parse a type name used by `Exchange.Declare` (e.g. "topic").
+    pub fn from_name(name: &str) -> Option<ExchangeKind> {
+        match name {
+            "direct" => Some(ExchangeKind::Direct),
+            "fanout" => Some(ExchangeKind::Fanout),
+            "topic" => Some(ExchangeKind::Topic),
+            "headers" => Some(ExchangeKind::Headers),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ExchangeKind::Direct => "direct",
+            ExchangeKind::Fanout => "fanout",
+            ExchangeKind::Topic => "topic",
+            ExchangeKind::Headers => "headers",
+        }
+    }
+}
+
+
+
+// Binding {{{
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+pub enum Destination {
+    Queue(String),
+    Exchange(String),
+}
+
+
+/// Binding from an exchange. Two bindings are the same if all fields are equal.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Binding {
+    pub destination: Destination,
+    pub routing_key: String,
+    pub arguments: HashMap<AmqpString, FieldArgument>,
+}
+
+
+impl Binding {
+    /// Whether a message passes this binding of an exchange of `kind`.
+    pub fn matches(
+        &self,
+        kind: ExchangeKind,
+        routing_key: &str,
+        headers: &HashMap<AmqpString, FieldArgument>,
+    ) -> bool {
+        match kind {
+            ExchangeKind::Direct => self.routing_key == routing_key,
+            ExchangeKind::Fanout => true,
+            ExchangeKind::Topic => topic_matches(&self.routing_key, routing_key),
+            ExchangeKind::Headers => headers_match(&self.arguments, headers),
+        }
+    }
+}
+
+
+/// Match a routing key against a topic pattern.
+pub fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches(pattern: &[&str], words: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => words.is_empty(),
+            Some((&"#", rest)) => (0..words.len() + 1).any(|i| matches(rest, &words[i..])),
+            Some((&p, rest)) => {
+                match words.split_first() {
+                    Some((&w, words)) => (p == "*" || p == w) && matches(rest, words),
+                    None => false,
+                }
+            }
+        }
+    }
+
+    // Empty key has no word as RabbitMQ.
+    fn words(key: &str) -> Vec<&str> {
+        if key.is_empty() { Vec::new() } else { key.split('.').collect() }
+    }
+
+    matches(&words(pattern), &words(routing_key))
+}
+// }}}
+
+
+
+// Headers matching {{{
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+enum XMatch {
+    All,
+    Any,
+    AllWithX,
+    AnyWithX,
+}
+
+
+impl XMatch {
+    /// Read "x-match" of binding arguments. `None` if it has an invalid value.
+    fn of(arguments: &HashMap<AmqpString, FieldArgument>) -> Option<XMatch> {
+        let value = match arguments.get(&AmqpString::from("x-match")) {
+            None => return Some(XMatch::All),
+            Some(&FieldArgument::LongString(ref s)) |
+            Some(&FieldArgument::ShortString(ref s)) => s,
+            Some(_) => return None,
+        };
+        match value.0.as_ref() {
+            b"all" => Some(XMatch::All),
+            b"any" => Some(XMatch::Any),
+            b"all-with-x" => Some(XMatch::AllWithX),
+            b"any-with-x" => Some(XMatch::AnyWithX),
+            _ => None,
+        }
+    }
+}
+
+
+fn headers_match(
+    arguments: &HashMap<AmqpString, FieldArgument>,
+    headers: &HashMap<AmqpString, FieldArgument>,
+) -> bool {
+    let x_match = XMatch::of(arguments).unwrap_or(XMatch::All);
+    let with_x = x_match == XMatch::AllWithX || x_match == XMatch::AnyWithX;
+    let mut args = arguments.iter().filter(|&(k, _)| {
+        k.0.as_ref() != b"x-match" && (with_x || !k.0.starts_with(b"x-"))
+    });
+    let matches = |(k, v): (&AmqpString, &FieldArgument)| {
+        headers.get(k).map_or(false, |h| values_match(v, h))
+    };
+
+    match x_match {
+        XMatch::All | XMatch::AllWithX => args.all(matches),
+        XMatch::Any | XMatch::AnyWithX => args.any(matches),
+    }
+}
+
+
+/// Compare a binding argument with a header. Like RabbitMQ, integers and floats of any width
+/// are compared by their values, so `SignedLong(1)` matches `Double(1.0)`.
+fn values_match(argument: &FieldArgument, header: &FieldArgument) -> bool {
+    if let FieldArgument::Void = *argument {
+        return true;
+    }
+    if let (Some(a), Some(h)) = (as_integer(argument), as_integer(header)) {
+        return a == h;
+    }
+    if let (Some(a), Some(h)) = (as_float(argument), as_float(header)) {
+        return a == h;
+    }
+    if let (Some(i), Some(f)) = (as_integer(argument), as_float(header)) {
+        return integer_equals_float(i, f);
+    }
+    if let (Some(f), Some(i)) = (as_float(argument), as_integer(header)) {
+        return integer_equals_float(i, f);
+    }
+    if let (Some(a), Some(h)) = (as_str(argument), as_str(header)) {
+        return a == h;
+    }
+    argument == header
+}
+
+
+fn as_integer(value: &FieldArgument) -> Option<i128> {
+    match *value {
+        FieldArgument::SignedOctet(v) => Some(v as i128),
+        FieldArgument::UnsignedOctet(v) => Some(v as i128),
+        FieldArgument::SignedShort(v) => Some(v as i128),
+        FieldArgument::UnsignedShort(v) => Some(v as i128),
+        FieldArgument::SignedLong(v) => Some(v as i128),
+        FieldArgument::UnsignedLong(v) => Some(v as i128),
+        FieldArgument::SignedLongLong(v) => Some(v as i128),
+        FieldArgument::UnsignedLongLong(v) => Some(v as i128),
+        _ => None,
+    }
+}
+
+
+/// Exact comparison, which does not round large integers into floats.
+fn integer_equals_float(i: i128, f: f64) -> bool {
+    f.fract() == 0.0 && f.abs() < 2f64.powi(127) && f as i128 == i
+}
+
+
+fn as_float(value: &FieldArgument) -> Option<f64> {
+    match *value {
+        FieldArgument::Float(v) => Some(v as f64),
+        FieldArgument::Double(v) => Some(v),
+        _ => None,
+    }
+}
+
+
+fn as_str(value: &FieldArgument) -> Option<&[u8]> {
+    match *value {
+        FieldArgument::ShortString(ref s) |
+        FieldArgument::LongString(ref s) => Some(s.0.as_ref()),
+        _ => None,
+    }
+}
+// }}}
+
+
+
+// Router {{{
+#[derive(Clone, Debug)]
+struct Exchange {
+    kind: ExchangeKind,
+    bindings: Vec<Binding>,
+}
+
+
+/// Exchanges, queues and bindings between them.
+#[derive(Clone, Debug)]
+pub struct Router {
+    exchanges: HashMap<String, Exchange>,
+    queues: HashSet<String>,
+}
+
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+
+impl Router {
+    /// Make a router which has only the default exchange.
+    pub fn new() -> Router {
+        let mut exchanges = HashMap::new();
+        exchanges.insert(
+            String::new(),
+            Exchange {
+                kind: ExchangeKind::Direct,
+                bindings: Vec::new(),
+            },
+        );
+        Router {
+            exchanges: exchanges,
+            queues: HashSet::new(),
+        }
+    }
+
+    /// Make a router which also has the "amq.*" exchanges which RabbitMQ declares.
+    pub fn with_default_exchanges() -> Router {
+        let mut router = Router::new();
+        let defaults = [
+            ("amq.direct", ExchangeKind::Direct),
+            ("amq.fanout", ExchangeKind::Fanout),
+            ("amq.topic", ExchangeKind::Topic),
+            ("amq.headers", ExchangeKind::Headers),
+            ("amq.match", ExchangeKind::Headers),
+        ];
+        for &(name, kind) in defaults.iter() {
+            router.declare_exchange(name, kind).unwrap();
+        }
+        router
+    }
+
+    /// Declare an exchange. Declaring an existing exchange of the same kind does nothing.
+    pub fn declare_exchange(&mut self, name: &str, kind: ExchangeKind) -> Result<(), RoutingError> {
+        if let Some(ex) = self.exchanges.get(name) {
+            if ex.kind == kind {
+                return Ok(());
+            }
+            return Err(RoutingError::KindMismatch {
+                exchange: name.into(),
+                current: ex.kind,
+            });
+        }
+        let ex = Exchange {
+            kind: kind,
+            bindings: Vec::new(),
+        };
+        self.exchanges.insert(name.into(), ex);
+        Ok(())
+    }
+
+    /// Delete an exchange and bindings from / to it. Returns false if it does not exist.
+    pub fn delete_exchange(&mut self, name: &str) -> Result<bool, RoutingError> {
+        if name == "" {
+            return Err(RoutingError::DefaultExchange);
+        }
+        if self.exchanges.remove(name).is_none() {
+            return Ok(false);
+        }
+        self.remove_bindings_to(&Destination::Exchange(name.into()));
+        Ok(true)
+    }
+
+    pub fn exchange_kind(&self, name: &str) -> Option<ExchangeKind> {
+        self.exchanges.get(name).map(|e| e.kind)
+    }
+
+    /// Bindings whose source is the exchange, or `None` if it does not exist.
+    pub fn bindings(&self, exchange: &str) -> Option<&[Binding]> {
+        self.exchanges.get(exchange).map(|e| e.bindings.as_slice())
+    }
+
+    pub fn declare_queue(&mut self, name: &str) {
+        self.queues.insert(name.into());
+    }
+
+    /// Delete a queue and bindings to it. Returns false if it does not exist.
+    pub fn delete_queue(&mut self, name: &str) -> bool {
+        if !self.queues.remove(name) {
+            return false;
+        }
+        self.remove_bindings_to(&Destination::Queue(name.into()));
+        true
+    }
+
+    pub fn has_queue(&self, name: &str) -> bool {
+        self.queues.contains(name)
+    }
+
+    fn remove_bindings_to(&mut self, destination: &Destination) {
+        for ex in self.exchanges.values_mut() {
+            ex.bindings.retain(|b| b.destination != *destination);
+        }
+    }
+
+
+    // Bind / Unbind {{{
+    pub fn bind_queue(&mut self, m: &queue::BindMethod) -> Result<(), RoutingError> {
+        let destination = self.queue_destination(&m.queue)?;
+        self.bind(&m.exchange, destination, &m.routing_key, &m.arguments)
+    }
+
+    /// Returns false if there is no such binding.
+    pub fn unbind_queue(&mut self, m: &queue::UnbindMethod) -> Result<bool, RoutingError> {
+        let destination = self.queue_destination(&m.queue)?;
+        self.unbind(&m.exchange, destination, &m.routing_key, &m.arguments)
+    }
+
+    pub fn bind_exchange(&mut self, m: &exchange::BindMethod) -> Result<(), RoutingError> {
+        let destination = self.exchange_destination(&m.destination)?;
+        self.bind(&m.source, destination, &m.routing_key, &m.arguments)
+    }
+
+    /// Returns false if there is no such binding.
+    pub fn unbind_exchange(&mut self, m: &exchange::UnbindMethod) -> Result<bool, RoutingError> {
+        let destination = self.exchange_destination(&m.destination)?;
+        self.unbind(&m.source, destination, &m.routing_key, &m.arguments)
+    }
+
+    fn queue_destination(&self, queue: &str) -> Result<Destination, RoutingError> {
+        if !self.queues.contains(queue) {
+            return Err(RoutingError::QueueNotFound(queue.into()));
+        }
+        Ok(Destination::Queue(queue.into()))
+    }
+
+    fn exchange_destination(&self, exchange: &str) -> Result<Destination, RoutingError> {
+        if exchange == "" {
+            return Err(RoutingError::DefaultExchange);
+        }
+        if !self.exchanges.contains_key(exchange) {
+            return Err(RoutingError::ExchangeNotFound(exchange.into()));
+        }
+        Ok(Destination::Exchange(exchange.into()))
+    }
+
+    fn source_mut(&mut self, source: &str) -> Result<&mut Exchange, RoutingError> {
+        if source == "" {
+            return Err(RoutingError::DefaultExchange);
+        }
+        self.exchanges.get_mut(source).ok_or_else(|| {
+            RoutingError::ExchangeNotFound(source.into())
+        })
+    }
+
+    fn bind(
+        &mut self,
+        source: &str,
+        destination: Destination,
+        routing_key: &str,
+        arguments: &HashMap<AmqpString, FieldArgument>,
+    ) -> Result<(), RoutingError> {
+        let ex = self.source_mut(source)?;
+        if ex.kind == ExchangeKind::Headers && XMatch::of(arguments).is_none() {
+            return Err(RoutingError::InvalidXMatch);
+        }
+        let binding = Binding {
+            destination: destination,
+            routing_key: routing_key.into(),
+            arguments: arguments.clone(),
+        };
+        if !ex.bindings.contains(&binding) {
+            ex.bindings.push(binding);
+        }
+        Ok(())
+    }
+
+    fn unbind(
+        &mut self,
+        source: &str,
+        destination: Destination,
+        routing_key: &str,
+        arguments: &HashMap<AmqpString, FieldArgument>,
+    ) -> Result<bool, RoutingError> {
+        let ex = self.source_mut(source)?;
+        let len = ex.bindings.len();
+        ex.bindings.retain(|b| {
+            b.destination != destination || b.routing_key != routing_key ||
+                b.arguments != *arguments
+        });
+        Ok(ex.bindings.len() != len)
+    }
+    // }}}
+
+
+    // Route {{{
+    /// Queues which a published message is routed to, in the order they are found and without
+    /// duplicates.
+    pub fn route(
+        &self,
+        publish: &PublishMethod,
+        properties: &Properties,
+    ) -> Result<Vec<String>, RoutingError> {
+        let empty = HashMap::new();
+        let headers = properties.headers.as_ref().unwrap_or(&empty);
+        self.route_with(&publish.exchange, &publish.routing_key, headers)
+    }
+
+    /// Same as `route` but takes the exchange, routing key and headers directly.
+    pub fn route_with(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        headers: &HashMap<AmqpString, FieldArgument>,
+    ) -> Result<Vec<String>, RoutingError> {
+        if !self.exchanges.contains_key(exchange) {
+            return Err(RoutingError::ExchangeNotFound(exchange.into()));
+        }
+
+        let mut queues = Vec::new();
+        if exchange == "" {
+            if self.queues.contains(routing_key) {
+                queues.push(routing_key.into());
+            }
+        } else {
+            let mut visited = HashSet::new();
+            self.visit(exchange, routing_key, headers, &mut visited, &mut queues);
+        }
+        Ok(queues)
+    }
+
+    fn visit<'a>(
+        &'a self,
+        exchange: &'a str,
+        routing_key: &str,
+        headers: &HashMap<AmqpString, FieldArgument>,
+        visited: &mut HashSet<&'a str>,
+        queues: &mut Vec<String>,
+    ) {
+        if !visited.insert(exchange) {
+            return;
+        }
+        let ex = match self.exchanges.get(exchange) {
+            Some(ex) => ex,
+            None => return,
+        };
+
+        for b in ex.bindings.iter() {
+            if !b.matches(ex.kind, routing_key, headers) {
+                continue;
+            }
+            match b.destination {
+                Destination::Queue(ref q) => {
+                    if !queues.contains(q) {
+                        queues.push(q.clone());
+                    }
+                }
+                Destination::Exchange(ref e) => {
+                    self.visit(e, routing_key, headers, visited, queues);
+                }
+            }
+        }
+    }
+    // }}}
+}
+// }}}
+
+
+
+// RoutingError {{{
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub enum RoutingError {
+    ExchangeNotFound(String),
+    QueueNotFound(String),
+    /// The default exchange can not be bound, unbound nor deleted.
+    DefaultExchange,
+    /// An exchange of the same name exists with another kind.
+    KindMismatch {
+        exchange: String,
+        current: ExchangeKind,
+    },
+    /// "x-match" of a binding to a headers exchange is not one of "all", "any",
+    /// "all-with-x" and "any-with-x".
+    InvalidXMatch,
+}
+
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RoutingError::ExchangeNotFound(ref name) => write!(f, "no exchange '{}'", name),
+            RoutingError::QueueNotFound(ref name) => write!(f, "no queue '{}'", name),
+            RoutingError::DefaultExchange => {
+                write!(f, "operation not permitted on the default exchange")
+            }
+            RoutingError::KindMismatch {
+                ref exchange,
+                current,
+            } => write!(
+                f,
+                "exchange '{}' exists with type '{}'",
+                exchange,
+                current.name()
+            ),
+            RoutingError::InvalidXMatch => write!(f, "invalid x-match field value"),
+        }
+    }
+}
+
+
+impl Error for RoutingError {}
+// }}}
+
+
+
+// TESTS {{{
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(entries: &[(&'static str, FieldArgument)]) -> HashMap<AmqpString, FieldArgument> {
+        entries
+            .iter()
+            .map(|&(k, ref v)| (AmqpString::from(k), v.clone()))
+            .collect()
+    }
+
+    fn bind_queue(
+        router: &mut Router,
+        exchange: &'static str,
+        queue: &'static str,
+        routing_key: &'static str,
+        arguments: HashMap<AmqpString, FieldArgument>,
+    ) -> Result<(), RoutingError> {
+        router.bind_queue(&queue::BindMethod {
+            reserved1: 0,
+            queue: AmqpString::from(queue),
+            exchange: AmqpString::from(exchange),
+            routing_key: AmqpString::from(routing_key),
+            no_wait: false,
+            arguments: arguments,
+        })
+    }
+
+    fn bind_exchange(
+        router: &mut Router,
+        source: &'static str,
+        destination: &'static str,
+        routing_key: &'static str,
+    ) -> Result<(), RoutingError> {
+        router.bind_exchange(&exchange::BindMethod {
+            reserved1: 0,
+            destination: AmqpString::from(destination),
+            source: AmqpString::from(source),
+            routing_key: AmqpString::from(routing_key),
+            no_wait: false,
+            arguments: HashMap::new(),
+        })
+    }
+
+    fn route(router: &Router, exchange: &str, routing_key: &str) -> Vec<String> {
+        router.route_with(exchange, routing_key, &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn topic_pattern() {
+        assert!(topic_matches("a.*.c", "a.b.c"));
+        assert!(!topic_matches("a.*.c", "a.c"));
+        assert!(topic_matches("a.#", "a"));
+        assert!(topic_matches("a.#", "a.b.c"));
+        assert!(topic_matches("#.c", "a.b.c"));
+        assert!(topic_matches("a.#.c", "a.c"));
+        assert!(topic_matches("#", ""));
+        assert!(!topic_matches("a.*", "a.b.c"));
+        assert!(!topic_matches("*", ""));
+    }
+
+    #[test]
+    fn direct_fanout_and_default_exchange() {
+        let mut router = Router::with_default_exchanges();
+        router.declare_queue("q1");
+        router.declare_queue("q2");
+        bind_queue(&mut router, "amq.direct", "q1", "k", HashMap::new()).unwrap();
+        bind_queue(&mut router, "amq.fanout", "q1", "", HashMap::new()).unwrap();
+        bind_queue(&mut router, "amq.fanout", "q2", "", HashMap::new()).unwrap();
+
+        assert_eq!(route(&router, "amq.direct", "k"), vec!["q1"]);
+        assert!(route(&router, "amq.direct", "other").is_empty());
+        assert_eq!(route(&router, "amq.fanout", "any"), vec!["q1", "q2"]);
+        assert_eq!(route(&router, "", "q2"), vec!["q2"]);
+        assert!(route(&router, "", "missing").is_empty());
+        assert_eq!(
+            router.route_with("missing", "", &HashMap::new()),
+            Err(RoutingError::ExchangeNotFound("missing".into()))
+        );
+        assert_eq!(
+            bind_queue(&mut router, "", "q1", "k", HashMap::new()),
+            Err(RoutingError::DefaultExchange)
+        );
+    }
+
+    #[test]
+    fn headers_x_match() {
+        let mut router = Router::with_default_exchanges();
+        for q in &["all", "any", "all-with-x", "any-with-x", "void"] {
+            router.declare_queue(q);
+        }
+        let args = |x_match: &'static str| {
+            table(&[
+                ("x-match", FieldArgument::LongString(AmqpString::from(x_match))),
+                ("format", FieldArgument::LongString(AmqpString::from("pdf"))),
+                ("size", FieldArgument::SignedLong(10)),
+                ("x-tenant", FieldArgument::LongString(AmqpString::from("a"))),
+            ])
+        };
+        for q in &["all", "any", "all-with-x", "any-with-x"] {
+            bind_queue(&mut router, "amq.headers", q, "", args(q)).unwrap();
+        }
+        let void = table(&[("format", FieldArgument::Void)]);
+        bind_queue(&mut router, "amq.headers", "void", "", void).unwrap();
+
+        let route_headers = |headers: &[(&'static str, FieldArgument)]| {
+            let mut queues = router.route_with("amq.headers", "", &table(headers)).unwrap();
+            queues.sort();
+            queues
+        };
+
+        let pdf = FieldArgument::LongString(AmqpString::from("pdf"));
+        let tenant = FieldArgument::LongString(AmqpString::from("a"));
+        // Integers of another width match by value.
+        let size = FieldArgument::UnsignedOctet(10);
+        assert_eq!(
+            route_headers(&[("format", pdf.clone()), ("size", size.clone())]),
+            vec!["all", "any", "any-with-x", "void"]
+        );
+        assert_eq!(
+            route_headers(&[("format", pdf.clone()), ("size", size), ("x-tenant", tenant.clone())]),
+            vec!["all", "all-with-x", "any", "any-with-x", "void"]
+        );
+        // Integers and floats match by value too.
+        assert_eq!(
+            route_headers(&[("format", pdf.clone()), ("size", FieldArgument::Double(10.0))]),
+            vec!["all", "any", "any-with-x", "void"]
+        );
+        assert_eq!(
+            route_headers(&[("format", pdf.clone()), ("size", FieldArgument::Float(10.5))]),
+            vec!["any", "any-with-x", "void"]
+        );
+        assert_eq!(route_headers(&[("x-tenant", tenant)]), vec!["any-with-x"]);
+        let doc = FieldArgument::LongString(AmqpString::from("doc"));
+        assert_eq!(route_headers(&[("format", doc)]), vec!["void"]);
+        assert!(route_headers(&[]).is_empty());
+
+        let invalid = table(&[("x-match", FieldArgument::LongString(AmqpString::from("some")))]);
+        assert_eq!(
+            bind_queue(&mut router, "amq.headers", "all", "", invalid),
+            Err(RoutingError::InvalidXMatch)
+        );
+    }
+
+    #[test]
+    fn exchange_to_exchange_with_cycle() {
+        let mut router = Router::new();
+        router.declare_exchange("a", ExchangeKind::Topic).unwrap();
+        router.declare_exchange("b", ExchangeKind::Fanout).unwrap();
+        router.declare_queue("qa");
+        router.declare_queue("qb");
+        bind_exchange(&mut router, "a", "b", "logs.#").unwrap();
+        bind_exchange(&mut router, "b", "a", "").unwrap();
+        bind_queue(&mut router, "a", "qa", "logs.error", HashMap::new()).unwrap();
+        bind_queue(&mut router, "b", "qb", "", HashMap::new()).unwrap();
+
+        assert_eq!(route(&router, "a", "logs.error"), vec!["qb", "qa"]);
+        assert_eq!(route(&router, "a", "logs.info"), vec!["qb"]);
+        assert_eq!(route(&router, "b", "logs.error"), vec!["qa", "qb"]);
+        assert!(route(&router, "a", "metrics").is_empty());
+
+        assert_eq!(router.delete_exchange("b"), Ok(true));
+        assert!(router.bindings("a").unwrap().iter().all(|b| {
+            b.destination != Destination::Exchange("b".into())
+        }));
+        assert_eq!(route(&router, "a", "logs.error"), vec!["qa"]);
+    }
+
+    #[test]
+    fn unbind_needs_same_arguments() {
+        let mut router = Router::with_default_exchanges();
+        router.declare_queue("q");
+        let args = table(&[("x-match", FieldArgument::LongString(AmqpString::from("any")))]);
+        bind_queue(&mut router, "amq.match", "q", "", args.clone()).unwrap();
+        bind_queue(&mut router, "amq.match", "q", "", args.clone()).unwrap();
+        assert_eq!(router.bindings("amq.match").unwrap().len(), 1);
+
+        let mut unbind = queue::UnbindMethod {
+            reserved1: 0,
+            queue: AmqpString::from("q"),
+            exchange: AmqpString::from("amq.match"),
+            routing_key: AmqpString::from(""),
+            arguments: HashMap::new(),
+        };
+        assert_eq!(router.unbind_queue(&unbind), Ok(false));
+        unbind.arguments = args;
+        assert_eq!(router.unbind_queue(&unbind), Ok(true));
+        assert!(router.bindings("amq.match").unwrap().is_empty());
+    }
+}
+// }}}